@@ -0,0 +1,311 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use tracing::info;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::Identity;
+use crate::server;
+use crate::webhooks::Webhooks;
+use crate::Chat;
+
+/// The longest reason a websocket close frame can carry.
+const MAX_REASON_LEN: usize = 123;
+
+/// The request to the admin API came without the right token.
+#[derive(Debug)]
+pub struct InvalidToken;
+
+impl warp::reject::Reject for InvalidToken {}
+
+/// The user or its address is banned from the chat.
+#[derive(Debug)]
+pub struct Banned;
+
+impl warp::reject::Reject for Banned {}
+
+/// Who is not allowed to join.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    /// A logged in user, by the subject of its identity.
+    Subject(String),
+    Ip(IpAddr),
+}
+
+struct Ban {
+    id: usize,
+    target: BanTarget,
+    reason: String,
+    until: Instant,
+}
+
+#[derive(Serialize)]
+struct BanInfo<'a> {
+    id: usize,
+    #[serde(flatten)]
+    target: &'a BanTarget,
+    reason: &'a str,
+    expires_in_secs: u64,
+}
+
+/// Bans in force, expired ones are dropped whenever bans are looked at.
+#[derive(Default)]
+pub struct Bans {
+    next_id: AtomicUsize,
+    bans: RwLock<Vec<Ban>>,
+}
+
+impl Bans {
+    async fn add(&self, target: BanTarget, reason: String, until: Instant) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(id, ?target, %reason, duration = ?until.saturating_duration_since(Instant::now()), "banning");
+        let mut bans = self.bans.write().await;
+        bans.retain(|ban| ban.until > Instant::now());
+        bans.push(Ban { id, target, reason, until });
+        id
+    }
+
+    async fn remove(&self, id: usize) -> bool {
+        let mut bans = self.bans.write().await;
+        let before = bans.len();
+        bans.retain(|ban| ban.id != id);
+        bans.len() != before
+    }
+
+    async fn list(&self) -> Response {
+        let now = Instant::now();
+        let bans = self.bans.read().await;
+        let bans: Vec<_> = bans.iter()
+            .filter(|ban| ban.until > now)
+            .map(|ban| BanInfo {
+                id: ban.id,
+                target: &ban.target,
+                reason: &ban.reason,
+                expires_in_secs: (ban.until - now).as_secs(),
+            })
+            .collect();
+        warp::reply::json(&bans).into_response()
+    }
+
+    async fn is_banned(&self, subject: Option<&str>, ip: Option<IpAddr>) -> bool {
+        let now = Instant::now();
+        self.bans.read().await.iter()
+            .filter(|ban| ban.until > now)
+            .any(|ban| ban.target.matches(subject, ip))
+    }
+}
+
+impl BanTarget {
+    fn matches(&self, subject: Option<&str>, ip: Option<IpAddr>) -> bool {
+        match self {
+            BanTarget::Subject(banned) => subject == Some(banned.as_str()),
+            BanTarget::Ip(banned) => ip == Some(*banned),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct KickRequest {
+    reason: Option<String>,
+}
+
+/// Exactly one of `uid`, `subject` and `ip`. A user given by `uid` is banned by its subject
+/// when it is logged in, by its address otherwise.
+#[derive(Deserialize)]
+struct BanRequest {
+    uid: Option<usize>,
+    subject: Option<String>,
+    ip: Option<IpAddr>,
+    secs: u64,
+    reason: Option<String>,
+}
+
+/// The admin API is missing unless ADMIN_TOKEN is set.
+pub fn token_from_env() -> Option<String> {
+    std::env::var("ADMIN_TOKEN").ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// GET /admin/connections, POST /admin/connections/:connection/kick,
+/// GET /admin/bans, POST /admin/bans, DELETE /admin/bans/:id and GET /admin/webhooks/deliveries,
+/// all of them require `Authorization: Bearer <ADMIN_TOKEN>`.
+pub fn routes(
+    token: Option<String>,
+    chat: Chat,
+    bans: Arc<Bans>,
+    webhooks: Option<Arc<Webhooks>>,
+) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    let authorized = warp::path("admin")
+        .and(authorized(token))
+        .untuple_one();
+    let chat = warp::any().map(move || chat.clone());
+    let bans = warp::any().map(move || bans.clone());
+
+    let connections = warp::path!("connections")
+        .and(warp::get())
+        .and(chat.clone())
+        .then(|chat: Chat| async move {
+            warp::reply::json(&chat.read().await.connections()).into_response()
+        });
+
+    let kick = warp::path!("connections" / usize / "kick")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(chat.clone())
+        .then(kick);
+
+    let list_bans = warp::path!("bans")
+        .and(warp::get())
+        .and(bans.clone())
+        .then(|bans: Arc<Bans>| async move { bans.list().await });
+
+    let add_ban = warp::path!("bans")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(chat)
+        .and(bans.clone())
+        .then(ban);
+
+    let remove_ban = warp::path!("bans" / usize)
+        .and(warp::delete())
+        .and(bans)
+        .then(|id, bans: Arc<Bans>| async move {
+            if bans.remove(id).await {
+                StatusCode::NO_CONTENT.into_response()
+            } else {
+                StatusCode::NOT_FOUND.into_response()
+            }
+        });
+
+    // Without webhooks there is nothing to deliver
+    let deliveries = warp::path!("webhooks" / "deliveries")
+        .and(warp::get())
+        .map(move || {
+            let log = webhooks.as_ref().map(|webhooks| webhooks.log()).unwrap_or_default();
+            warp::reply::json(&log).into_response()
+        });
+
+    authorized.and(
+        connections
+            .or(kick).unify()
+            .or(list_bans).unify()
+            .or(add_ban).unify()
+            .or(remove_ban).unify()
+            .or(deliveries).unify())
+}
+
+/// Rejects banned users and addresses with `Banned`, passes the identity and address on.
+pub fn admitted(
+    identity: impl Filter<Extract=(Option<Identity>,), Error=Rejection> + Clone,
+    bans: Arc<Bans>,
+) -> impl Filter<Extract=(Option<Identity>, Option<SocketAddr>), Error=Rejection> + Clone {
+    identity
+        .and(server::remote())
+        .and_then(move |identity: Option<Identity>, remote: Option<SocketAddr>| {
+            let bans = bans.clone();
+            async move {
+                let subject = identity.as_ref().map(|identity| identity.subject.as_str());
+                if bans.is_banned(subject, remote.map(|addr| addr.ip())).await {
+                    Err(warp::reject::custom(Banned))
+                } else {
+                    Ok((identity, remote))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<InvalidToken>().is_some() {
+        Ok(warp::reply::with_status("Invalid admin token", StatusCode::UNAUTHORIZED).into_response())
+    } else if rejection.find::<Banned>().is_some() {
+        Ok(warp::reply::with_status("Banned", StatusCode::FORBIDDEN).into_response())
+    } else {
+        Err(rejection)
+    }
+}
+
+fn authorized(token: Option<String>) -> impl Filter<Extract=((),), Error=Rejection> + Clone {
+    // Comparing digests takes the same time however much of the token is right
+    let expected = token.map(|token| Sha256::digest(token.as_bytes()));
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            async move {
+                let expected = expected.ok_or_else(warp::reject::not_found)?;
+                let given = header.as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "))
+                    .map(|token| Sha256::digest(token.as_bytes()));
+                if given == Some(expected) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(InvalidToken))
+                }
+            }
+        })
+}
+
+async fn kick(connection: usize, request: KickRequest, chat: Chat) -> Response {
+    let reason = request.reason.unwrap_or_else(|| "Kicked".to_owned());
+    if reason.len() > MAX_REASON_LEN {
+        return too_long_reason();
+    }
+    if chat.read().await.kick(connection, &reason) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+async fn ban(request: BanRequest, chat: Chat, bans: Arc<Bans>) -> Response {
+    let reason = request.reason.unwrap_or_else(|| "Banned".to_owned());
+    if reason.len() > MAX_REASON_LEN {
+        return too_long_reason();
+    }
+    let until = match Instant::now().checked_add(Duration::from_secs(request.secs)) {
+        Some(until) => until,
+        None => return warp::reply::with_status(
+            "The ban lasts too long", StatusCode::BAD_REQUEST).into_response(),
+    };
+
+    let chat = chat.read().await;
+    let connections = chat.connections();
+    let target = match (request.uid, request.subject, request.ip) {
+        (Some(uid), None, None) => match connections.iter().find(|info| info.uid == uid) {
+            Some(info) => match (&info.subject, info.remote) {
+                (Some(subject), _) => BanTarget::Subject(subject.clone()),
+                (None, Some(remote)) => BanTarget::Ip(remote.ip()),
+                (None, None) => return warp::reply::with_status(
+                    "The user has neither a subject nor an address", StatusCode::CONFLICT).into_response(),
+            },
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        (None, Some(subject), None) => BanTarget::Subject(subject),
+        (None, None, Some(ip)) => BanTarget::Ip(ip),
+        _ => return warp::reply::with_status(
+            "Expected exactly one of uid, subject and ip", StatusCode::BAD_REQUEST).into_response(),
+    };
+
+    let id = bans.add(target.clone(), reason.clone(), until).await;
+    // Who is banned now has to go, those who connected in the meantime included
+    for info in chat.connections() {
+        if target.matches(info.subject.as_deref(), info.remote.map(|addr| addr.ip())) {
+            chat.kick(info.connection, &reason);
+        }
+    }
+
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "id": id })), StatusCode::CREATED)
+        .into_response()
+}
+
+fn too_long_reason() -> Response {
+    let message = format!("The reason must not be longer than {} bytes", MAX_REASON_LEN);
+    warp::reply::with_status(message, StatusCode::BAD_REQUEST).into_response()
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use url::Url;
+use warp::http::{StatusCode, Uri};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+const SESSION_COOKIE: &str = "chat_session";
+
+/// How long a user may stay on the identity provider's login page.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A chat user as identified by the OpenID Connect provider.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub name: String,
+}
+
+/// The request came without a valid session while login is required.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Our `/auth/callback` as the provider redirects to it
+    pub redirect_url: String,
+    /// Separated by spaces
+    pub scopes: String,
+    /// How long a login lasts before the user has to log in again
+    pub session_lifetime: Duration,
+}
+
+impl OidcConfig {
+    /// Reads the identity provider settings, `None` means login is disabled.
+    pub fn from_env() -> Option<OidcConfig> {
+        let issuer = std::env::var("OIDC_ISSUER").ok()?;
+        let client_id = std::env::var("OIDC_CLIENT_ID")
+            .expect("Missing env variable OIDC_CLIENT_ID required by OIDC_ISSUER");
+        let redirect_url = std::env::var("OIDC_REDIRECT_URL")
+            .expect("Missing env variable OIDC_REDIRECT_URL required by OIDC_ISSUER");
+        let client_secret = std::env::var("OIDC_CLIENT_SECRET").ok();
+        let scopes = std::env::var("OIDC_SCOPES")
+            .unwrap_or_else(|_| "openid profile".to_owned());
+        let lifetime = std::env::var("OIDC_SESSION_SECS").unwrap_or_else(|_| "86400".to_owned());
+        let session_lifetime = Duration::from_secs(lifetime.parse()
+            .unwrap_or_else(|_| panic!("Env variable OIDC_SESSION_SECS contains non numeric value: {}", lifetime)));
+        Some(OidcConfig { issuer, client_id, client_secret, redirect_url, scopes, session_lifetime })
+    }
+}
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: u64,
+    nonce: Option<String>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    started: Instant,
+}
+
+struct Session {
+    identity: Identity,
+    started: Instant,
+}
+
+/// Authorization code flow with PKCE against a single identity provider.
+pub struct Oidc {
+    config: OidcConfig,
+    provider: ProviderMetadata,
+    http: reqwest::Client,
+    pending: RwLock<HashMap<String, PendingLogin>>,
+    sessions: RwLock<HashMap<String, Session>>,
+}
+
+impl Oidc {
+    pub async fn discover(config: OidcConfig) -> Result<Oidc, String> {
+        let http = reqwest::Client::new();
+        let discovery_url = format!("{}/.well-known/openid-configuration", config.issuer.trim_end_matches('/'));
+        let provider = http.get(&discovery_url)
+            .send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Could not fetch {}: {}", discovery_url, e))?
+            .json::<ProviderMetadata>().await
+            .map_err(|e| format!("Could not parse {}: {}", discovery_url, e))?;
+        if provider.issuer != config.issuer {
+            return Err(format!("Provider reports issuer {} instead of {}", provider.issuer, config.issuer));
+        }
+        Ok(Oidc {
+            config,
+            provider,
+            http,
+            pending: RwLock::default(),
+            sessions: RwLock::default(),
+        })
+    }
+
+    async fn start_login(&self) -> Result<Uri, String> {
+        let state = random_token();
+        let nonce = random_token();
+        let verifier = random_token();
+        let challenge = base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+
+        let authorize_url = Url::parse_with_params(&self.provider.authorization_endpoint, &[
+            ("response_type", "code"),
+            ("client_id", &self.config.client_id),
+            ("redirect_uri", &self.config.redirect_url),
+            ("scope", &self.config.scopes),
+            ("state", &state),
+            ("nonce", &nonce),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ]).map_err(|e| format!("Invalid authorization endpoint: {}", e))?;
+
+        let mut pending = self.pending.write().await;
+        // Forget logins which were abandoned on the provider's page
+        pending.retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+        pending.insert(state, PendingLogin { verifier, nonce, started: Instant::now() });
+
+        authorize_url.as_str().parse::<Uri>()
+            .map_err(|e| format!("Invalid authorization url: {}", e))
+    }
+
+    async fn finish_login(&self, params: CallbackParams) -> Result<String, String> {
+        if let Some(error) = params.error {
+            return Err(format!("Provider returned error: {}", error));
+        }
+        let code = params.code.ok_or("Missing code")?;
+        let state = params.state.ok_or("Missing state")?;
+        let login = self.pending.write().await.remove(&state)
+            .filter(|login| login.started.elapsed() < LOGIN_TIMEOUT)
+            .ok_or("Unknown or expired state")?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &login.verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let tokens = self.http.post(&self.provider.token_endpoint)
+            .form(&form)
+            .send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Token request failed: {}", e))?
+            .json::<TokenResponse>().await
+            .map_err(|e| format!("Could not parse token response: {}", e))?;
+
+        let identity = self.validate(&tokens.id_token, &login.nonce)?;
+        info!("user logged in: {} ({})", identity.subject, identity.name);
+
+        let session = random_token();
+        let mut sessions = self.sessions.write().await;
+        // Forget sessions which expired since
+        sessions.retain(|_, session| session.started.elapsed() < self.config.session_lifetime);
+        sessions.insert(session.clone(), Session { identity, started: Instant::now() });
+        Ok(session)
+    }
+
+    /// The token comes straight from the token endpoint, so we trust the transport
+    /// instead of checking its signature (OpenID Connect Core 3.1.3.7), but the
+    /// claims still have to be meant for us.
+    fn validate(&self, id_token: &str, nonce: &str) -> Result<Identity, String> {
+        let payload = id_token.split('.').nth(1).ok_or("Malformed id_token")?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| format!("Malformed id_token: {}", e))?;
+        let claims = serde_json::from_slice::<IdTokenClaims>(&payload)
+            .map_err(|e| format!("Malformed id_token claims: {}", e))?;
+
+        if claims.iss != self.provider.issuer {
+            return Err(format!("Unexpected issuer {}", claims.iss));
+        }
+        let audience_matches = match &claims.aud {
+            Audience::One(aud) => *aud == self.config.client_id,
+            Audience::Many(aud) => aud.contains(&self.config.client_id),
+        };
+        if !audience_matches {
+            return Err("The id_token was issued for another client".to_owned());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if claims.exp <= now {
+            return Err("The id_token has expired".to_owned());
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err("The id_token nonce does not match".to_owned());
+        }
+
+        let name = claims.name
+            .or(claims.preferred_username)
+            .unwrap_or_else(|| claims.sub.clone());
+        Ok(Identity { subject: claims.sub, name })
+    }
+
+    async fn session(&self, session: Option<String>) -> Option<Identity> {
+        self.sessions.read().await.get(&session?)
+            .filter(|session| session.started.elapsed() < self.config.session_lifetime)
+            .map(|session| session.identity.clone())
+    }
+
+    /// Kept by the browser as long as the session lasts, and only sent over https
+    /// unless the chat itself is served over http.
+    fn session_cookie(&self, session: &str) -> String {
+        let mut cookie = format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            SESSION_COOKIE, session, self.config.session_lifetime.as_secs());
+        if !self.config.redirect_url.starts_with("http://") {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+/// GET /auth/login, GET /auth/callback and the login redirect for the chat page,
+/// all of them are missing when login is disabled.
+pub fn routes(oidc: Option<Arc<Oidc>>) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    let oidc = warp::any().and_then(move || {
+        let oidc = oidc.clone();
+        async move { oidc.ok_or_else(warp::reject::not_found) }
+    });
+
+    let login = warp::path!("auth" / "login")
+        .and(warp::get())
+        .and(oidc.clone())
+        .and_then(login);
+
+    let callback = warp::path!("auth" / "callback")
+        .and(warp::get())
+        .and(warp::query::<CallbackParams>())
+        .and(oidc.clone())
+        .and_then(callback);
+
+    // GET / without a session -> login
+    let login_required = warp::path::end()
+        .and(warp::get())
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and(oidc)
+        .and_then(login_required);
+
+    login.or(callback).unify()
+        .or(login_required).unify()
+}
+
+/// Resolves the logged in user, or rejects with `Unauthorized` when login is enabled
+/// and the request has no valid session.
+pub fn identity(oidc: Option<Arc<Oidc>>) -> impl Filter<Extract=(Option<Identity>,), Error=Rejection> + Clone {
+    warp::cookie::optional(SESSION_COOKIE)
+        .and_then(move |session: Option<String>| {
+            let oidc = oidc.clone();
+            async move {
+                match oidc {
+                    None => Ok(None),
+                    Some(oidc) => oidc.session(session).await
+                        .map(Some)
+                        .ok_or_else(|| warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_status("Login required", StatusCode::UNAUTHORIZED).into_response())
+    } else {
+        Err(rejection)
+    }
+}
+
+async fn login(oidc: Arc<Oidc>) -> Result<Response, Infallible> {
+    Ok(match oidc.start_login().await {
+        Ok(authorize_url) => warp::redirect::see_other(authorize_url).into_response(),
+        Err(e) => {
+            warn!("could not start login: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    })
+}
+
+async fn login_required(session: Option<String>, oidc: Arc<Oidc>) -> Result<Response, Rejection> {
+    match oidc.session(session).await {
+        // Let the static assets serve the page
+        Some(_) => Err(warp::reject::not_found()),
+        None => Ok(warp::redirect::see_other(Uri::from_static("/auth/login")).into_response()),
+    }
+}
+
+async fn callback(params: CallbackParams, oidc: Arc<Oidc>) -> Result<Response, Infallible> {
+    Ok(match oidc.finish_login(params).await {
+        Ok(session) => {
+            let cookie = oidc.session_cookie(&session);
+            let redirect = warp::redirect::see_other(Uri::from_static("/"));
+            warp::reply::with_header(redirect, "set-cookie", cookie).into_response()
+        }
+        Err(e) => {
+            warn!("login failed: {}", e);
+            warp::reply::with_status("Login failed", StatusCode::BAD_REQUEST).into_response()
+        }
+    })
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
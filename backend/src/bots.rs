@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use protocol::ServerEvent;
+use tokio::sync::mpsc;
+use tracing::{info_span, Instrument};
+
+use crate::Chat;
+
+/// A chat bot living inside the backend. It is in every room, gets all events there
+/// but those caused by bots, and posts messages under its own name.
+pub trait Bot: Send + 'static {
+    fn name(&self) -> String;
+
+    /// Called for the events of all rooms in the order they happened, `room` tells where.
+    fn on_event(&mut self, room: &str, event: &ServerEvent, say: &Say);
+
+    /// Whether `on_event` may take long, it then runs on a thread of its own instead
+    /// of holding up the connections served by the same worker.
+    fn blocking(&self) -> bool {
+        false
+    }
+}
+
+/// Posts messages of a bot.
+#[derive(Clone)]
+pub struct Say {
+    tx: mpsc::UnboundedSender<(String, String)>,
+}
+
+impl Say {
+    pub fn now(&self, room: &str, text: String) {
+        // The poster runs as long as there is a `Say`
+        let _ = self.tx.send((room.to_owned(), text));
+    }
+
+    pub fn later(&self, room: &str, text: String, delay: Duration) {
+        let say = self.clone();
+        let room = room.to_owned();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(delay).await;
+            say.now(&room, text);
+        });
+    }
+}
+
+/// Adds the bot to the chat and runs it in a task of its own, or a thread for blocking bots.
+pub async fn spawn(chat: Chat, mut bot: Box<dyn Bot>) {
+    let name = bot.name();
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let uid = chat.write().await.add_bot(name.clone(), events_tx);
+    let span = info_span!("bot", uid, %name);
+
+    let (say_tx, mut say_rx) = mpsc::unbounded_channel::<(String, String)>();
+    tokio::task::spawn(async move {
+        while let Some((room, text)) = say_rx.recv().await {
+            chat.read().await.bot_message(uid, room, text);
+        }
+    }.instrument(span.clone()));
+
+    let say = Say { tx: say_tx };
+    if bot.blocking() {
+        // Ends once the chat is gone, and with it the sender of the events
+        tokio::task::spawn_blocking(move || span.in_scope(|| {
+            while let Some((room, event)) = events_rx.blocking_recv() {
+                bot.on_event(&room, &event, &say);
+            }
+        }));
+        return;
+    }
+    tokio::task::spawn(async move {
+        while let Some((room, event)) = events_rx.recv().await {
+            bot.on_event(&room, &event, &say);
+        }
+    }.instrument(span));
+}
+
+/// The longest delay of a reminder.
+const MAX_REMIND_SECS: u64 = 24 * 60 * 60;
+/// How many reminders a user may have pending at once.
+const MAX_REMINDERS: usize = 5;
+
+/// Repeats "!echo <text>", and "!remind <seconds> <text>" after that many seconds.
+#[derive(Default)]
+pub struct EchoBot {
+    /// Reminders not yet posted, by uid.
+    reminders: Arc<Mutex<HashMap<usize, usize>>>,
+}
+
+impl EchoBot {
+    /// Counts the reminder as pending unless the user already has too many.
+    fn remind(&self, uid: usize) -> bool {
+        let mut reminders = self.reminders.lock().unwrap(); // Not poisoned, holders never panic
+        let pending = reminders.entry(uid).or_default();
+        if *pending >= MAX_REMINDERS {
+            return false;
+        }
+        *pending += 1;
+        true
+    }
+}
+
+impl Bot for EchoBot {
+    fn name(&self) -> String {
+        "EchoBot".to_owned()
+    }
+
+    fn on_event(&mut self, room: &str, event: &ServerEvent, say: &Say) {
+        let (uid, name, text) = match event {
+            ServerEvent::Message { uid, name, text, .. } => (*uid, name, text),
+            _ => return,
+        };
+
+        if let Some(text) = text.strip_prefix("!echo ") {
+            say.now(room, text.to_owned());
+        } else if let Some(reminder) = text.strip_prefix("!remind ") {
+            match reminder.split_once(' ').and_then(|(secs, text)| Some((secs.parse().ok()?, text))) {
+                Some((secs, text)) if secs <= MAX_REMIND_SECS && self.remind(uid) => {
+                    let (say, room, text) = (say.clone(), room.to_owned(), format!("{}: {}", name, text));
+                    let reminders = self.reminders.clone();
+                    tokio::task::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(secs)).await;
+                        say.now(&room, text);
+                        let mut reminders = reminders.lock().unwrap(); // Not poisoned, holders never panic
+                        if let Some(pending) = reminders.get_mut(&uid) {
+                            *pending -= 1;
+                            if *pending == 0 {
+                                reminders.remove(&uid);
+                            }
+                        }
+                    });
+                }
+                _ => say.now(room, format!(
+                    "{}: try !remind <seconds> <text>, with up to {} seconds and {} reminders at a time",
+                    name, MAX_REMIND_SECS, MAX_REMINDERS,
+                )),
+            }
+        }
+    }
+}
+
+/// The bots named in BOTS, separated by commas.
+pub fn from_env() -> Vec<Box<dyn Bot>> {
+    let names = std::env::var("BOTS").unwrap_or_default();
+    names.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| -> Box<dyn Bot> {
+            match name {
+                "echo" => Box::<EchoBot>::default(),
+                _ => panic!("Env variable BOTS contains unknown bot: {}", name),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use protocol::ServerEvent;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tracing::{info, info_span, Instrument};
+
+use crate::chat::{Member, ToPeers};
+use crate::cluster::ClusterConfig;
+use crate::metrics::Metrics;
+use crate::redis::RedisBroker;
+use crate::Chat;
+
+/// How often a backend on a bus tells the others that it is still there.
+const HEARTBEAT: Duration = Duration::from_secs(2);
+/// A backend on a bus which has not been heard of for this long is gone, with its users.
+const SILENCE: Duration = Duration::from_secs(6);
+
+/// Which of how many nodes of a cluster this is. Ids handed out by different nodes never
+/// collide, a single backend is node 0 of 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Node {
+    pub const ALONE: Node = Node { index: 0, count: 1 };
+
+    /// The next id of this node after `last`, which may have come from any node.
+    /// Node `index` hands out `index + 1`, `index + 1 + count` and so on.
+    pub fn next_id(self, last: u64) -> u64 {
+        let (own, count) = (self.index as u64 + 1, self.count as u64);
+        let next = last + 1;
+        next + (own % count + count - next % count) % count
+    }
+}
+
+/// Carries the events of the rooms between the backends serving one chat, every backend
+/// delivers what the others publish to its own users. The broker decides how the
+/// backends find each other, and what happens when one of them goes away.
+pub trait Broker: Send + 'static {
+    /// Starts carrying events in tasks of its own, until `shutdown` turns true. Fails
+    /// when the broker cannot be set up at all, losing it later is for it to recover from.
+    fn start(self: Box<Self>, relays: Relays, shutdown: watch::Receiver<bool>) -> BoxFuture<Result<(), String>>;
+}
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// What backends sharing a chat tell each other, `node` is the one telling.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Envelope {
+    pub node: usize,
+    #[serde(flatten)]
+    pub relay: Relay,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Relay {
+    /// Everybody on the node, which asks the others who is on theirs
+    Hello { members: Vec<Member> },
+    /// Everybody on the node, answering a hello
+    Members { members: Vec<Member> },
+    /// For the users of the room, `from` is the user who caused it
+    Event { room: String, from: usize, event: ServerEvent },
+    /// The node is still there
+    Alive,
+    /// The node is gone, and its users with it
+    Bye,
+}
+
+/// What a broker works with: the events of this backend for the others, and the users of
+/// this backend for what the others publish.
+#[derive(Clone)]
+pub struct Relays {
+    chat: Chat,
+    node: usize,
+    /// The other backends which told us who is on them, and have not gone away since
+    peers: Arc<Mutex<HashSet<usize>>>,
+    /// Until the broker tells otherwise, the backend is ready as far as the broker goes
+    connected: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+}
+
+impl Relays {
+    pub(crate) fn new(chat: Chat, node: Node, metrics: Arc<Metrics>) -> Relays {
+        Relays { chat, node: node.index, peers: Arc::default(), connected: Arc::new(AtomicBool::new(true)), metrics }
+    }
+
+    /// Brokers which lose their connection tell it here, and once they are back, so that
+    /// the backend gets no new users in between.
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// The index of this backend, for the envelopes it sends.
+    pub fn node(&self) -> usize {
+        self.node
+    }
+
+    /// The events of this backend from now on, after who is on it. Every link to other
+    /// backends needs its own, ending it when the link is lost.
+    pub async fn link(&self) -> mpsc::UnboundedReceiver<Envelope> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.chat.write().await.add_peer(tx);
+        let (envelopes_tx, envelopes_rx) = mpsc::unbounded_channel();
+        let node = self.node;
+        let mut first = true;
+        tokio::task::spawn(async move {
+            while let Some(to_peers) = rx.recv().await {
+                let relay = match to_peers {
+                    // The link is new, the others are asked who is on theirs
+                    ToPeers::Members(members) if first => Relay::Hello { members },
+                    ToPeers::Members(members) => Relay::Members { members },
+                    ToPeers::Event { room, from, event } => Relay::Event { room, from, event },
+                };
+                first = false;
+                if envelopes_tx.send(Envelope { node, relay }).is_err() {
+                    // The link is lost, and with it the receiver
+                    return;
+                }
+            }
+        });
+        envelopes_rx
+    }
+
+    /// Sends who is on this backend down every link once more, answering a hello.
+    pub async fn introduce(&self) {
+        self.chat.read().await.introduce();
+    }
+
+    /// Hands what another backend published to the users of this one.
+    pub async fn deliver(&self, Envelope { node, relay }: Envelope) {
+        if let Relay::Hello { .. } | Relay::Members { .. } | Relay::Bye = relay {
+            let mut peers = self.peers.lock()
+                .unwrap(); // Never held across a panic
+            match relay {
+                Relay::Bye => peers.remove(&node),
+                _ => peers.insert(node),
+            };
+            self.metrics.peers.set(peers.len() as i64);
+        }
+        match relay {
+            Relay::Hello { members } | Relay::Members { members } => self.chat.read().await.sync_node(node, members),
+            Relay::Event { room, event, .. } if changes_presence(&event) =>
+                self.chat.read().await.relayed_presence(node, &room, event),
+            Relay::Event { room, from, event } => self.chat.read().await.relayed(&room, from, event),
+            Relay::Alive => {}
+            Relay::Bye => self.chat.read().await.sync_node(node, vec![]),
+        }
+    }
+}
+
+/// Whether the event changes who is in a room under which name, or the topic.
+fn changes_presence(event: &ServerEvent) -> bool {
+    matches!(event,
+        ServerEvent::Joined { .. } | ServerEvent::Left { .. } | ServerEvent::Renamed { .. } | ServerEvent::Topic { .. })
+}
+
+/// The broker configured by env variables, if any: a cluster linked directly with
+/// CLUSTER_NODES, or one sharing a Redis server with REDIS_URL.
+pub fn from_env() -> Option<(Node, Box<dyn Broker>)> {
+    if let Some(config) = ClusterConfig::from_env() {
+        return Some((config.node(), Box::new(config)));
+    }
+    RedisBroker::from_env().map(|(node, broker)| (node, Box::new(broker) as Box<dyn Broker>))
+}
+
+/// Brokers where every backend publishes to all others and hears from all others, like
+/// the channel of a Redis server. `to_bus` and `from_bus` are one connection to the
+/// bus, envelopes of this backend come back on `from_bus` too.
+///
+/// Rides the bus until it is lost or `shutdown` turns true, the latter returns `true`.
+pub async fn ride_bus(
+    relays: &Relays,
+    to_bus: mpsc::UnboundedSender<Envelope>,
+    mut from_bus: mpsc::UnboundedReceiver<Envelope>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool {
+    let own = relays.node();
+    let mut outgoing = relays.link().await;
+    // Nodes we know about, with when we heard of them last
+    let mut heard: HashMap<usize, Instant> = HashMap::new();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT);
+    let stopped = loop {
+        tokio::select! {
+            Some(envelope) = outgoing.recv() => {
+                let _ = to_bus.send(envelope);
+            }
+            envelope = from_bus.recv() => match envelope {
+                Some(Envelope { node, .. }) if node == own => {}
+                Some(envelope) => {
+                    match envelope.relay {
+                        Relay::Bye => {
+                            heard.remove(&envelope.node);
+                        }
+                        ref relay => {
+                            if let Relay::Hello { .. } = relay {
+                                relays.introduce().await;
+                            }
+                            if heard.insert(envelope.node, Instant::now()).is_none() {
+                                info!(node = envelope.node + 1, "node joined the bus");
+                            }
+                        }
+                    }
+                    relays.deliver(envelope).await;
+                }
+                None => break false,
+            },
+            _ = heartbeat.tick() => {
+                let _ = to_bus.send(Envelope { node: own, relay: Relay::Alive });
+                let silent: Vec<_> = heard.iter()
+                    .filter(|(_, last)| last.elapsed() > SILENCE)
+                    .map(|(&node, _)| node)
+                    .collect();
+                for node in silent {
+                    info!(node = node + 1, "node went silent on the bus");
+                    heard.remove(&node);
+                    relays.deliver(Envelope { node, relay: Relay::Bye }).await;
+                }
+            }
+            _ = shutdown_asked(shutdown) => break true,
+        }
+    };
+    if stopped {
+        let _ = to_bus.send(Envelope { node: own, relay: Relay::Bye });
+    }
+    // Whoever comes back says hello again
+    for (node, _) in heard {
+        relays.deliver(Envelope { node, relay: Relay::Bye }).await;
+    }
+    stopped
+}
+
+/// Completes once `shutdown` turns true.
+pub async fn shutdown_asked(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            // Nobody can ask us to stop anymore
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Runs the task until it is done or `shutdown` turns true, whichever comes first.
+pub async fn until_shutdown(mut shutdown: watch::Receiver<bool>, task: impl Future<Output = ()>) {
+    tokio::select! {
+        _ = task => {}
+        _ = shutdown_asked(&mut shutdown) => {}
+    }
+}
+
+/// A bus within this process, for backends embedded side by side like in tests.
+/// Every backend gets a clone.
+#[derive(Clone, Default)]
+pub struct InProcessBroker {
+    riders: Arc<Mutex<Vec<mpsc::UnboundedSender<Envelope>>>>,
+}
+
+impl Broker for InProcessBroker {
+    fn start(self: Box<Self>, relays: Relays, mut shutdown: watch::Receiver<bool>) -> BoxFuture<Result<(), String>> {
+        Box::pin(async move {
+            let (from_bus_tx, from_bus) = mpsc::unbounded_channel();
+            self.riders.lock()
+                .unwrap() // Never held across a panic
+                .push(from_bus_tx);
+            let (to_bus, mut to_bus_rx) = mpsc::unbounded_channel::<Envelope>();
+            let riders = self.riders.clone();
+            tokio::task::spawn(async move {
+                while let Some(envelope) = to_bus_rx.recv().await {
+                    riders.lock()
+                        .unwrap() // Never held across a panic
+                        .retain(|rider| rider.send(envelope.clone()).is_ok());
+                }
+            });
+            let span = info_span!("in_process_broker", node = relays.node() + 1);
+            tokio::task::spawn(async move {
+                ride_bus(&relays, to_bus, from_bus, &mut shutdown).await;
+            }.instrument(span));
+            Ok(())
+        })
+    }
+}
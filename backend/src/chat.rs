@@ -0,0 +1,695 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use protocol::{Attachment, ServerEvent, UserInfo, DEFAULT_ROOM};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tracing::{debug, info};
+
+use crate::auth::{self, Identity};
+use crate::broker::Node;
+use crate::metrics::Metrics;
+use crate::pipeline::{Audience, Incoming, Pipeline, Rejected};
+use crate::registry::{Presence, Registry, Sharded};
+
+/// What the writer of a websocket gets from its `Outbox`.
+pub enum Outgoing {
+    Event(ServerEvent),
+    /// Close the websocket, telling the client why.
+    Close(String),
+}
+
+/// Queues events for the websocket of a connection, keeping track of
+/// how many of them wait to be written.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::UnboundedSender<Outgoing>,
+    metrics: Arc<Metrics>,
+}
+
+impl Outbox {
+    /// The writer of the websocket has to decrement `outbound_queue_depth`
+    /// for everything it takes from `rx`.
+    pub fn new(tx: mpsc::UnboundedSender<Outgoing>, metrics: Arc<Metrics>) -> Outbox {
+        Outbox { tx, metrics }
+    }
+
+    pub fn send(&self, event: ServerEvent) -> Result<(), SendError<()>> {
+        self.queue(Outgoing::Event(event))
+    }
+
+    pub fn close(&self, reason: String) -> Result<(), SendError<()>> {
+        self.queue(Outgoing::Close(reason))
+    }
+
+    /// The error does not carry what could not be queued, nobody wants it back.
+    fn queue(&self, outgoing: Outgoing) -> Result<(), SendError<()>> {
+        self.metrics.outbound_queue_depth.inc();
+        self.tx.send(outgoing)
+            .map_err(|_| {
+                self.metrics.outbound_queue_depth.dec();
+                SendError(())
+            })
+    }
+}
+
+/// A live connection as listed by the admin API.
+#[derive(Serialize)]
+pub struct ConnectionInfo {
+    pub connection: usize,
+    pub uid: usize,
+    pub name: String,
+    pub room: String,
+    pub subject: Option<String>,
+    pub remote: Option<SocketAddr>,
+}
+
+/// A user which is connected, or has lost its connection but may still resume.
+struct User {
+    name: String,
+    room: String,
+    subject: Option<String>,
+    resume_token: String,
+    /// The connection currently serving the user.
+    connection: usize,
+    remote: Option<SocketAddr>,
+    /// `None` while the user is waiting to be resumed.
+    tx: Option<Outbox>,
+}
+
+/// A user connected to another node of the cluster.
+struct RemoteUser {
+    node: usize,
+    name: String,
+    room: String,
+}
+
+/// A user as the other nodes of the cluster learn about it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub uid: usize,
+    pub name: String,
+    pub room: String,
+}
+
+/// What goes to the other nodes of the cluster, in the order it happened here.
+pub enum ToPeers {
+    /// Everybody on this node right now
+    Members(Vec<Member>),
+    /// For the users of `room`, sent by the user with id `from`
+    Event { room: String, from: usize, event: ServerEvent },
+}
+
+/// A bot, which is in every room and gets their events, see `bots`.
+struct BotMember {
+    uid: usize,
+    name: String,
+    /// Events with the room they happened in
+    tx: mpsc::UnboundedSender<(String, ServerEvent)>,
+}
+
+/// Where a reconnecting client left off.
+pub struct Resume {
+    pub token: String,
+    pub last_seen: u64,
+}
+
+/// Who a connection turned out to be.
+pub struct Joined {
+    pub uid: usize,
+    pub room: String,
+    /// The connection took over a user which had lost its connection.
+    pub resumed: bool,
+}
+
+/// Our state of currently connected users, and the recent messages
+/// kept for users who resume after losing their connection.
+///
+/// Sending messages and users coming and going only need `&self`, so that they all go on
+/// side by side under a read lock. Users, resume tokens and rooms are sharded with a lock
+/// each instead. The write lock is left to commands and to adding bots, integrations,
+/// watchers and peers.
+///
+/// Locks are taken in this order: a shard of users, of resume tokens, of rooms.
+pub struct ChatState {
+    /// Key is the user id
+    users: Sharded<usize, User>,
+    /// Users of the other nodes of the cluster, key is the user id
+    remote: Mutex<HashMap<usize, RemoteUser>>,
+    /// Key is the resume token of the user
+    resume_tokens: Sharded<String, usize>,
+    bots: Vec<BotMember>,
+    /// Names of those posting over the HTTP API, key is the user id
+    integrations: HashMap<usize, String>,
+    /// Get every message with its room, like bots but without being in the chat
+    watchers: Vec<mpsc::UnboundedSender<(String, ServerEvent)>>,
+    /// Get every event for the users of a room, for the other nodes
+    peers: Vec<mpsc::UnboundedSender<ToPeers>>,
+    /// Who is in each room, where its events go, and what it has been talking about
+    registry: Registry,
+    node: Node,
+    last_uid: AtomicUsize,
+    resume_grace: Duration,
+    pipeline: Pipeline,
+    metrics: Arc<Metrics>,
+}
+
+impl ChatState {
+    pub fn new(resume_grace: Duration, history_size: usize, max_rooms: usize, node: Node, pipeline: Pipeline, metrics: Arc<Metrics>) -> ChatState {
+        ChatState {
+            users: Sharded::default(),
+            remote: Mutex::default(),
+            resume_tokens: Sharded::default(),
+            bots: vec![],
+            integrations: HashMap::new(),
+            watchers: vec![],
+            peers: vec![],
+            registry: Registry::new(history_size, max_rooms, node),
+            node,
+            last_uid: AtomicUsize::new(0),
+            resume_grace,
+            pipeline,
+            metrics,
+        }
+    }
+
+    /// How long a user who lost its connection is kept for resuming.
+    pub fn resume_grace(&self) -> Duration {
+        self.resume_grace
+    }
+
+    /// Users, bots and integrations share the ids, those of other nodes included.
+    fn next_uid(&self) -> usize {
+        let next = |last| self.node.next_id(last as u64) as usize;
+        let last = self.last_uid.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            .unwrap(); // Always gives a new value
+        next(last)
+    }
+
+    /// Registers a connection and tells who it is. A resumed user keeps its id and room
+    /// and gets the messages it missed, anybody else joins `room` as a new user, or the
+    /// default room when `room` would be one too many.
+    pub fn connect(
+        &self,
+        connection: usize,
+        remote: Option<SocketAddr>,
+        tx: Outbox,
+        identity: Option<Identity>,
+        room: String,
+        resume: Option<Resume>,
+    ) -> Joined {
+        let subject = identity.as_ref().map(|identity| identity.subject.clone());
+        if let Some(joined) = resume.and_then(|resume| self.resume(connection, remote, &tx, &subject, resume)) {
+            return joined;
+        }
+
+        let uid = self.next_uid();
+        // Logged in users chat under the name given by the identity provider, with a number
+        // added when somebody else goes by it already. Checked before the shard of the user
+        // is locked, as the check locks every shard.
+        let name = match identity {
+            Some(identity) => (1..)
+                .map(|n| if n == 1 { identity.name.clone() } else { format!("{}#{}", identity.name, n) })
+                .find(|name| !self.taken(uid, name))
+                .unwrap(), // Endless, some number is free
+            None => format!("User#{}", uid),
+        };
+        info!(uid, %name, "new chat user");
+        self.metrics.users.inc();
+
+        let resume_token = auth::random_token();
+        self.resume_tokens.lock(&resume_token).insert(resume_token.clone(), uid);
+        let mut users = self.users.lock(&uid);
+        let user = users.entry(uid).or_insert(User {
+            name: name.clone(),
+            room: room.clone(),
+            subject,
+            resume_token,
+            connection,
+            remote,
+            tx: Some(tx.clone()),
+        });
+        let entered = self.registry.enter(&room, uid, name.clone(), tx.clone(),
+            |presence| self.welcome(uid, user, presence),
+            |event| self.forward(uid, &room, event));
+        if entered {
+            return Joined { uid, room, resumed: false };
+        }
+        info!(uid, %room, "too many rooms, joining the default room instead");
+        user.room = DEFAULT_ROOM.to_owned();
+        let entered = self.registry.enter(DEFAULT_ROOM, uid, name, tx.clone(),
+            |presence| self.welcome(uid, user, presence),
+            |event| self.forward(uid, DEFAULT_ROOM, event));
+        debug_assert!(entered, "the default room is never one too many");
+        let _ = tx.send(ServerEvent::Notice { text: format!("There are too many rooms to open {}, you are in {}", room, DEFAULT_ROOM) });
+        Joined { uid, room: DEFAULT_ROOM.to_owned(), resumed: false }
+    }
+
+    /// Takes over the user with the token, unless it has left or been taken over since.
+    fn resume(
+        &self,
+        connection: usize,
+        remote: Option<SocketAddr>,
+        tx: &Outbox,
+        subject: &Option<String>,
+        Resume { token, last_seen }: Resume,
+    ) -> Option<Joined> {
+        let uid = *self.resume_tokens.lock(&token).get(&token)?;
+        let mut users = self.users.lock(&uid);
+        let user = users.get_mut(&uid)
+            .filter(|user| user.resume_token == token)?;
+        // A logged in user may only resume itself
+        if user.subject != *subject {
+            return None;
+        }
+
+        // Any older connection is still unaware that it has been replaced
+        user.connection = connection;
+        user.remote = remote;
+        user.tx = Some(tx.clone());
+        user.resume_token = auth::random_token();
+        self.resume_tokens.lock(&token).remove(&token);
+        self.resume_tokens.lock(&user.resume_token).insert(user.resume_token.clone(), uid);
+
+        // Only messages of others, the user has seen its own ones
+        let user = &*user;
+        let missed = self.registry.resume(&user.room, uid, last_seen, tx.clone(),
+            |presence| self.welcome(uid, user, presence));
+        info!(uid, missed, "chat user resumed");
+        Some(Joined { uid, room: user.room.clone(), resumed: true })
+    }
+
+    /// New message from this user, once it has passed the pipeline send it to everyone
+    /// else in its room (except same uid)...
+    pub fn message(&self, uid: usize, text: String, attachments: Vec<Attachment>) {
+        self.submit(uid, text, attachments, |chat, message| {
+            chat.broadcast_message(message);
+        });
+    }
+
+    /// An action of this user, e.g. "/me waves", for everybody in its room once it has
+    /// passed the pipeline like any message.
+    pub fn emote(&self, uid: usize, text: String) {
+        self.submit(uid, text, vec![], |chat, Incoming { uid, name, room, text, .. }| {
+            chat.to_room(uid, &room, ServerEvent::Emote { uid, name, text });
+        });
+    }
+
+    /// Adds a bot to every room and returns its id, the bot gets the events of all rooms
+    /// but those caused by bots.
+    pub fn add_bot(&mut self, name: String, tx: mpsc::UnboundedSender<(String, ServerEvent)>) -> usize {
+        let uid = self.next_uid();
+        info!(uid, %name, "new bot");
+        self.bots.push(BotMember { uid, name, tx });
+        uid
+    }
+
+    /// A message of the bot for everybody in `room`, bots are trusted to skip the pipeline.
+    pub fn bot_message(&self, uid: usize, room: String, text: String) {
+        let name = match self.bots.iter().find(|bot| bot.uid == uid) {
+            Some(bot) => bot.name.clone(),
+            None => return,
+        };
+        self.broadcast_message(Incoming { uid, name, room, text, attachments: vec![] });
+    }
+
+    /// Every message broadcast from now on is sent to `tx` along with its room.
+    pub fn watch_messages(&mut self, tx: mpsc::UnboundedSender<(String, ServerEvent)>) {
+        // A watcher which has stopped is gone for good
+        self.watchers.retain(|tx| !tx.is_closed());
+        self.watchers.push(tx);
+    }
+
+    /// Registers an integration which posts over the HTTP API and returns its id.
+    pub fn add_integration(&mut self, name: String) -> usize {
+        let uid = self.next_uid();
+        info!(uid, %name, "new integration");
+        self.integrations.insert(uid, name);
+        uid
+    }
+
+    /// A message of the integration for everybody in `room`, it passes the pipeline like
+    /// any message. Returns the id of the message, or why it was rejected.
+    pub fn integration_message(&self, uid: usize, room: String, text: String) -> Result<u64, String> {
+        let name = match self.integrations.get(&uid) {
+            Some(name) => name.clone(),
+            None => return Err("Unknown integration".to_owned()),
+        };
+        self.process(
+            Incoming { uid, name, room, text, attachments: vec![] },
+            |chat, message| Ok(chat.broadcast_message(message)),
+            |_, Rejected(reason)| Err(reason))
+    }
+
+    /// Every event for the users of a room goes to `tx` from now on, for other nodes of
+    /// the cluster. It gets the users of this node first, who the others have to know about.
+    pub fn add_peer(&mut self, tx: mpsc::UnboundedSender<ToPeers>) {
+        self.peers.retain(|tx| !tx.is_closed());
+        let _ = tx.send(ToPeers::Members(self.members()));
+        self.peers.push(tx);
+    }
+
+    /// Tells the other nodes who is on this one again, for nodes which just joined.
+    pub fn introduce(&self) {
+        let members = self.members();
+        for tx in self.peers.iter() {
+            let _ = tx.send(ToPeers::Members(members.clone()));
+        }
+    }
+
+    fn members(&self) -> Vec<Member> {
+        self.users.each()
+            .flat_map(|users| users.iter()
+                .map(|(&uid, user)| Member { uid, name: user.name.clone(), room: user.room.clone() })
+                .collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// An event in `room` which happened on another node, for the users of this node.
+    /// Joins, leaves, renames and topics go to `relayed_presence` instead.
+    pub fn relayed(&self, room: &str, from: usize, event: ServerEvent) {
+        match event {
+            ServerEvent::Message { id, .. } => self.registry.publish_relayed(room, from, id, event),
+            event => self.registry.broadcast(room, from, event, |_| {}),
+        };
+    }
+
+    /// A join, leave, rename or topic in `room` on the node, for the users of this node.
+    pub fn relayed_presence(&self, node: usize, room: &str, event: ServerEvent) {
+        {
+            let mut remote = self.remote.lock()
+                .unwrap(); // Never held across a panic
+            match &event {
+                ServerEvent::Joined { uid, name } => {
+                    remote.insert(*uid, RemoteUser { node, name: name.clone(), room: room.to_owned() });
+                }
+                ServerEvent::Left { uid, .. } => {
+                    remote.remove(uid);
+                }
+                ServerEvent::Renamed { uid, name } => {
+                    if let Some(user) = remote.get_mut(uid) {
+                        user.name = name.clone();
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Ids start at 1, every user of this node gets it
+        self.registry.broadcast(room, 0, event, |_| {});
+    }
+
+    /// The node has `members` now, after it (re)connected or with none once it is gone.
+    /// Tells the users of this node who joined and left in the meantime.
+    pub fn sync_node(&self, node: usize, members: Vec<Member>) {
+        let gone: Vec<_> = self.remote.lock()
+            .unwrap() // Never held across a panic
+            .iter()
+            .filter(|(uid, user)| user.node == node && !members.iter().any(|member| member.uid == **uid && member.room == user.room))
+            .map(|(&uid, user)| (uid, user.name.clone(), user.room.clone()))
+            .collect();
+        for (uid, name, room) in gone {
+            self.relayed_presence(node, &room, ServerEvent::Left { uid, name });
+        }
+        for Member { uid, name, room } in members {
+            let known = self.remote.lock()
+                .unwrap() // Never held across a panic
+                .get(&uid)
+                .map(|user| user.name.clone());
+            match known {
+                Some(known) if known != name => self.relayed_presence(node, &room, ServerEvent::Renamed { uid, name }),
+                Some(_) => {}
+                None => self.relayed_presence(node, &room, ServerEvent::Joined { uid, name }),
+            }
+        }
+    }
+
+    /// Renames the user unless somebody else in the chat has this name already.
+    /// Takes `&mut self`, so that nobody else is renamed at the same time.
+    pub fn rename(&mut self, uid: usize, name: String) -> Result<(), String> {
+        if self.taken(uid, &name) {
+            return Err(format!("The name {} is taken", name));
+        }
+        let room = {
+            let mut users = self.users.lock(&uid);
+            let user = match users.get_mut(&uid) {
+                Some(user) => user,
+                None => return Ok(()),
+            };
+            if user.subject.is_some() {
+                return Err("Your name comes from your login".to_owned());
+            }
+            info!(uid, old = %user.name, new = %name, "renaming user");
+            user.name = name.clone();
+            user.room.clone()
+        };
+        self.to_room(uid, &room, ServerEvent::Renamed { uid, name });
+        Ok(())
+    }
+
+    /// Whether anybody but `uid` goes by the name, here or on other nodes.
+    fn taken(&self, uid: usize, name: &str) -> bool {
+        self.users.each().any(|users| users.iter().any(|(&other, user)| other != uid && user.name == name))
+            || self.bots.iter().any(|bot| bot.name == name)
+            || self.integrations.values().any(|integration| integration == name)
+            || self.remote.lock()
+                .unwrap() // Never held across a panic
+                .values().any(|user| user.name == name)
+    }
+
+    /// The names of the users in the room of this user.
+    pub fn names(&self, uid: usize) -> Vec<String> {
+        let room = match self.room_of(uid) {
+            Some(room) => room,
+            None => return vec![],
+        };
+        let mut names: Vec<_> = self.registry.presence(&room).users.into_iter()
+            .map(|user| user.name)
+            .chain(self.bots.iter().map(|bot| bot.name.clone()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn topic(&self, uid: usize) -> Option<String> {
+        self.registry.presence(&self.room_of(uid)?).topic
+    }
+
+    /// Sets the topic of the room of this user and tells everybody in it, once it has
+    /// passed the pipeline like any message.
+    pub fn set_topic(&self, uid: usize, topic: String) {
+        let (name, room) = match self.name_and_room(uid) {
+            Some(found) => found,
+            None => return,
+        };
+        let passed = self.process(
+            Incoming { uid, name, room, text: topic, attachments: vec![] },
+            |_, message| Some(message),
+            |chat, Rejected(reason)| {
+                chat.notice(uid, reason);
+                None
+            });
+        let Incoming { name, room, text: topic, .. } = match passed {
+            Some(message) => message,
+            None => return,
+        };
+        info!(uid, %topic, "topic changed");
+        self.to_room(uid, &room, ServerEvent::Topic { name, topic });
+    }
+
+    /// Tells only this user.
+    pub fn notice(&self, uid: usize, text: String) {
+        self.send_to(uid, ServerEvent::Notice { text });
+    }
+
+    /// Runs the text of this user through the pipeline, and `deliver`s it unless it is rejected.
+    fn submit(&self, uid: usize, text: String, attachments: Vec<Attachment>, deliver: impl FnOnce(&Self, Incoming)) {
+        let (name, room) = match self.name_and_room(uid) {
+            Some(found) => found,
+            None => return,
+        };
+        self.process(Incoming { uid, name, room, text, attachments }, deliver, |chat, Rejected(reason)| {
+            chat.notice(uid, reason);
+        });
+    }
+
+    /// Runs the message through the pipeline, and either `deliver`s or `reject`s it.
+    /// The events emitted by the pipeline go out after that.
+    fn process<R>(
+        &self,
+        mut message: Incoming,
+        deliver: impl FnOnce(&Self, Incoming) -> R,
+        reject: impl FnOnce(&Self, Rejected) -> R,
+    ) -> R {
+        self.metrics.messages_received.inc();
+        let (uid, room) = (message.uid, message.room.clone());
+        let mut emitted = vec![];
+        let result = match self.pipeline.process(&mut message, &mut emitted) {
+            Ok(()) => deliver(self, message),
+            Err(rejected) => reject(self, rejected),
+        };
+        for (audience, event) in emitted {
+            match audience {
+                Audience::Sender => self.send_to(uid, event),
+                Audience::Room => {
+                    self.broadcast(uid, &room, event.clone());
+                    self.send_to(uid, event);
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the id the message got.
+    fn broadcast_message(&self, Incoming { uid, name, room, text, attachments }: Incoming) -> u64 {
+        let message = |id| ServerEvent::Message { id, uid, name, text, attachments };
+        let (id, recipients) = self.registry.publish(&room, uid, message, |event| {
+            self.forward(uid, &room, event);
+            for tx in self.watchers.iter() {
+                let _ = tx.send((room.clone(), event.clone()));
+            }
+        });
+        debug!(id, recipients, "message broadcast");
+        self.metrics.messages_broadcast.inc_by(recipients);
+        id
+    }
+
+    /// The connection is gone, but the user stays until it resumes or `leave` is called.
+    /// Returns `false` when another connection has taken over the user already.
+    pub fn detach(&self, uid: usize, connection: usize) -> bool {
+        match self.users.lock(&uid).get_mut(&uid) {
+            Some(user) if user.connection == connection => {
+                user.tx = None;
+                self.registry.detach(&user.room, uid);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the user, unless it has resumed since `connection` was detached.
+    pub fn leave(&self, uid: usize, connection: usize) {
+        let mut users = self.users.lock(&uid);
+        let resumed = match users.get(&uid) {
+            Some(user) => user.connection != connection || user.tx.is_some(),
+            None => return,
+        };
+        if !resumed {
+            info!(uid, "good bye user");
+            self.remove(&mut users, uid);
+        }
+    }
+
+    /// The users which are connected right now, without those waiting to be resumed.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.users.each()
+            .flat_map(|users| users.iter()
+                .filter(|(_, user)| user.tx.is_some())
+                .map(|(&uid, user)| ConnectionInfo {
+                    connection: user.connection,
+                    uid,
+                    name: user.name.clone(),
+                    room: user.room.clone(),
+                    subject: user.subject.clone(),
+                    remote: user.remote,
+                })
+                .collect::<Vec<_>>())
+            .collect();
+        connections.sort_by_key(|info| info.connection);
+        connections
+    }
+
+    /// Closes the connection and removes its user right away, so that it cannot resume.
+    /// Returns `false` when there is no such live connection.
+    pub fn kick(&self, connection: usize, reason: &str) -> bool {
+        for mut users in self.users.each() {
+            let uid = match users.iter().find(|(_, user)| user.connection == connection && user.tx.is_some()) {
+                Some((&uid, _)) => uid,
+                None => continue,
+            };
+            info!(uid, connection, reason, "kicking user");
+            if let Some(tx) = &users[&uid].tx {
+                let _ = tx.close(reason.to_owned());
+            }
+            self.remove(&mut users, uid);
+            return true;
+        }
+        false
+    }
+
+    /// `users` is the locked shard of the user.
+    fn remove(&self, users: &mut HashMap<usize, User>, uid: usize) {
+        self.metrics.users.dec();
+        let User { name, room, resume_token, .. } = users.remove(&uid).unwrap(); // Callers check the user exists
+        self.resume_tokens.lock(&resume_token).remove(&resume_token);
+        self.broadcast(uid, &room, ServerEvent::Left { uid, name });
+    }
+
+    fn welcome(&self, uid: usize, user: &User, Presence { users, topic, last_id }: Presence) -> ServerEvent {
+        let users = users.into_iter()
+            .chain(self.bots.iter().map(|bot| UserInfo { uid: bot.uid, name: bot.name.clone() }))
+            .collect();
+        ServerEvent::Welcome {
+            uid,
+            name: user.name.clone(),
+            room: user.room.clone(),
+            topic,
+            resume_token: user.resume_token.clone(),
+            last_id,
+            users,
+        }
+    }
+
+    fn name_and_room(&self, uid: usize) -> Option<(String, String)> {
+        self.users.lock(&uid).get(&uid).map(|user| (user.name.clone(), user.room.clone()))
+    }
+
+    fn room_of(&self, uid: usize) -> Option<String> {
+        self.users.lock(&uid).get(&uid).map(|user| user.room.clone())
+    }
+
+    /// Sends the event to everybody in the room of this user, the user included.
+    fn to_room(&self, uid: usize, room: &str, event: ServerEvent) {
+        self.broadcast(uid, room, event.clone());
+        self.send_to(uid, event);
+    }
+
+    fn send_to(&self, uid: usize, event: ServerEvent) {
+        if let Some(tx) = self.users.lock(&uid).get(&uid).and_then(|user| user.tx.as_ref()) {
+            let _ = tx.send(event);
+        }
+    }
+
+    /// Sends the event to the users in `room` and to the bots, returns the number of users
+    /// it was queued for.
+    fn broadcast(&self, from: usize, room: &str, event: ServerEvent) -> u64 {
+        self.registry.broadcast(room, from, event, |event| self.forward(from, room, event))
+    }
+
+    /// What goes to the users of a room goes to the bots and the other nodes too.
+    fn forward(&self, from: usize, room: &str, event: &ServerEvent) {
+        self.to_bots(from, room, event);
+        self.to_peers(from, room, event);
+    }
+
+    fn to_peers(&self, from: usize, room: &str, event: &ServerEvent) {
+        for tx in self.peers.iter() {
+            // Reconnecting, the node gets everything it needs once it is back
+            let _ = tx.send(ToPeers::Event { room: room.to_owned(), from, event: event.clone() });
+        }
+    }
+
+    fn to_bots(&self, from: usize, room: &str, event: &ServerEvent) {
+        if self.bots.iter().any(|bot| bot.uid == from) {
+            // Bots answering each other would never stop
+            return;
+        }
+        for bot in self.bots.iter() {
+            // A bot which has stopped is gone for good
+            let _ = bot.tx.send((room.to_owned(), event.clone()));
+        }
+    }
+}
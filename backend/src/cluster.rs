@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::broker::{until_shutdown, BoxFuture, Broker, Envelope, Node, Relay, Relays};
+
+/// Before dialing a node again which could not be reached or went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// For a node dialing this one to tell the secret.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A broker linking every node with every other one over TCP, where each sends the
+/// events of its rooms to all others as lines of JSON, after a first line with the secret.
+/// Links are not encrypted, the addresses must not be reachable from outside the cluster.
+pub struct ClusterConfig {
+    /// Where the nodes listen for each other as `host:port`, the same list on every node
+    pub nodes: Vec<String>,
+    /// Which of the nodes this is, counting from 0
+    pub node: usize,
+    /// Shared by all nodes, links which do not start with it are dropped
+    pub secret: String,
+}
+
+impl ClusterConfig {
+    /// The nodes in CLUSTER_NODES separated by commas, this one in CLUSTER_NODE counting
+    /// from 1, and the secret they share in CLUSTER_SECRET.
+    pub fn from_env() -> Option<ClusterConfig> {
+        let nodes: Vec<_> = std::env::var("CLUSTER_NODES").ok()?
+            .split(',')
+            .map(str::trim)
+            .filter(|node| !node.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let node = std::env::var("CLUSTER_NODE")
+            .expect("Missing env variable CLUSTER_NODE containing the number of this node in CLUSTER_NODES");
+        let node = node.parse::<usize>().ok().filter(|&node| node >= 1 && node <= nodes.len())
+            .unwrap_or_else(|| panic!("Env variable CLUSTER_NODE contains no number of a node in CLUSTER_NODES: {}", node));
+        let secret = std::env::var("CLUSTER_SECRET").ok().filter(|secret| !secret.is_empty())
+            .expect("Missing env variable CLUSTER_SECRET containing the secret all nodes in CLUSTER_NODES share");
+        if secret.contains('\n') {
+            panic!("Env variable CLUSTER_SECRET must be a single line");
+        }
+        Some(ClusterConfig { nodes, node: node - 1, secret })
+    }
+
+    pub fn node(&self) -> Node {
+        Node { index: self.node, count: self.nodes.len() }
+    }
+}
+
+impl Broker for ClusterConfig {
+    /// Listens for the other nodes and dials each of them. A node which goes away takes
+    /// its users along, they leave until it is back. Fails when the address of this node
+    /// cannot be bound.
+    fn start(self: Box<Self>, relays: Relays, shutdown: watch::Receiver<bool>) -> BoxFuture<Result<(), String>> {
+        Box::pin(async move {
+            let own = &self.nodes[self.node];
+            let listener = TcpListener::bind(own.as_str()).await
+                .map_err(|e| format!("Could not bind cluster address {}: {}", own, e))?;
+            info!(node = self.node + 1, nodes = self.nodes.len(), "cluster listening on {}", own);
+
+            let (listening_relays, listening_shutdown) = (relays.clone(), shutdown.clone());
+            let secret = Arc::new(self.secret.clone());
+            tokio::task::spawn(until_shutdown(shutdown.clone(), async move {
+                // The latest link of every node, an older one which ends has been replaced
+                let links = Arc::new(Mutex::new(HashMap::new()));
+                let mut last_link = 0;
+                loop {
+                    // Not ready while other nodes cannot link to this one
+                    let (stream, remote) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("could not accept cluster link: {}", e);
+                            listening_relays.set_connected(false);
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                            continue;
+                        }
+                    };
+                    listening_relays.set_connected(true);
+                    last_link += 1;
+                    let span = info_span!("cluster_link", %remote);
+                    let receiving = receive(stream, secret.clone(), last_link, links.clone(), listening_relays.clone());
+                    tokio::task::spawn(until_shutdown(listening_shutdown.clone(), receiving).instrument(span));
+                }
+            }));
+
+            for (index, peer) in self.nodes.iter().enumerate().filter(|(index, _)| *index != self.node) {
+                let span = info_span!("cluster_peer", node = index + 1, %peer);
+                let dialing = dial(peer.clone(), self.secret.clone(), relays.clone());
+                tokio::task::spawn(until_shutdown(shutdown.clone(), dialing).instrument(span));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Sends the events of this node to the peer, and connects again whenever the link is lost.
+async fn dial(peer: String, secret: String, relays: Relays) {
+    loop {
+        match TcpStream::connect(peer.as_str()).await {
+            Ok(mut stream) => {
+                info!("cluster peer connected");
+                if let Err(e) = stream.write_all(format!("{}\n", secret).as_bytes()).await {
+                    warn!("lost cluster peer: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+                let mut outgoing = relays.link().await;
+                while let Some(envelope) = outgoing.recv().await {
+                    let mut line = serde_json::to_vec(&envelope)
+                        .unwrap(); // Events and members serialize fine
+                    line.push(b'\n');
+                    if let Err(e) = stream.write_all(&line).await {
+                        warn!("lost cluster peer: {}", e);
+                        break;
+                    }
+                }
+            }
+            Err(e) => warn!("could not reach cluster peer: {}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Hands the events of the node on the other end to the users of this node, until
+/// the link is lost. Drops links which do not tell the secret first.
+async fn receive(stream: TcpStream, secret: Arc<String>, link: u64, links: Arc<Mutex<HashMap<usize, u64>>>, relays: Relays) {
+    let mut reader = BufReader::new(stream);
+    if !knows(&mut reader, &secret).await {
+        warn!("cluster link without the secret dropped");
+        return;
+    }
+    let mut lines = reader.lines();
+    let mut node = None;
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("lost cluster link: {}", e);
+                break;
+            }
+        };
+        let envelope: Envelope = match serde_json::from_str(&line) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("unexpected cluster relay: {}", e);
+                break;
+            }
+        };
+        match (&envelope.relay, node) {
+            (Relay::Hello { members }, None) => {
+                info!(node = envelope.node + 1, members = members.len(), "cluster link up");
+                links.lock()
+                    .unwrap() // Never held across a panic
+                    .insert(envelope.node, link);
+                node = Some(envelope.node);
+            }
+            (Relay::Hello { .. }, Some(_)) | (_, None) => {
+                warn!("cluster link out of order");
+                break;
+            }
+            (_, Some(node)) if node != envelope.node => {
+                warn!("cluster link of node {} relays for node {}", node + 1, envelope.node + 1);
+                break;
+            }
+            _ => {}
+        }
+        relays.deliver(envelope).await;
+    }
+
+    if let Some(node) = node {
+        let replaced = links.lock()
+            .unwrap() // Never held across a panic
+            .get(&node) != Some(&link);
+        if !replaced {
+            info!(node = node + 1, "cluster link down, its users leave");
+            relays.deliver(Envelope { node, relay: Relay::Bye }).await;
+        }
+    }
+}
+
+/// Whether the first line is the secret, read no further than its length.
+async fn knows(reader: &mut BufReader<TcpStream>, secret: &str) -> bool {
+    let mut line = String::new();
+    let mut first_line = (&mut *reader).take(secret.len() as u64 + 1);
+    if !matches!(tokio::time::timeout(HANDSHAKE_TIMEOUT, first_line.read_line(&mut line)).await, Ok(Ok(_))) {
+        return false;
+    }
+    let told = line.strip_suffix('\n').unwrap_or("").as_bytes();
+    // Takes as long wherever they differ
+    told.len() == secret.len() && told.iter().zip(secret.as_bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
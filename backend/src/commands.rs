@@ -0,0 +1,82 @@
+use crate::chat::ChatState;
+
+/// The longest name users may give themselves.
+const MAX_NAME_LEN: usize = 32;
+/// Names of new users start with it, nobody else may take such a name.
+const RESERVED_NAME_PREFIX: &str = "User#";
+
+const HELP: &str = "Commands: /nick <name> changes your name, /me <action> tells what you do, \
+    /who lists the users in the room, /topic [topic] shows or sets the topic of the room, /help shows this";
+
+/// A command typed into the message input.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// A plain message which starts with a slash, typed with two of them.
+    Say(String),
+    Nick(String),
+    Me(String),
+    Who,
+    Help,
+    /// Shows the topic without one, sets it otherwise.
+    Topic(Option<String>),
+}
+
+/// `None` for plain messages, an error for commands which are unknown or lack their argument.
+pub fn parse(text: &str) -> Option<Result<Command, String>> {
+    let command = text.strip_prefix('/')?;
+    if command.starts_with('/') {
+        return Some(Ok(Command::Say(command.to_owned())));
+    }
+
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (command, ""),
+    };
+    let required = |argument: &str, usage: &str| match argument {
+        "" => Err(format!("Usage: {}", usage)),
+        argument => Ok(argument.to_owned()),
+    };
+    Some(match name {
+        "nick" => required(argument, "/nick <name>").and_then(|name| {
+            if name.chars().count() > MAX_NAME_LEN || name.contains(char::is_whitespace) {
+                Err(format!("Names are a single word of at most {} characters", MAX_NAME_LEN))
+            } else if name.starts_with(RESERVED_NAME_PREFIX) {
+                Err(format!("Names starting with {} are given to new users", RESERVED_NAME_PREFIX))
+            } else {
+                Ok(Command::Nick(name))
+            }
+        }),
+        "me" => required(argument, "/me <action>").map(Command::Me),
+        "who" => Ok(Command::Who),
+        "help" => Ok(Command::Help),
+        "topic" if argument.is_empty() => Ok(Command::Topic(None)),
+        "topic" => Ok(Command::Topic(Some(argument.to_owned()))),
+        _ => Err(format!("Unknown command /{}, try /help", name)),
+    })
+}
+
+/// Answers go to the issuer only, what changes the room is announced to the room.
+pub fn run(chat: &mut ChatState, uid: usize, command: Command) {
+    match command {
+        Command::Say(text) => chat.message(uid, text, vec![]),
+        Command::Nick(name) => {
+            if let Err(error) = chat.rename(uid, name) {
+                chat.notice(uid, error);
+            }
+        }
+        Command::Me(action) => chat.emote(uid, action),
+        Command::Who => {
+            let names = chat.names(uid).join(", ");
+            chat.notice(uid, format!("In this room: {}", names));
+        }
+        Command::Help => chat.notice(uid, HELP.to_owned()),
+        Command::Topic(None) => {
+            let answer = match chat.topic(uid) {
+                Some(topic) => format!("The topic is: {}", topic),
+                None => "There is no topic".to_owned(),
+            };
+            chat.notice(uid, answer);
+        }
+        Command::Topic(Some(topic)) => chat.set_topic(uid, topic),
+    }
+}
@@ -0,0 +1,791 @@
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{CloseCode, Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::{CloseFrame, Frame, FrameHeader};
+use tracing::info;
+
+use crate::metrics::Metrics;
+
+/// Ends every compressed message, and is left out of it on the wire.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Compression of websocket messages on /chat with permessage-deflate (RFC 7692), for
+/// clients which offer it.
+#[derive(Clone, Debug)]
+pub struct DeflateConfig {
+    /// 9 to 15, both sides keep a window of 2^bits bytes per connection at most
+    pub window_bits: u8,
+    /// Whether a message is compressed with what the messages before it had, which is
+    /// what makes chatty rooms compress well. Without it, connections free their
+    /// compression state in between messages.
+    pub context_takeover: bool,
+    /// 0 to 9, from fast to small, of the messages we send
+    pub level: u32,
+    /// Messages which are larger, or grow larger when inflated, close the connection with
+    /// 1009 Message Too Big. Inflating stops right there.
+    pub max_message_size: usize,
+    /// Compressed frames for a connection are held back beyond this many bytes, until
+    /// the connection takes them
+    pub max_pending: usize,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            window_bits: 15,
+            context_takeover: true,
+            level: Compression::default().level(),
+            max_message_size: 64 << 20,
+            max_pending: 64 << 10,
+        }
+    }
+}
+
+impl DeflateConfig {
+    /// Compression is on when WS_DEFLATE is `true`, `None` means it is off.
+    pub fn from_env() -> Option<DeflateConfig> {
+        let enabled = std::env::var("WS_DEFLATE").unwrap_or_else(|_| "false".to_owned());
+        if !parse_bool("WS_DEFLATE", &enabled) {
+            return None;
+        }
+        let default = DeflateConfig::default();
+        let window_bits = std::env::var("WS_DEFLATE_WINDOW_BITS").unwrap_or_else(|_| "15".to_owned());
+        let window_bits = window_bits.parse::<u8>().ok().filter(|bits| (9..=15).contains(bits))
+            .unwrap_or_else(|| panic!("Env variable WS_DEFLATE_WINDOW_BITS contains no number from 9 to 15: {}", window_bits));
+        let context_takeover = std::env::var("WS_DEFLATE_CONTEXT_TAKEOVER").unwrap_or_else(|_| "true".to_owned());
+        let context_takeover = parse_bool("WS_DEFLATE_CONTEXT_TAKEOVER", &context_takeover);
+        let level = std::env::var("WS_DEFLATE_LEVEL").unwrap_or_else(|_| default.level.to_string());
+        let level = level.parse::<u32>().ok().filter(|level| *level <= 9)
+            .unwrap_or_else(|| panic!("Env variable WS_DEFLATE_LEVEL contains no number from 0 to 9: {}", level));
+        Some(DeflateConfig {
+            window_bits,
+            context_takeover,
+            level,
+            max_message_size: parse_size("WS_DEFLATE_MAX_MESSAGE_SIZE", default.max_message_size),
+            max_pending: parse_size("WS_DEFLATE_MAX_PENDING", default.max_pending),
+        })
+    }
+
+    pub fn with_window_bits(mut self, bits: u8) -> DeflateConfig {
+        self.window_bits = bits;
+        self
+    }
+
+    pub fn with_context_takeover(mut self, context_takeover: bool) -> DeflateConfig {
+        self.context_takeover = context_takeover;
+        self
+    }
+
+    pub fn with_level(mut self, level: u32) -> DeflateConfig {
+        self.level = level;
+        self
+    }
+
+    pub fn with_max_message_size(mut self, size: usize) -> DeflateConfig {
+        self.max_message_size = size;
+        self
+    }
+
+    pub fn with_max_pending(mut self, size: usize) -> DeflateConfig {
+        self.max_pending = size;
+        self
+    }
+
+    /// The first of the offers in `Sec-WebSocket-Extensions` which we can agree to.
+    pub fn negotiate(&self, offered: &str) -> Option<Agreed> {
+        offered.split(',').find_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            if params.next() != Some("permessage-deflate") {
+                return None;
+            }
+            self.agree(params)
+        })
+    }
+
+    fn agree<'a>(&self, params: impl Iterator<Item=&'a str>) -> Option<Agreed> {
+        let mut agreed = Agreed {
+            server_window_bits: self.window_bits,
+            client_window_bits: None,
+            server_context_takeover: self.context_takeover,
+            client_context_takeover: self.context_takeover,
+            server_window_bits_offered: false,
+            level: self.level,
+            max_message_size: self.max_message_size,
+            max_pending: self.max_pending,
+        };
+        let mut seen = vec![];
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            let bits = || value.and_then(|value| value.parse::<u8>().ok()).filter(|bits| (8..=15).contains(bits));
+            match (name, value) {
+                ("server_no_context_takeover", None) => agreed.server_context_takeover = false,
+                ("client_no_context_takeover", None) => agreed.client_context_takeover = false,
+                // The compressor cannot keep to a window of 256 bytes
+                ("server_max_window_bits", Some(_)) => {
+                    agreed.server_window_bits = agreed.server_window_bits.min(bits().filter(|&bits| bits >= 9)?);
+                    agreed.server_window_bits_offered = true;
+                }
+                ("client_max_window_bits", None) => agreed.client_window_bits = Some(self.window_bits),
+                ("client_max_window_bits", Some(_)) => agreed.client_window_bits = Some(self.window_bits.min(bits()?)),
+                _ => return None,
+            }
+        }
+        Some(agreed)
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> bool {
+    match value {
+        "true" => true,
+        "false" => false,
+        _ => panic!("Env variable {} contains neither true nor false: {}", name, value),
+    }
+}
+
+/// Bytes, `default` when the variable is not set.
+fn parse_size(name: &str, default: usize) -> usize {
+    let size = match std::env::var(name) {
+        Ok(size) => size,
+        Err(_) => return default,
+    };
+    let size = size.parse::<usize>()
+        .unwrap_or_else(|_| panic!("Env variable {} contains non numeric value: {}", name, size));
+    if size == 0 {
+        panic!("Env variable {} must be positive", name);
+    }
+    size
+}
+
+/// What a client and we agreed on for its connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Agreed {
+    server_window_bits: u8,
+    /// `None` when the client offered no limit on its window, it may use 15 bits then
+    client_window_bits: Option<u8>,
+    server_context_takeover: bool,
+    client_context_takeover: bool,
+    server_window_bits_offered: bool,
+    /// Not negotiated, taken over from the config for the connection
+    level: u32,
+    max_message_size: usize,
+    max_pending: usize,
+}
+
+impl Agreed {
+    /// What goes into the `Sec-WebSocket-Extensions` of the response.
+    pub fn response(&self) -> String {
+        let mut response = "permessage-deflate".to_owned();
+        if !self.server_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if !self.client_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if self.server_window_bits < 15 || self.server_window_bits_offered {
+            response.push_str(&format!("; server_max_window_bits={}", self.server_window_bits));
+        }
+        if let Some(bits) = self.client_window_bits.filter(|&bits| bits < 15) {
+            response.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        response
+    }
+}
+
+/// The bytes of the messages of a connection before and after compression, counted
+/// into the metrics as well.
+pub struct Ratio {
+    metrics: Arc<Metrics>,
+    sent: AtomicU64,
+    sent_compressed: AtomicU64,
+    received: AtomicU64,
+    received_compressed: AtomicU64,
+}
+
+impl Ratio {
+    pub fn new(metrics: Arc<Metrics>) -> Ratio {
+        Ratio {
+            metrics,
+            sent: AtomicU64::default(),
+            sent_compressed: AtomicU64::default(),
+            received: AtomicU64::default(),
+            received_compressed: AtomicU64::default(),
+        }
+    }
+
+    fn sent(&self, size: usize, compressed: usize) {
+        self.sent.fetch_add(size as u64, Ordering::Relaxed);
+        self.sent_compressed.fetch_add(compressed as u64, Ordering::Relaxed);
+        self.metrics.deflate_sent_bytes.inc_by(size as u64);
+        self.metrics.deflate_sent_compressed_bytes.inc_by(compressed as u64);
+    }
+
+    fn received(&self, size: usize, compressed: usize) {
+        self.received.fetch_add(size as u64, Ordering::Relaxed);
+        self.received_compressed.fetch_add(compressed as u64, Ordering::Relaxed);
+        self.metrics.deflate_received_bytes.inc_by(size as u64);
+        self.metrics.deflate_received_compressed_bytes.inc_by(compressed as u64);
+    }
+
+    /// Logs how well the messages of the connection compressed so far.
+    pub fn log(&self) {
+        let ratio = |size: &AtomicU64, compressed: &AtomicU64| {
+            let (size, compressed) = (size.load(Ordering::Relaxed), compressed.load(Ordering::Relaxed));
+            format!("{:.2}", size as f64 / compressed.max(1) as f64)
+        };
+        info!(
+            sent = self.sent.load(Ordering::Relaxed),
+            sent_compressed = self.sent_compressed.load(Ordering::Relaxed),
+            sent_ratio = %ratio(&self.sent, &self.sent_compressed),
+            received = self.received.load(Ordering::Relaxed),
+            received_compressed = self.received_compressed.load(Ordering::Relaxed),
+            received_ratio = %ratio(&self.received, &self.received_compressed),
+            "compression");
+    }
+}
+
+/// Sits between a connection and the websocket on it, and compresses the messages the
+/// websocket writes as well as inflates those it reads, frame by frame. Passes everything
+/// through as it is when nothing was agreed.
+pub struct Deflating<S> {
+    io: S,
+    deflate: Option<Deflate>,
+}
+
+impl<S> Deflating<S> {
+    pub fn new(io: S, agreed: Option<(Agreed, Arc<Ratio>)>) -> Deflating<S> {
+        let deflate = agreed.map(|(agreed, ratio)| Deflate {
+            agreed,
+            ratio,
+            compressor: None,
+            decompressor: None,
+            received: vec![],
+            readable: Cursor::new(vec![]),
+            receiving: Receiving::Idle,
+            collected: vec![],
+            written: vec![],
+            writable: Cursor::new(vec![]),
+            sending_fragments: false,
+            closing: false,
+        });
+        Deflating { io, deflate }
+    }
+}
+
+struct Deflate {
+    agreed: Agreed,
+    ratio: Arc<Ratio>,
+    /// Created for the first message, and for every message without context takeover
+    compressor: Option<Compress>,
+    decompressor: Option<Decompress>,
+    /// Read from the connection, but not a whole frame yet
+    received: Vec<u8>,
+    /// Inflated frames for the websocket to read
+    readable: Cursor<Vec<u8>>,
+    receiving: Receiving,
+    /// The frames of the compressed message received so far
+    collected: Vec<u8>,
+    /// Written by the websocket, but not a whole frame yet
+    written: Vec<u8>,
+    /// Compressed frames for the connection to take
+    writable: Cursor<Vec<u8>>,
+    /// Fragmented messages are sent as they are
+    sending_fragments: bool,
+    /// A message was too large, nothing but the close frame in `writable` goes out anymore
+    closing: bool,
+}
+
+/// Which kind of message the frames received belong to.
+enum Receiving {
+    Idle,
+    /// Its first frame had RSV1 set, the frames are collected until the last one
+    Compressed(OpCode),
+    Plain,
+}
+
+impl Deflate {
+    /// Turns the whole frames received into frames for the websocket to read.
+    fn inflate_received(&mut self) -> io::Result<()> {
+        let mut done = 0;
+        while let Some((header, range)) = next_frame(&self.received[done..], self.agreed.max_message_size)? {
+            let (start, end) = (done + range.0, done + range.1);
+            let frame = done..end;
+            done = end;
+            let mut payload = self.received[start..end].to_vec();
+            if let Some(mask) = header.mask {
+                payload.iter_mut().enumerate().for_each(|(i, byte)| *byte ^= mask[i % 4]);
+            }
+            let opcode = match (header.opcode, &mut self.receiving) {
+                (OpCode::Data(Data::Text | Data::Binary), Receiving::Idle) if header.rsv1 => header.opcode,
+                (OpCode::Data(Data::Continue), Receiving::Compressed(opcode)) if !header.rsv1 => *opcode,
+                (OpCode::Data(Data::Text | Data::Binary), Receiving::Idle) if !header.is_final => {
+                    self.receiving = Receiving::Plain;
+                    self.readable.get_mut().extend_from_slice(&self.received[frame]);
+                    continue;
+                }
+                (OpCode::Data(Data::Continue), Receiving::Plain) if header.is_final => {
+                    self.receiving = Receiving::Idle;
+                    self.readable.get_mut().extend_from_slice(&self.received[frame]);
+                    continue;
+                }
+                _ => {
+                    // The websocket tells what is wrong with frames out of order
+                    self.readable.get_mut().extend_from_slice(&self.received[frame]);
+                    continue;
+                }
+            };
+            if self.collected.len() + payload.len() > self.agreed.max_message_size {
+                return Err(too_large());
+            }
+            self.collected.append(&mut payload);
+            if !header.is_final {
+                self.receiving = Receiving::Compressed(opcode);
+                continue;
+            }
+            self.receiving = Receiving::Idle;
+            let compressed = std::mem::take(&mut self.collected);
+            let message = self.inflate(compressed)?;
+            let header = FrameHeader { is_final: true, rsv1: false, rsv2: false, rsv3: false, opcode, mask: Some([0; 4]) };
+            Frame::from_payload(header, message).format(self.readable.get_mut())
+                .map_err(io::Error::other)?;
+        }
+        self.received.drain(..done);
+        Ok(())
+    }
+
+    /// Inflates no more than one byte past `max_message_size`, and fails when there is more.
+    fn inflate(&mut self, mut compressed: Vec<u8>) -> io::Result<Vec<u8>> {
+        let window_bits = self.agreed.client_window_bits.unwrap_or(15);
+        let decompressor = self.decompressor.get_or_insert_with(|| Decompress::new_with_window_bits(false, window_bits));
+        compressed.extend_from_slice(&TAIL);
+        let limit = self.agreed.max_message_size + 1;
+        let mut message = Vec::with_capacity((compressed.len() * 4).min(limit));
+        let start = decompressor.total_in();
+        loop {
+            let (before, inflated) = (decompressor.total_in(), message.len());
+            let consumed = (before - start) as usize;
+            decompressor.decompress_vec(&compressed[consumed..], &mut message, FlushDecompress::Sync)
+                .map_err(|e| invalid(&format!("could not inflate: {}", e)))?;
+            if message.len() >= limit {
+                return Err(too_large());
+            }
+            let consumed = (decompressor.total_in() - start) as usize;
+            if consumed == compressed.len() && message.len() < message.capacity() {
+                break;
+            }
+            if decompressor.total_in() == before && message.len() == inflated {
+                // Ended before the end of the message
+                return Err(invalid("could not inflate: trailing data"));
+            }
+            message.reserve(message.capacity().max(1024).min(limit - message.len()));
+        }
+        self.ratio.received(message.len(), compressed.len() - TAIL.len());
+        if !self.agreed.client_context_takeover {
+            self.decompressor = None;
+        }
+        Ok(message)
+    }
+
+    /// Turns the whole frames written into frames for the connection to take.
+    fn deflate_written(&mut self) -> io::Result<()> {
+        let mut done = 0;
+        while let Some((mut header, range)) = next_frame(&self.written[done..], self.agreed.max_message_size)? {
+            let (start, end) = (done + range.0, done + range.1);
+            let frame = done..end;
+            done = end;
+            match header.opcode {
+                OpCode::Data(Data::Text | Data::Binary) if header.is_final && !self.sending_fragments => {
+                    let message = self.written[start..end].to_vec();
+                    let compressed = self.deflate(&message)?;
+                    header.rsv1 = true;
+                    Frame::from_payload(header, compressed).format(self.writable.get_mut())
+                        .map_err(io::Error::other)?;
+                }
+                OpCode::Data(_) => {
+                    self.sending_fragments = !header.is_final;
+                    self.writable.get_mut().extend_from_slice(&self.written[frame]);
+                }
+                OpCode::Control(_) => self.writable.get_mut().extend_from_slice(&self.written[frame]),
+            }
+        }
+        self.written.drain(..done);
+        Ok(())
+    }
+
+    fn deflate(&mut self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let (level, window_bits) = (Compression::new(self.agreed.level), self.agreed.server_window_bits);
+        let compressor = self.compressor
+            .get_or_insert_with(|| Compress::new_with_window_bits(level, false, window_bits));
+        let mut compressed = Vec::with_capacity(message.len() / 2 + 64);
+        let start = compressor.total_in();
+        loop {
+            let consumed = (compressor.total_in() - start) as usize;
+            compressor.compress_vec(&message[consumed..], &mut compressed, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            let consumed = (compressor.total_in() - start) as usize;
+            if consumed == message.len() && compressed.len() < compressed.capacity() {
+                break;
+            }
+            compressed.reserve(compressed.capacity().max(1024));
+        }
+        if compressed.ends_with(&TAIL) {
+            compressed.truncate(compressed.len() - TAIL.len());
+        }
+        self.ratio.sent(message.len(), compressed.len());
+        if !self.agreed.server_context_takeover {
+            self.compressor = None;
+        }
+        Ok(compressed)
+    }
+}
+
+/// The header of the first frame in `bytes` and where its payload is, once the frame is whole.
+/// Fails for frames larger than `max_size`.
+fn next_frame(bytes: &[u8], max_size: usize) -> io::Result<Option<(FrameHeader, (usize, usize))>> {
+    let mut cursor = Cursor::new(bytes);
+    let (header, len) = match FrameHeader::parse(&mut cursor).map_err(|e| invalid(&e.to_string()))? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    if len > max_size as u64 {
+        return Err(too_large());
+    }
+    let start = cursor.position() as usize;
+    let end = start + len as usize;
+    Ok((end <= bytes.len()).then_some((header, (start, end))))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+/// A message larger than `max_message_size`, which the connection is closed with 1009 for.
+#[derive(Debug)]
+struct TooLarge;
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("message too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, TooLarge)
+}
+
+fn is_too_large(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<TooLarge>())
+}
+
+impl Deflate {
+    /// Leaves nothing but a close frame with 1009 Message Too Big for the connection to take.
+    fn close_too_large(&mut self) {
+        self.closing = true;
+        self.written.clear();
+        let close = CloseFrame { code: CloseCode::Size, reason: "message too large".into() };
+        Frame::close(Some(close)).format(self.writable.get_mut())
+            .unwrap(); // Writes into a Vec
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Deflating<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.deflate.is_none() {
+            return Pin::new(&mut self.io).poll_read(cx, buf);
+        }
+        loop {
+            if self.deflate.as_ref().unwrap().closing { // Checked above
+                futures_util::ready!(self.poll_writable(cx))?;
+                return Poll::Ready(Err(too_large()));
+            }
+            let Deflating { io, deflate } = &mut *self;
+            let deflate = deflate.as_mut().unwrap(); // Checked above
+            let readable = deflate.readable.get_ref().len() - deflate.readable.position() as usize;
+            if readable > 0 {
+                let start = deflate.readable.position() as usize;
+                let len = readable.min(buf.remaining());
+                buf.put_slice(&deflate.readable.get_ref()[start..start + len]);
+                deflate.readable.set_position((start + len) as u64);
+                if deflate.readable.position() as usize == deflate.readable.get_ref().len() {
+                    deflate.readable = Cursor::new(vec![]);
+                }
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            futures_util::ready!(Pin::new(&mut *io).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // Closed, the websocket tells whether in the middle of a frame
+                return Poll::Ready(Ok(()));
+            }
+            deflate.received.extend_from_slice(chunk.filled());
+            match deflate.inflate_received() {
+                Err(e) if is_too_large(&e) => deflate.close_too_large(),
+                result => result?,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Deflating<S> {
+    /// Hands the compressed frames to the connection.
+    fn poll_writable(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Deflating { io, deflate } = self;
+        let writable = match deflate {
+            Some(deflate) => &mut deflate.writable,
+            None => return Poll::Ready(Ok(())),
+        };
+        while (writable.position() as usize) < writable.get_ref().len() {
+            let start = writable.position() as usize;
+            let written = futures_util::ready!(Pin::new(&mut *io).poll_write(cx, &writable.get_ref()[start..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            writable.set_position((start + written) as u64);
+        }
+        *writable = Cursor::new(vec![]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflating<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.deflate.is_none() {
+            return Pin::new(&mut self.io).poll_write(cx, buf);
+        }
+        if self.deflate.as_ref().unwrap().closing { // Checked above
+            return Poll::Ready(Err(too_large()));
+        }
+        if self.poll_writable(cx)?.is_pending() {
+            let deflate = self.deflate.as_ref().unwrap(); // Checked above
+            if deflate.writable.get_ref().len() > deflate.agreed.max_pending {
+                return Poll::Pending;
+            }
+        }
+        let deflate = self.deflate.as_mut().unwrap(); // Checked above
+        deflate.written.extend_from_slice(buf);
+        deflate.deflate_written()?;
+        // Whatever the connection does not take now waits for the next write or flush
+        let _ = self.poll_writable(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures_util::ready!(self.poll_writable(cx))?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures_util::ready!(self.poll_writable(cx))?;
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Poll;
+
+    use flate2::{Compress, Compression, FlushCompress};
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Control, Data, OpCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+
+    use super::*;
+
+    fn deflating(io: DuplexStream, config: DeflateConfig) -> Deflating<DuplexStream> {
+        let agreed = config.negotiate("permessage-deflate").unwrap();
+        Deflating::new(io, Some((agreed, Arc::new(Ratio::new(Arc::new(Metrics::new()))))))
+    }
+
+    /// A frame as a client sends it, masked.
+    fn frame(opcode: OpCode, rsv1: bool, is_final: bool, payload: &[u8]) -> Vec<u8> {
+        let header = FrameHeader { is_final, rsv1, rsv2: false, rsv3: false, opcode, mask: Some([1, 2, 3, 4]) };
+        let mut bytes = vec![];
+        Frame::from_payload(header, payload.to_vec()).format(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// A message compressed the way clients do, without the tail.
+    fn compress(message: &[u8]) -> Vec<u8> {
+        let mut compressor = Compress::new(Compression::default(), false);
+        let mut compressed = Vec::with_capacity(message.len() + 64);
+        compressor.compress_vec(message, &mut compressed, FlushCompress::Sync).unwrap();
+        assert!(compressed.ends_with(&TAIL));
+        compressed.truncate(compressed.len() - TAIL.len());
+        compressed
+    }
+
+    /// The header and the unmasked payload of the first frame in `bytes`.
+    fn parse(bytes: &[u8]) -> (FrameHeader, Vec<u8>) {
+        let (header, (start, end)) = next_frame(bytes, usize::MAX).unwrap().unwrap();
+        let mut payload = bytes[start..end].to_vec();
+        if let Some(mask) = header.mask {
+            payload.iter_mut().enumerate().for_each(|(i, byte)| *byte ^= mask[i % 4]);
+        }
+        (header, payload)
+    }
+
+    async fn read_some(deflating: &mut Deflating<DuplexStream>) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; 1 << 16];
+        let read = deflating.read(&mut buf).await?;
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    #[test]
+    fn frames_are_parsed_once_they_are_whole() {
+        let bytes = frame(OpCode::Data(Data::Text), true, true, b"hello");
+        assert!(next_frame(&bytes[..1], 100).unwrap().is_none());
+        assert!(next_frame(&bytes[..bytes.len() - 1], 100).unwrap().is_none());
+        let (header, (start, end)) = next_frame(&bytes, 100).unwrap().unwrap();
+        assert!(header.rsv1 && header.is_final);
+        assert_eq!(header.opcode, OpCode::Data(Data::Text));
+        assert_eq!((start, end), (bytes.len() - 5, bytes.len()));
+        assert!(is_too_large(&next_frame(&bytes, 4).unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn compressed_messages_are_inflated() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut deflating = deflating(server, DeflateConfig::default());
+        let mut client = client;
+        client.write_all(&frame(OpCode::Data(Data::Text), true, true, &compress(b"hello hello hello"))).await.unwrap();
+        let (header, payload) = parse(&read_some(&mut deflating).await.unwrap());
+        assert!(!header.rsv1 && header.is_final);
+        assert_eq!(payload, b"hello hello hello");
+    }
+
+    #[tokio::test]
+    async fn fragmented_messages_are_inflated_once_whole() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let mut deflating = deflating(server, DeflateConfig::default());
+        let compressed = compress(b"fragmented and compressed");
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        client.write_all(&frame(OpCode::Data(Data::Text), true, false, first)).await.unwrap();
+        client.write_all(&frame(OpCode::Data(Data::Continue), false, true, second)).await.unwrap();
+        let (header, payload) = parse(&read_some(&mut deflating).await.unwrap());
+        assert_eq!(header.opcode, OpCode::Data(Data::Text));
+        assert!(!header.rsv1 && header.is_final);
+        assert_eq!(payload, b"fragmented and compressed");
+    }
+
+    #[tokio::test]
+    async fn plain_fragments_pass_through() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let mut deflating = deflating(server, DeflateConfig::default());
+        let first = frame(OpCode::Data(Data::Text), false, false, b"not ");
+        let second = frame(OpCode::Data(Data::Continue), false, true, b"compressed");
+        client.write_all(&[first.clone(), second.clone()].concat()).await.unwrap();
+        let mut read = vec![];
+        while read.len() < first.len() + second.len() {
+            read.extend(read_some(&mut deflating).await.unwrap());
+        }
+        assert_eq!(read, [first, second].concat());
+    }
+
+    #[tokio::test]
+    async fn control_frames_pass_through_in_between_fragments() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let mut deflating = deflating(server, DeflateConfig::default());
+        let compressed = compress(b"interrupted by a ping");
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let ping = frame(OpCode::Control(Control::Ping), false, true, b"ping");
+        client.write_all(&frame(OpCode::Data(Data::Text), true, false, first)).await.unwrap();
+        client.write_all(&ping).await.unwrap();
+        client.write_all(&frame(OpCode::Data(Data::Continue), false, true, second)).await.unwrap();
+        let mut read = vec![];
+        while read.len() <= ping.len() {
+            read.extend(read_some(&mut deflating).await.unwrap());
+        }
+        assert_eq!(&read[..ping.len()], &ping[..]);
+        assert_eq!(parse(&read[ping.len()..]).1, b"interrupted by a ping");
+
+        let mut pong = vec![];
+        Frame::pong(b"pong".to_vec()).format(&mut pong).unwrap();
+        deflating.write_all(&pong).await.unwrap();
+        deflating.flush().await.unwrap();
+        let mut sent = vec![0; pong.len()];
+        client.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, pong);
+    }
+
+    #[tokio::test]
+    async fn malformed_compressed_messages_end_the_connection() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let mut deflating = deflating(server, DeflateConfig::default());
+        client.write_all(&frame(OpCode::Data(Data::Binary), true, true, &[0xff; 16])).await.unwrap();
+        let e = read_some(&mut deflating).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(!is_too_large(&e));
+    }
+
+    #[tokio::test]
+    async fn messages_inflating_past_the_limit_are_closed_with_1009() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let mut deflating = deflating(server, DeflateConfig::default().with_max_message_size(4096));
+        let bomb = compress(&vec![0; 1 << 20]);
+        assert!(bomb.len() < 4096);
+        client.write_all(&frame(OpCode::Data(Data::Binary), true, true, &bomb)).await.unwrap();
+        assert!(is_too_large(&read_some(&mut deflating).await.unwrap_err()));
+
+        let mut close = vec![0; 64];
+        let read = client.read(&mut close).await.unwrap();
+        let (header, payload) = parse(&close[..read]);
+        assert_eq!(header.opcode, OpCode::Control(Control::Close));
+        assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), 1009);
+        assert!(deflating.write_all(b"anything").await.is_err());
+    }
+
+    #[test]
+    fn inflating_stops_at_the_limit() {
+        let (_, server) = tokio::io::duplex(1);
+        let mut deflating = deflating(server, DeflateConfig::default().with_max_message_size(1000));
+        let deflate = deflating.deflate.as_mut().unwrap();
+        let bomb = compress(&vec![0; 10 << 20]);
+        assert!(is_too_large(&deflate.inflate(bomb.clone()).unwrap_err()));
+        let decompressor = deflate.decompressor.as_ref().unwrap();
+        assert!(decompressor.total_out() <= 1001);
+        assert!((decompressor.total_in() as usize) < bomb.len() / 2);
+    }
+
+    #[test]
+    fn compressed_messages_larger_than_the_limit_are_refused() {
+        let (_, server) = tokio::io::duplex(1);
+        let mut deflating = deflating(server, DeflateConfig::default().with_max_message_size(100));
+        let deflate = deflating.deflate.as_mut().unwrap();
+        deflate.received = frame(OpCode::Data(Data::Text), true, false, &[0; 60]);
+        deflate.received.extend(frame(OpCode::Data(Data::Continue), false, true, &[0; 60]));
+        assert!(is_too_large(&deflate.inflate_received().unwrap_err()));
+    }
+
+    #[test]
+    fn writes_wait_once_max_pending_is_held_back() {
+        let (_client, server) = tokio::io::duplex(64);
+        let mut deflating = deflating(server, DeflateConfig::default().with_level(0).with_max_pending(1024));
+        let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+        let mut message = vec![];
+        Frame::message(vec![7; 300], OpCode::Data(Data::Binary), true).format(&mut message).unwrap();
+        let mut accepted = 0;
+        while let Poll::Ready(written) = Pin::new(&mut deflating).poll_write(&mut cx, &message) {
+            assert_eq!(written.unwrap(), message.len());
+            accepted += 1;
+            assert!(accepted < 100, "writes never wait");
+        }
+        let held = deflating.deflate.as_ref().unwrap().writable.get_ref().len();
+        assert!(held > 1024 && held <= 1024 + message.len() + 64, "{} bytes held back", held);
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use protocol::{ClientParams, Frame, Polled, ServerEvent, Session};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tracing::{info, Instrument, Span};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::sse::Event;
+use warp::{Filter, Rejection, Reply};
+
+use crate::admin::{self, Bans};
+use crate::auth::{self, Identity, Oidc};
+use crate::chat::{Joined, Outbox, Outgoing};
+use crate::files::FileStore;
+use crate::metrics::Metrics;
+use crate::{Chat, ConnectionIds};
+
+/// How long a poll waits for events, proxies tend to give up on requests after 30 seconds.
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// A long polling connection which has not polled for this long is lost.
+const POLL_IDLE: Duration = Duration::from_secs(30);
+/// The largest event a client may send, messages are much shorter anyway.
+const MAX_EVENT_BYTES: u64 = 64 * 1024;
+
+/// Connections for clients which cannot use websockets, sharing the chat with `/chat`.
+/// They get events from a Server-Sent Events stream or by long polling, and send theirs
+/// with POST requests naming the session, a secret given out with the connection.
+struct Fallback {
+    chat: Chat,
+    metrics: Arc<Metrics>,
+    files: Option<Arc<FileStore>>,
+    /// Key is the session
+    sessions: RwLock<HashMap<String, Connection>>,
+    connection_ids: Arc<ConnectionIds>,
+}
+
+#[derive(Clone)]
+struct Connection {
+    uid: usize,
+    connection: usize,
+    span: Span,
+    /// Long polling only, the events wait here for the next poll
+    poll: Option<Arc<Poll>>,
+}
+
+struct Poll {
+    /// Locked while a poll is waiting
+    rx: Mutex<mpsc::UnboundedReceiver<Outgoing>>,
+    last_poll: std::sync::Mutex<Instant>,
+}
+
+impl Fallback {
+    /// Joins the chat like a websocket would, returns the session of the new connection.
+    async fn open(
+        &self,
+        tx: mpsc::UnboundedSender<Outgoing>,
+        identity: Option<Identity>,
+        remote: Option<SocketAddr>,
+        params: ClientParams,
+        transport: &'static str,
+        poll: Option<Arc<Poll>>,
+    ) -> (String, Joined) {
+        let connection = self.connection_ids.next();
+        let span = crate::connection_span(connection, remote, transport);
+        self.metrics.connections.inc();
+        span.in_scope(|| info!("connection opened"));
+
+        let tx = Outbox::new(tx, self.metrics.clone());
+        let joined = crate::join(&self.chat, connection, remote, tx, identity, params)
+            .instrument(span.clone())
+            .await;
+        let session = auth::random_token();
+        let connection = Connection { uid: joined.uid, connection, span, poll };
+        self.sessions.write().await.insert(session.clone(), connection);
+        (session, joined)
+    }
+
+    async fn get(&self, session: &str) -> Option<Connection> {
+        self.sessions.read().await.get(session).cloned()
+    }
+
+    /// The connection is lost, its user may still resume.
+    async fn close(&self, session: &str) {
+        let connection = match self.sessions.write().await.remove(session) {
+            Some(connection) => connection,
+            None => return,
+        };
+        self.metrics.connections.dec();
+        crate::user_disconnected(connection.uid, connection.connection, &self.chat)
+            .instrument(connection.span)
+            .await;
+    }
+}
+
+/// Closes the connection of an event stream once it is dropped, i.e. the client went away.
+struct CloseOnDrop {
+    fallback: Arc<Fallback>,
+    session: String,
+}
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        let fallback = self.fallback.clone();
+        let session = std::mem::take(&mut self.session);
+        tokio::task::spawn(async move { fallback.close(&session).await });
+    }
+}
+
+/// GET /sse, POST /poll, GET /poll/:session and POST /send/:session. The query of
+/// GET /sse and POST /poll is the same as on `/chat`.
+pub fn routes(
+    chat: Chat,
+    metrics: Arc<Metrics>,
+    files: Option<Arc<FileStore>>,
+    oidc: Option<Arc<Oidc>>,
+    bans: Arc<Bans>,
+    connection_ids: Arc<ConnectionIds>,
+) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    let fallback = Arc::new(Fallback { chat, metrics, files, sessions: RwLock::default(), connection_ids });
+    let fallback = warp::any().map(move || fallback.clone());
+    let admitted = admin::admitted(auth::identity(oidc), bans);
+
+    let sse = warp::path!("sse")
+        .and(warp::get())
+        .and(fallback.clone())
+        .and(admitted.clone())
+        .and(warp::query::<ClientParams>())
+        .and(warp::header::optional::<String>("last-event-id"))
+        .then(sse);
+
+    let open_poll = warp::path!("poll")
+        .and(warp::post())
+        .and(fallback.clone())
+        .and(admitted)
+        .and(warp::query::<ClientParams>())
+        .then(open_poll);
+
+    let poll = warp::path!("poll" / String)
+        .and(warp::get())
+        .and(fallback.clone())
+        .then(poll);
+
+    let send = warp::path!("send" / String)
+        .and(warp::post())
+        .and(fallback)
+        .and(warp::body::content_length_limit(MAX_EVENT_BYTES))
+        .and(warp::body::bytes())
+        .then(send);
+
+    sse
+        .or(open_poll).unify()
+        .or(poll).unify()
+        .or(send).unify()
+}
+
+/// The first event is `session`, then come the chat events as plain messages, and a
+/// `close` event with the reason when the backend closes the connection.
+///
+/// Every chat event has the resume token and the id of the last message seen as id,
+/// so that an EventSource which reconnects on its own resumes its user.
+async fn sse(
+    fallback: Arc<Fallback>,
+    identity: Option<Identity>,
+    remote: Option<SocketAddr>,
+    mut params: ClientParams,
+    last_event_id: Option<String>,
+) -> Response {
+    // An EventSource reconnects to the same url, the resume token there was used up already
+    if let Some((token, last_seen)) = last_event_id.as_deref().and_then(|id| id.rsplit_once('.')) {
+        params.resume = Some(token.to_owned());
+        params.last_seen = last_seen.parse().ok();
+    }
+    let resumed_from = params.last_seen.unwrap_or(0);
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (session, joined) = fallback.open(tx, identity, remote, params, "sse", None).await;
+    let metrics = fallback.metrics.clone();
+    let close = CloseOnDrop { fallback, session: session.clone() };
+
+    // A new user has seen nothing before its welcome
+    let last_seen = joined.resumed.then_some(resumed_from);
+    let events = stream::unfold(Some((rx, String::new(), last_seen, close)), move |state| {
+        let metrics = metrics.clone();
+        async move {
+            let (mut rx, mut token, mut last_seen, close) = state?;
+            let outgoing = rx.recv().await?;
+            metrics.outbound_queue_depth.dec();
+            let event = match outgoing {
+                Outgoing::Event(event) => event,
+                Outgoing::Close(reason) => return Some((Event::default().event("close").data(reason), None)),
+            };
+            let last_seen = match &event {
+                ServerEvent::Welcome { resume_token, last_id, .. } => {
+                    token = resume_token.clone();
+                    *last_seen.get_or_insert(*last_id)
+                }
+                ServerEvent::Message { id, .. } => *last_seen.insert(last_seen.unwrap_or(0).max(*id)),
+                _ => last_seen.unwrap_or(0),
+            };
+            let sse_event = Event::default()
+                .id(format!("{}.{}", token, last_seen))
+                .json_data(&event)
+                .unwrap(); // Plain structs always serialize
+            Some((sse_event, Some((rx, token, Some(last_seen), close))))
+        }
+    });
+
+    let stream = stream::once(async move { Event::default().event("session").data(session) })
+        .chain(events)
+        .map(Ok::<_, Infallible>);
+    warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response()
+}
+
+async fn open_poll(
+    fallback: Arc<Fallback>,
+    identity: Option<Identity>,
+    remote: Option<SocketAddr>,
+    params: ClientParams,
+) -> Response {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let poll = Arc::new(Poll { rx: Mutex::new(rx), last_poll: std::sync::Mutex::new(Instant::now()) });
+    let (session, _) = fallback.open(tx, identity, remote, params, "long-poll", Some(poll.clone())).await;
+
+    // Nobody tells us when a client stops polling
+    let idle = session.clone();
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(POLL_IDLE).await;
+            if fallback.get(&idle).await.is_none() {
+                return;
+            }
+            let waiting = poll.rx.try_lock().is_err();
+            let last_poll = *poll.last_poll.lock().unwrap(); // Never held across a panic
+            if !waiting && last_poll.elapsed() >= POLL_IDLE {
+                fallback.close(&idle).await;
+                return;
+            }
+        }
+    });
+
+    warp::reply::with_status(warp::reply::json(&Session { session }), StatusCode::CREATED).into_response()
+}
+
+/// Answers as soon as there are events, or after `POLL_TIMEOUT` without any.
+async fn poll(session: String, fallback: Arc<Fallback>) -> Response {
+    let poll = match fallback.get(&session).await.and_then(|connection| connection.poll) {
+        Some(poll) => poll,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let mut outgoing = vec![];
+    {
+        let mut rx = poll.rx.lock().await;
+        *poll.last_poll.lock().unwrap() = Instant::now(); // Never held across a panic
+        if let Ok(Some(first)) = tokio::time::timeout(POLL_TIMEOUT, rx.recv()).await {
+            outgoing.push(first);
+            while let Ok(next) = rx.try_recv() {
+                outgoing.push(next);
+            }
+        }
+        *poll.last_poll.lock().unwrap() = Instant::now(); // Never held across a panic
+    }
+
+    let mut polled = Polled { events: vec![], closed: None };
+    for outgoing in outgoing {
+        fallback.metrics.outbound_queue_depth.dec();
+        match outgoing {
+            Outgoing::Event(event) if polled.closed.is_none() => polled.events.push(event),
+            Outgoing::Event(_) => {}
+            Outgoing::Close(reason) => polled.closed = Some(reason),
+        }
+    }
+    if polled.closed.is_some() {
+        fallback.close(&session).await;
+    }
+    warp::reply::json(&polled).into_response()
+}
+
+/// Takes a `ClientEvent` as JSON, like a text frame on `/chat`.
+async fn send(session: String, fallback: Arc<Fallback>, body: Bytes) -> Response {
+    let connection = match fallback.get(&session).await {
+        Some(connection) => connection,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => return warp::reply::with_status("Events are JSON", StatusCode::BAD_REQUEST).into_response(),
+    };
+    crate::user_message(connection.uid, Frame::Text(text), &fallback.chat, fallback.files.as_deref())
+        .instrument(connection.span)
+        .await;
+    StatusCode::ACCEPTED.into_response()
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use image::io::{Limits, Reader};
+use image::{ImageFormat, ImageOutputFormat};
+use protocol::Attachment;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::admin::{self, Bans};
+use crate::auth::{self, Identity, Oidc};
+
+/// Thumbnails of images fit into this many pixels.
+const THUMBNAIL_SIZE: (u32, u32) = (160, 120);
+/// Images which take more memory than this to decode are rejected.
+const MAX_DECODED_BYTES: u64 = 256 << 20;
+
+/// Where uploads are kept and what they may be.
+pub struct FilesConfig {
+    pub dir: PathBuf,
+    /// Larger uploads are rejected
+    pub max_bytes: u64,
+    /// The content types which may be uploaded
+    pub types: Vec<String>,
+}
+
+impl FilesConfig {
+    /// Reads the upload settings, `None` means uploads are disabled.
+    pub fn from_env() -> Option<FilesConfig> {
+        let dir = PathBuf::from(std::env::var("UPLOADS_DIR").ok()?);
+        let max_bytes = std::env::var("UPLOAD_MAX_BYTES").unwrap_or_else(|_| (10 << 20).to_string());
+        let max_bytes = max_bytes.parse()
+            .unwrap_or_else(|_| panic!("Env variable UPLOAD_MAX_BYTES contains non numeric value: {}", max_bytes));
+        let types = std::env::var("UPLOAD_TYPES")
+            .unwrap_or_else(|_| "image/png,image/jpeg,image/gif,image/webp,text/plain,application/pdf".to_owned())
+            .split(',')
+            .map(|content_type| content_type.trim().to_owned())
+            .filter(|content_type| !content_type.is_empty())
+            .collect();
+        Some(FilesConfig { dir, max_bytes, types })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    name: String,
+}
+
+/// Uploaded files on local disk, every file `<id>` comes with `<id>.json` describing it,
+/// and images with a PNG thumbnail in `<id>.thumbnail`.
+pub struct FileStore {
+    config: FilesConfig,
+    /// Key is the id
+    files: RwLock<HashMap<String, Attachment>>,
+}
+
+impl FileStore {
+    /// Creates the directory, or picks up the files uploaded before.
+    pub fn open(config: FilesConfig) -> Result<FileStore, String> {
+        fs::create_dir_all(&config.dir)
+            .map_err(|e| format!("Could not create {:?}: {}", config.dir, e))?;
+        let mut files = HashMap::new();
+        let entries = fs::read_dir(&config.dir)
+            .map_err(|e| format!("Could not read {:?}: {}", config.dir, e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| format!("Could not read {:?}: {}", config.dir, e))?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let attachment = fs::read(&path).ok()
+                .and_then(|json| serde_json::from_slice::<Attachment>(&json).ok());
+            match attachment {
+                Some(attachment) => {
+                    files.insert(attachment.id.clone(), attachment);
+                }
+                None => warn!(?path, "skipping unreadable upload"),
+            }
+        }
+        info!(dir = ?config.dir, files = files.len(), "uploads opened");
+        Ok(FileStore { config, files: RwLock::new(files) })
+    }
+
+    /// Whether uploads can be stored, by writing a file and removing it again.
+    pub fn check_writable(&self) -> Result<(), String> {
+        let probe = self.config.dir.join(format!(".writable-{}", auth::random_token()));
+        fs::write(&probe, b"").and_then(|()| fs::remove_file(&probe))
+            .map_err(|e| format!("could not write to {:?}: {}", self.config.dir, e))
+    }
+
+    /// The attachments with these ids, or the first id which is unknown.
+    pub async fn resolve(&self, ids: &[String]) -> Result<Vec<Attachment>, String> {
+        let files = self.files.read().await;
+        ids.iter()
+            .map(|id| files.get(id).cloned().ok_or_else(|| id.clone()))
+            .collect()
+    }
+
+    async fn store(&self, name: String, content_type: String, data: Bytes) -> Result<Attachment, UploadError> {
+        if !self.config.types.contains(&content_type) {
+            return Err(UploadError::Type(content_type));
+        }
+        if !looks_like(&content_type, &data) {
+            return Err(UploadError::Content(content_type));
+        }
+        let thumbnail = match ImageFormat::from_mime_type(&content_type) {
+            Some(format) => {
+                let data = data.clone();
+                let thumbnail = tokio::task::spawn_blocking(move || thumbnail(&data, format)).await
+                    .map_err(|e| UploadError::Io(e.to_string()))?;
+                match thumbnail {
+                    Ok(thumbnail) => Some(thumbnail),
+                    Err(e) => {
+                        info!("rejecting undecodable image: {}", e);
+                        return Err(UploadError::Content(content_type));
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let attachment = Attachment {
+            id: auth::random_token(),
+            name,
+            content_type,
+            size: data.len() as u64,
+        };
+        let path = self.config.dir.join(&attachment.id);
+        let meta = serde_json::to_vec(&attachment)
+            .unwrap(); // Plain structs always serialize
+        tokio::task::spawn_blocking(move || {
+            fs::write(&path, &data)?;
+            if let Some(thumbnail) = thumbnail {
+                fs::write(path.with_extension("thumbnail"), thumbnail)?;
+            }
+            fs::write(path.with_extension("json"), meta)
+        }).await
+            .map_err(|e| UploadError::Io(e.to_string()))?
+            .map_err(|e| UploadError::Io(e.to_string()))?;
+
+        self.files.write().await.insert(attachment.id.clone(), attachment.clone());
+        info!(id = %attachment.id, name = %attachment.name, size = attachment.size, "file uploaded");
+        Ok(attachment)
+    }
+
+    async fn load(&self, id: &str) -> Option<(Attachment, Vec<u8>)> {
+        self.read(id, "").await
+    }
+
+    /// Only images have one.
+    async fn load_thumbnail(&self, id: &str) -> Option<Vec<u8>> {
+        if let Some((_, thumbnail)) = self.read(id, "thumbnail").await {
+            return Some(thumbnail);
+        }
+
+        // Uploaded before there were thumbnails
+        let (attachment, data) = self.load(id).await?;
+        let format = ImageFormat::from_mime_type(&attachment.content_type)?;
+        let path = self.config.dir.join(&attachment.id).with_extension("thumbnail");
+        let thumbnail = tokio::task::spawn_blocking(move || {
+            let thumbnail = thumbnail(&data, format)?;
+            if let Err(e) = fs::write(&path, &thumbnail) {
+                warn!(?path, "could not keep thumbnail: {}", e);
+            }
+            Ok::<_, String>(thumbnail)
+        }).await;
+        match thumbnail {
+            Ok(Ok(thumbnail)) => Some(thumbnail),
+            Ok(Err(e)) => {
+                warn!(%id, "could not make thumbnail: {}", e);
+                None
+            }
+            Err(e) => {
+                error!(%id, "could not make thumbnail: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Reads the file `<id>.<extension>`, or `<id>` without one.
+    async fn read(&self, id: &str, extension: &'static str) -> Option<(Attachment, Vec<u8>)> {
+        // Only ids we gave out ever make it into a path
+        let attachment = self.files.read().await.get(id)?.clone();
+        let path = self.config.dir.join(&attachment.id).with_extension(extension);
+        let data = tokio::task::spawn_blocking(move || fs::read(path)).await
+            .map_err(io::Error::other)
+            .and_then(|data| data);
+        match data {
+            Ok(data) => Some((attachment, data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !extension.is_empty() => None,
+            Err(e) => {
+                error!(%id, "could not read upload: {}", e);
+                None
+            }
+        }
+    }
+}
+
+enum UploadError {
+    Type(String),
+    Content(String),
+    Io(String),
+}
+
+/// A PNG of the image scaled down to fit into `THUMBNAIL_SIZE`, the first frame of
+/// animations. Smaller images keep their size.
+fn thumbnail(data: &[u8], format: ImageFormat) -> Result<Vec<u8>, String> {
+    let mut reader = Reader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_DECODED_BYTES);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| e.to_string())?;
+
+    let (width, height) = THUMBNAIL_SIZE;
+    let image = if image.width() > width || image.height() > height {
+        image.thumbnail(width, height)
+    } else {
+        image
+    };
+    let mut png = Cursor::new(vec![]);
+    image.write_to(&mut png, ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(png.into_inner())
+}
+
+/// Images have to start like one, we serve them inline.
+fn looks_like(content_type: &str, data: &[u8]) -> bool {
+    match content_type {
+        "image/png" => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => data.starts_with(b"\xff\xd8\xff"),
+        "image/gif" => data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a"),
+        "image/webp" => data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP",
+        _ => true,
+    }
+}
+
+/// POST /files?name=<file name> with the file as body and its Content-Type,
+/// GET /files/:id and GET /files/:id/thumbnail, all of them are missing when uploads
+/// are disabled. Only users who may chat get to them.
+pub fn routes(
+    files: Option<Arc<FileStore>>,
+    oidc: Option<Arc<Oidc>>,
+    bans: Arc<Bans>,
+) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    let max_bytes = files.as_ref().map_or(0, |files| files.config.max_bytes);
+    let files = warp::any().and_then(move || {
+        let files = files.clone();
+        async move { files.ok_or_else(warp::reject::not_found) }
+    });
+
+    // Uploading is chatting, so the same users may do it
+    let admitted = admin::admitted(auth::identity(oidc), bans);
+
+    let upload = warp::path!("files")
+        .and(warp::post())
+        .and(files.clone())
+        .and(admitted.clone())
+        .and(warp::query::<UploadParams>())
+        .and(warp::header::<String>("content-type"))
+        .and(warp::body::content_length_limit(max_bytes))
+        .and(warp::body::bytes())
+        .then(upload);
+
+    let download = warp::path!("files" / String)
+        .and(warp::get())
+        .and(files.clone())
+        .and(admitted.clone())
+        .then(download);
+
+    let thumbnail = warp::path!("files" / String / "thumbnail")
+        .and(warp::get())
+        .and(files)
+        .and(admitted)
+        .then(download_thumbnail);
+
+    upload.or(download).unify()
+        .or(thumbnail).unify()
+}
+
+async fn upload(
+    files: Arc<FileStore>,
+    _: Option<Identity>,
+    _: Option<SocketAddr>,
+    params: UploadParams,
+    content_type: String,
+    data: Bytes,
+) -> Response {
+    // Parameters like the charset are not part of the type
+    let content_type = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+    match files.store(params.name, content_type, data).await {
+        Ok(attachment) => warp::reply::with_status(warp::reply::json(&attachment), StatusCode::CREATED).into_response(),
+        Err(UploadError::Type(content_type)) => warp::reply::with_status(
+            format!("Files of type {} are not allowed", content_type), StatusCode::UNSUPPORTED_MEDIA_TYPE).into_response(),
+        Err(UploadError::Content(content_type)) => warp::reply::with_status(
+            format!("The file is not {}", content_type), StatusCode::BAD_REQUEST).into_response(),
+        Err(UploadError::Io(e)) => {
+            error!("could not store upload: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn download(id: String, files: Arc<FileStore>, _: Option<Identity>, _: Option<SocketAddr>) -> Response {
+    let (attachment, data) = match files.load(&id).await {
+        Some(file) => file,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    // Only images are shown in the page, anything else is saved
+    let disposition = if attachment.content_type.starts_with("image/") { "inline" } else { "attachment" };
+    // Header values are plain ASCII
+    let file_name = attachment.name.replace(|c: char| !c.is_ascii() || c.is_ascii_control() || c == '"' || c == '\\', "_");
+    let reply = warp::reply::with_header(data, "content-type", attachment.content_type);
+    let reply = warp::reply::with_header(reply, "content-disposition", format!("{}; filename=\"{}\"", disposition, file_name));
+    let reply = warp::reply::with_header(reply, "x-content-type-options", "nosniff");
+    // Files never change, an id is given out once
+    warp::reply::with_header(reply, "cache-control", "private, max-age=31536000, immutable").into_response()
+}
+
+async fn download_thumbnail(id: String, files: Arc<FileStore>, _: Option<Identity>, _: Option<SocketAddr>) -> Response {
+    let thumbnail = match files.load_thumbnail(&id).await {
+        Some(thumbnail) => thumbnail,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let reply = warp::reply::with_header(thumbnail, "content-type", "image/png");
+    let reply = warp::reply::with_header(reply, "x-content-type-options", "nosniff");
+    warp::reply::with_header(reply, "cache-control", "private, max-age=31536000, immutable").into_response()
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+type Check = Box<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// Whether we should get new users: the storage we depend on has to be
+/// available, and we must not be shutting down.
+#[derive(Default)]
+pub struct Readiness {
+    shutting_down: AtomicBool,
+    checks: Vec<(&'static str, Check)>,
+}
+
+impl Readiness {
+    pub fn add_check<F>(&mut self, name: &'static str, check: F)
+        where F: Fn() -> Result<(), String> + Send + Sync + 'static
+    {
+        self.checks.push((name, Box::new(check)));
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.shutting_down.load(Ordering::Relaxed) {
+            problems.push("shutting down".to_owned());
+        }
+        for (name, check) in self.checks.iter() {
+            if let Err(problem) = check() {
+                problems.push(format!("{}: {}", name, problem));
+            }
+        }
+        problems
+    }
+}
+
+/// GET /healthz and GET /readyz
+pub fn routes(readiness: Arc<Readiness>) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    // We are alive as long as we can answer
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| "ok".into_response());
+
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .map(move || {
+            let problems = readiness.problems();
+            if problems.is_empty() {
+                "ready".into_response()
+            } else {
+                warp::reply::with_status(problems.join("\n"), StatusCode::SERVICE_UNAVAILABLE).into_response()
+            }
+        });
+
+    healthz.or(readyz).unify()
+}
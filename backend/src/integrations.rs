@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, info_span, Instrument};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::Chat;
+
+/// The largest request an integration may send, messages are much shorter anyway.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// A service like CI or monitoring, posting into the chat under its own name.
+pub struct Integration {
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+struct PostMessage {
+    text: String,
+}
+
+/// The integrations in INTEGRATIONS, as `name=token` separated by commas.
+pub fn from_env() -> Vec<Integration> {
+    let integrations = std::env::var("INTEGRATIONS").unwrap_or_default();
+    integrations.split(',')
+        .map(str::trim)
+        .filter(|integration| !integration.is_empty())
+        .map(|integration| match integration.split_once('=') {
+            Some((name, token)) if !name.trim().is_empty() && !token.trim().is_empty() =>
+                Integration { name: name.trim().to_owned(), token: token.trim().to_owned() },
+            _ => panic!("Env variable INTEGRATIONS contains an integration without name=token: {}", integration),
+        })
+        .collect()
+}
+
+/// An integration as the chat knows it.
+struct Registered {
+    uid: usize,
+    name: String,
+    /// Comparing digests takes the same time however much of a token is right
+    token_digest: Vec<u8>,
+}
+
+/// POST /api/rooms/:room/messages with `{"text": "..."}`, requires
+/// `Authorization: Bearer <token>` of an integration. Missing without integrations.
+pub async fn routes(chat: Chat, integrations: Vec<Integration>) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    let mut registered = vec![];
+    for Integration { name, token } in integrations {
+        let uid = chat.write().await.add_integration(name.clone());
+        registered.push(Registered { uid, name, token_digest: Sha256::digest(token.as_bytes()).to_vec() });
+    }
+    let chat = warp::any().map(move || chat.clone());
+
+    warp::path!("api" / "rooms" / String / "messages")
+        .and(warp::post())
+        .and(integration(Arc::new(registered)))
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::json())
+        .and(chat)
+        .then(post_message)
+}
+
+/// The uid and name of the integration the bearer token belongs to, if any.
+fn integration(registered: Arc<Vec<Registered>>) -> impl Filter<Extract=(Option<(usize, String)>,), Error=Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let registered = registered.clone();
+            async move {
+                if registered.is_empty() {
+                    return Err(warp::reject::not_found());
+                }
+                let given = header.as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "))
+                    .map(|token| Sha256::digest(token.as_bytes()).to_vec());
+                Ok(registered.iter()
+                    .find(|integration| Some(&integration.token_digest) == given.as_ref())
+                    .map(|integration| (integration.uid, integration.name.clone())))
+            }
+        })
+}
+
+async fn post_message(room: String, integration: Option<(usize, String)>, request: PostMessage, chat: Chat) -> Response {
+    let (uid, name) = match integration {
+        Some(integration) => integration,
+        None => return warp::reply::with_status("Invalid integration token", StatusCode::UNAUTHORIZED).into_response(),
+    };
+    // Rooms like "ops team" arrive percent encoded
+    let room = match percent_decode_str(&room).decode_utf8() {
+        Ok(room) => room.into_owned(),
+        Err(_) => return warp::reply::with_status("Invalid room", StatusCode::BAD_REQUEST).into_response(),
+    };
+
+    let span = info_span!("integration", uid, %name, %room);
+    let result = async {
+        info!(len = request.text.len(), "message posted");
+        chat.read().await.integration_message(uid, room, request.text)
+    }.instrument(span).await;
+    match result {
+        Ok(id) => warp::reply::with_status(warp::reply::json(&serde_json::json!({ "id": id })), StatusCode::CREATED)
+            .into_response(),
+        Err(reason) => warp::reply::with_status(reason, StatusCode::BAD_REQUEST).into_response(),
+    }
+}
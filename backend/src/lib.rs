@@ -0,0 +1,297 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use protocol::{ClientEvent, ClientParams, Encoding, Frame, Subprotocol, CLOSE_UNSUPPORTED_PROTOCOL, DEFAULT_ROOM, PROTOCOL_VERSION};
+use tokio::sync::{mpsc, RwLock};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+
+use auth::Identity;
+use chat::{ChatState, Joined, Outbox, Outgoing, Resume};
+use deflate::Ratio;
+use files::FileStore;
+use metrics::Metrics;
+use websocket::Socket;
+
+mod admin;
+mod auth;
+mod bots;
+mod broker;
+mod chat;
+mod cluster;
+mod commands;
+mod deflate;
+mod fallback;
+mod files;
+mod health;
+mod integrations;
+mod metrics;
+mod origins;
+mod pipeline;
+mod plugins;
+mod redis;
+mod registry;
+mod server;
+mod webhooks;
+mod websocket;
+
+pub use auth::OidcConfig;
+pub use bots::{Bot, EchoBot, Say};
+pub use broker::{BoxFuture, Broker, Envelope, InProcessBroker, Node, Relay, Relays};
+pub use cluster::ClusterConfig;
+pub use deflate::DeflateConfig;
+pub use files::FilesConfig;
+pub use integrations::Integration;
+pub use pipeline::{Audience, Incoming, Middleware, Rejected};
+pub use plugins::Limits as PluginLimits;
+pub use redis::RedisBroker;
+pub use server::{ChatServer, RunningChat, Shutdown};
+pub use webhooks::{Trigger, Webhook, WebhooksConfig};
+
+/// Our state of currently connected users, see `ChatState` for what takes the write lock.
+type Chat = Arc<RwLock<ChatState>>;
+
+/// Tells connections apart, a resumed user gets a new connection.
+#[derive(Default)]
+struct ConnectionIds(AtomicUsize);
+
+impl ConnectionIds {
+    fn next(&self) -> usize {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// What we know about a connection once its handshake succeeded.
+struct Handshake {
+    connection: usize,
+    remote: Option<SocketAddr>,
+    identity: Option<Identity>,
+    params: ClientParams,
+    protocol: Negotiated,
+}
+
+/// The subprotocol picked from those a client offered in `Sec-WebSocket-Protocol`.
+enum Negotiated {
+    /// Clients from before subprotocols offer none, they speak version 1
+    /// and pick their encoding in the query.
+    Legacy,
+    Supported(Subprotocol),
+    /// The handshake selects the first one offered anyway, browsers would fail it
+    /// without telling why otherwise. The connection is closed right after.
+    Unsupported(String),
+}
+
+impl Negotiated {
+    fn new(offered: Option<&str>) -> Negotiated {
+        let mut offered = offered.unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .peekable();
+        let first = match offered.peek() {
+            Some(first) => first.to_string(),
+            None => return Negotiated::Legacy,
+        };
+        offered
+            .filter_map(|name| name.parse::<Subprotocol>().ok())
+            .find(|subprotocol| subprotocol.version == PROTOCOL_VERSION)
+            .map_or(Negotiated::Unsupported(first), Negotiated::Supported)
+    }
+
+    /// What goes into the `Sec-WebSocket-Protocol` of the response.
+    fn selected(&self) -> Option<String> {
+        match self {
+            Negotiated::Legacy => None,
+            Negotiated::Supported(subprotocol) => Some(subprotocol.name()),
+            Negotiated::Unsupported(first) => Some(first.clone()),
+        }
+    }
+}
+
+async fn user_connected(
+    mut ws: Socket,
+    ratio: Option<Arc<Ratio>>,
+    handshake: Handshake,
+    chat: Chat,
+    metrics: Arc<Metrics>,
+    files: Option<Arc<FileStore>>,
+) {
+    let Handshake { connection, remote, identity, params, protocol } = handshake;
+    let encoding = match protocol {
+        Negotiated::Legacy => params.encoding.unwrap_or_default(),
+        Negotiated::Supported(subprotocol) => subprotocol.encoding,
+        Negotiated::Unsupported(offered) => {
+            warn!(%offered, "unsupported protocol");
+            metrics.handshake_failures.inc();
+            let reason = format!("Unsupported protocol, supported are {} and {}",
+                Subprotocol::current(Encoding::Json).name(), Subprotocol::current(Encoding::MessagePack).name());
+            let _ = ws.send(close(CLOSE_UNSUPPORTED_PROTOCOL, reason)).await;
+            return;
+        }
+    };
+    metrics.connections.inc();
+    info!(encoding = encoding.as_str(), "connection opened");
+
+    // Split the socket into a sender and receive of messages.
+    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+
+    // Use an unbounded channel to handle buffering and flushing of messages
+    // to the websocket...
+    let (tx, rx) = mpsc::unbounded_channel();
+    let tx = Outbox::new(tx, metrics.clone());
+    let mut rx = UnboundedReceiverStream::new(rx);
+
+    let writer_metrics = metrics.clone();
+    tokio::task::spawn(async move {
+        let metrics = writer_metrics;
+        while let Some(outgoing) = rx.next().await {
+            metrics.outbound_queue_depth.dec();
+            let event = match outgoing {
+                Outgoing::Event(event) => event,
+                Outgoing::Close(reason) => {
+                    // Close code 4000 is ours to define, it means kicked
+                    let _ = user_ws_tx.send(close(4000, reason)).await;
+                    break;
+                }
+            };
+            let message = match encoding.encode(&event) {
+                Ok(Frame::Text(text)) => Message::Text(text),
+                Ok(Frame::Binary(data)) => Message::Binary(data),
+                Err(e) => {
+                    error!(?event, "could not encode: {}", e);
+                    continue;
+                }
+            };
+            let size = message.len() as u64;
+            user_ws_tx
+                .send(message)
+                .map_ok(|_| metrics.bytes_sent.inc_by(size))
+                .unwrap_or_else(|e| {
+                    metrics.send_errors.inc();
+                    error!("websocket send error: {}", e);
+                })
+                .await;
+        }
+    }.instrument(Span::current()));
+
+    let my_id = join(&chat, connection, remote, tx, identity, params).await.uid;
+
+    // Return a `Future` that is basically a state machine managing
+    // this specific user's connection.
+
+    // Every time the user sends a message, broadcast it to
+    // all other users...
+    while let Some(result) = user_ws_rx.next().await {
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("websocket error: {}", e);
+                break;
+            }
+        };
+        // Text frames carry JSON and binary ones MessagePack, whatever this connection receives
+        let frame = match msg {
+            Message::Text(text) => Frame::Text(text),
+            Message::Binary(data) => Frame::Binary(data),
+            // Pings, pongs and closes are handled by tungstenite
+            _ => continue,
+        };
+        user_message(my_id, frame, &chat, files.as_deref()).await;
+    }
+
+    // user_ws_rx stream will keep processing as long as the user stays
+    // connected. Once they disconnect, then...
+    metrics.connections.dec();
+    if let Some(ratio) = ratio {
+        ratio.log();
+    }
+    user_disconnected(my_id, connection, &chat).await;
+}
+
+fn close(code: u16, reason: String) -> Message {
+    Message::Close(Some(CloseFrame { code: CloseCode::from(code), reason: reason.into() }))
+}
+
+/// Everything logged about a connection carries these fields,
+/// uid and room are known once the user has joined.
+fn connection_span(connection: usize, remote: Option<SocketAddr>, transport: &'static str) -> Span {
+    info_span!("connection",
+        connection,
+        transport,
+        remote = %remote.map_or_else(|| "unknown".to_owned(), |addr| addr.to_string()),
+        uid = tracing::field::Empty,
+        room = tracing::field::Empty)
+}
+
+/// Saves the sender in our list of connected users, or takes over
+/// the user whose connection was lost, whatever the transport.
+async fn join(
+    chat: &Chat,
+    connection: usize,
+    remote: Option<SocketAddr>,
+    tx: Outbox,
+    identity: Option<Identity>,
+    params: ClientParams,
+) -> Joined {
+    let resume = params.resume.map(|token| Resume { token, last_seen: params.last_seen.unwrap_or(0) });
+    let room = params.room
+        .filter(|room| !room.is_empty())
+        .unwrap_or_else(|| DEFAULT_ROOM.to_owned());
+    let joined = chat.read().await.connect(connection, remote, tx, identity, room, resume);
+    Span::current()
+        .record("uid", joined.uid)
+        .record("room", joined.room.as_str());
+    joined
+}
+
+async fn user_message(my_id: usize, frame: Frame, chat: &Chat, files: Option<&FileStore>) {
+    match frame.decode::<ClientEvent>() {
+        Ok(ClientEvent::Message { text, attachments }) if !attachments.is_empty() => {
+            debug!(len = text.len(), attachments = attachments.len(), "message received");
+            let resolved = match files {
+                Some(files) => files.resolve(&attachments).await,
+                None => Err(attachments[0].clone()),
+            };
+            let chat = chat.read().await;
+            match resolved {
+                Ok(attachments) => chat.message(my_id, text, attachments),
+                Err(id) => chat.notice(my_id, format!("Unknown attachment {}", id)),
+            }
+        }
+        Ok(ClientEvent::Message { text, .. }) => {
+            debug!(len = text.len(), "message received");
+            // Plain messages go out side by side, only commands may change the chat
+            match commands::parse(&text) {
+                None => chat.read().await.message(my_id, text, vec![]),
+                Some(Ok(command)) => commands::run(&mut *chat.write().await, my_id, command),
+                Some(Err(error)) => chat.read().await.notice(my_id, error),
+            }
+        }
+        Err(e) => warn!(encoding = frame.encoding().as_str(), "unexpected message: {}", e),
+    }
+}
+
+async fn user_disconnected(my_id: usize, connection: usize, chat: &Chat) {
+    info!("connection lost");
+    let grace = {
+        let chat = chat.read().await;
+        if !chat.detach(my_id, connection) {
+            // Resumed by another connection already
+            return;
+        }
+        chat.resume_grace()
+    };
+
+    // Stream closed up, so remove from the user list,
+    // unless the user comes back in time
+    let chat = chat.clone();
+    tokio::task::spawn(async move {
+        tokio::time::sleep(grace).await;
+        chat.read().await.leave(my_id, connection);
+    }.instrument(Span::current()));
+}
@@ -1,142 +1,47 @@
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-
-use futures_util::{SinkExt, StreamExt, TryFutureExt};
-use log::{error, info};
-use tokio::sync::{mpsc, RwLock};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use warp::Filter;
-use warp::ws::{Message, WebSocket};
-
-/// Our global unique user id counter.
-static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
-
-/// Our state of currently connected users.
-///
-/// - Key is their id
-/// - Value is a sender of `warp::ws::Message`
-type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
+use backend::ChatServer;
+use tracing_subscriber::EnvFilter;
 
+/// The chat configured by env variables, see `ChatServer::from_env`.
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
-
-    // Keep track of all connected users, key is usize, value
-    // is a websocket sender.
-    let users = Users::default();
-    // Turn our "state" into a new Filter...
-    let users = warp::any().map(move || users.clone());
-
-    // GET /chat -> websocket upgrade
-    let chat = warp::path("chat")
-        // The `ws()` filter will prepare Websocket handshake...
-        .and(warp::ws())
-        .and(users)
-        .map(|ws: warp::ws::Ws, users| {
-            // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| user_connected(socket, users))
-        });
-
-    // GET /* -> UI
-    let static_assets = warp::get().and(warp::fs::dir(ui_static_assets()));
-
-    let routes = chat.or(static_assets);
-
-    warp::serve(routes).run(([127, 0, 0, 1], port())).await;
-}
-
-fn port() -> u16 {
-    let port_str = std::env::var("PORT")
-        .expect("Missing env variable PORT containing port to bind server");
-    port_str.parse::<u16>()
-        .expect(&format!("Env variable PORT contains non numeric value: {}", port_str))
-}
-
-fn ui_static_assets() -> String {
-    let static_assets = std::env::var("STATIC_ASSETS")
-        .expect("Missing env variable STATIC_ASSETS containing path to UI files");
-    let index_html = Path::new(&static_assets).join("index.html");
-    if !index_html.exists() || !index_html.is_file() {
-        panic!("Env variable STATIC_ASSETS does not point to an existing directory with index.html: {}", static_assets);
-    }
-    static_assets
-}
-
-async fn user_connected(ws: WebSocket, users: Users) {
-    // Use a counter to assign a new unique ID for this user.
-    let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
-
-    info!("new chat user: {}", my_id);
-
-    // Split the socket into a sender and receive of messages.
-    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
-
-    // Use an unbounded channel to handle buffering and flushing of messages
-    // to the websocket...
-    let (tx, rx) = mpsc::unbounded_channel();
-    let mut rx = UnboundedReceiverStream::new(rx);
+    init_tracing();
 
+    let server = ChatServer::from_env().start().await
+        .unwrap_or_else(|e| panic!("Could not start: {}", e));
+    let shutdown = server.shutdown_handle();
     tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
-            user_ws_tx
-                .send(message)
-                .unwrap_or_else(|e| {
-                    error!("websocket send error: {}", e);
-                })
-                .await;
-        }
+        terminated().await;
+        shutdown.shutdown();
     });
+    server.stopped().await;
+}
 
-    // Save the sender in our list of connected users.
-    users.write().await.insert(my_id, tx);
-
-    // Return a `Future` that is basically a state machine managing
-    // this specific user's connection.
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    // Every time the user sends a message, broadcast it to
-    // all other users...
-    while let Some(result) = user_ws_rx.next().await {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                error!("websocket error(uid={}): {}", my_id, e);
-                break;
-            }
-        };
-        user_message(my_id, msg, &users).await;
+    let mut terminate = signal(SignalKind::terminate())
+        .expect("Could not listen to SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
     }
-
-    // user_ws_rx stream will keep processing as long as the user stays
-    // connected. Once they disconnect, then...
-    user_disconnected(my_id, &users).await;
 }
 
-async fn user_message(my_id: usize, msg: Message, users: &Users) {
-    // Skip any non-Text messages...
-    let msg = if let Ok(s) = msg.to_str() {
-        s
-    } else {
-        return;
-    };
-
-    let new_msg = format!("<User#{}>: {}", my_id, msg);
+#[cfg(not(unix))]
+async fn terminated() {
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-    // New message from this user, send it to everyone else (except same uid)...
-    for (&uid, tx) in users.read().await.iter() {
-        if my_id != uid {
-            if let Err(_disconnected) = tx.send(Message::text(new_msg.clone())) {
-                // The tx is disconnected, our `user_disconnected` code
-                // should be happening in another task, nothing more to
-                // do here.
-            }
-        }
+/// Human readable logs by default, one JSON object per line with LOG_FORMAT=json.
+/// RUST_LOG filters as usual and defaults to info.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().with_current_span(true).with_span_list(false).init(),
+        Ok("pretty") | Err(_) => subscriber.init(),
+        Ok(format) => panic!("Env variable LOG_FORMAT contains unknown format: {}", format),
     }
 }
-
-async fn user_disconnected(my_id: usize, users: &Users) {
-    info!("good bye user: {}", my_id);
-    // Stream closed up, so remove from the user list
-    users.write().await.remove(&my_id);
-}
\ No newline at end of file
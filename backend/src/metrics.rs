@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use tracing::error;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// Counters and gauges exposed to Prometheus on GET /metrics.
+pub struct Metrics {
+    registry: Registry,
+    pub users: IntGauge,
+    pub connections: IntGauge,
+    pub messages_received: IntCounter,
+    pub messages_broadcast: IntCounter,
+    pub bytes_sent: IntCounter,
+    pub outbound_queue_depth: IntGauge,
+    pub send_errors: IntCounter,
+    pub handshake_failures: IntCounter,
+    pub peers: IntGauge,
+    pub deflate_sent_bytes: IntCounter,
+    pub deflate_sent_compressed_bytes: IntCounter,
+    pub deflate_received_bytes: IntCounter,
+    pub deflate_received_compressed_bytes: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGauge::new(name, help).unwrap(); // Names are valid constants
+            registry.register(Box::new(gauge.clone())).unwrap(); // Registered only once
+            gauge
+        };
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).unwrap(); // Names are valid constants
+            registry.register(Box::new(counter.clone())).unwrap(); // Registered only once
+            counter
+        };
+
+        Metrics {
+            users: gauge("chat_users", "Users in the chat, including those who may still resume"),
+            connections: gauge("chat_connections", "Open websocket connections"),
+            messages_received: counter("chat_messages_received_total", "Messages received from users"),
+            messages_broadcast: counter("chat_messages_broadcast_total", "Messages queued for delivery to other users"),
+            bytes_sent: counter("chat_bytes_sent_total", "Bytes written to websockets"),
+            outbound_queue_depth: gauge("chat_outbound_queue_depth", "Events queued for all websockets but not written yet"),
+            send_errors: counter("chat_send_errors_total", "Failed writes to websockets"),
+            handshake_failures: counter("chat_handshake_failures_total", "Rejected websocket upgrades on /chat"),
+            peers: gauge("chat_peers", "Other backends of the chat this one hears from"),
+            deflate_sent_bytes: counter("chat_deflate_sent_bytes_total", "Bytes of compressed websocket messages sent, before compression"),
+            deflate_sent_compressed_bytes: counter("chat_deflate_sent_compressed_bytes_total", "Bytes of compressed websocket messages sent, after compression"),
+            deflate_received_bytes: counter("chat_deflate_received_bytes_total", "Bytes of compressed websocket messages received, once inflated"),
+            deflate_received_compressed_bytes: counter("chat_deflate_received_compressed_bytes_total", "Bytes of compressed websocket messages received, as received"),
+            registry,
+        }
+    }
+
+    fn render(&self) -> Response {
+        let encoder = TextEncoder::new();
+        let mut buffer = vec![];
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buffer) {
+            error!("could not encode metrics: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        warp::reply::with_header(buffer, "content-type", encoder.format_type()).into_response()
+    }
+}
+
+/// GET /metrics
+pub fn route(metrics: Arc<Metrics>) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || metrics.render())
+}
@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use warp::http::header::{self, HeaderValue};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// The first path segments of the REST endpoints, which answer preflight requests.
+const REST_PATHS: [&str; 6] = ["api", "admin", "files", "poll", "send", "sse"];
+
+/// The request came from a page of an origin which may not use the chat.
+#[derive(Debug)]
+pub struct ForbiddenOrigin;
+
+impl warp::reject::Reject for ForbiddenOrigin {}
+
+/// The origins of web pages which may use the chat from a browser, besides the page
+/// served by the chat itself. Without any, only that page may.
+pub struct Origins {
+    /// Like `https://chat.example.com`
+    allowed: Vec<String>,
+}
+
+impl Origins {
+    pub fn new(allowed: Vec<String>) -> Origins {
+        Origins { allowed }
+    }
+
+    /// What a request with `origin` for `host` over `scheme` may do: `Ok(Some(origin))`
+    /// when it is another allowed origin which gets CORS headers, `Ok(None)` when it needs none.
+    fn check(&self, origin: Option<&str>, host: Option<&str>, scheme: &str) -> Result<Option<String>, ForbiddenOrigin> {
+        let origin = match origin {
+            // Not a browser, or a page of our own loading something
+            None => return Ok(None),
+            Some(origin) => origin.trim_end_matches('/'),
+        };
+        // A page served over http is not ours when we are served over https
+        let same = host.is_some_and(|host| origin.eq_ignore_ascii_case(&format!("{}://{}", scheme, host)));
+        if same {
+            Ok(None)
+        } else if self.allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            Ok(Some(origin.to_owned()))
+        } else {
+            Err(ForbiddenOrigin)
+        }
+    }
+}
+
+/// The origins in ALLOWED_ORIGINS separated by commas, none means only our own.
+pub fn from_env() -> Vec<String> {
+    let origins = std::env::var("ALLOWED_ORIGINS").unwrap_or_default();
+    origins.split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            let origin = origin.trim_end_matches('/');
+            match origin.split_once("://") {
+                Some((scheme, authority)) if !scheme.is_empty() && !authority.is_empty() && !authority.contains('/') =>
+                    origin.to_owned(),
+                _ => panic!("Env variable ALLOWED_ORIGINS contains no origin like https://chat.example.com: {}", origin),
+            }
+        })
+        .collect()
+}
+
+/// Rejects requests from pages of other origins which may not use the chat with
+/// `ForbiddenOrigin`, passes on the origin which gets CORS headers, if any.
+///
+/// We serve plain http, unless a proxy in front of us tells otherwise in `X-Forwarded-Proto`.
+pub fn allowed(origins: Arc<Origins>) -> impl Filter<Extract=(Option<String>,), Error=Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .and_then(move |origin: Option<String>, host: Option<String>, proto: Option<String>| {
+            // Proxies behind proxies append theirs, the first one is what the browser used
+            let scheme = proto.as_deref()
+                .and_then(|proto| proto.split(',').next())
+                .map_or("http", str::trim);
+            let checked = origins.check(origin.as_deref(), host.as_deref(), scheme).map_err(warp::reject::custom);
+            async move { checked }
+        })
+}
+
+/// Answers preflight requests for `routes` and adds CORS headers to what they answer,
+/// for the allowed origins only.
+pub fn cors(
+    origins: Arc<Origins>,
+    routes: impl Filter<Extract=(Response,), Error=Rejection> + Clone + Send + Sync + 'static,
+) -> impl Filter<Extract=(Response,), Error=Rejection> + Clone {
+    let preflight = warp::options()
+        .and(warp::path::peek())
+        .and_then(|path: warp::path::Peek| async move {
+            match path.segments().next() {
+                Some(first) if REST_PATHS.contains(&first) => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+        .and(warp::header::<String>("access-control-request-method"))
+        .and(allowed(origins.clone()))
+        .map(|_method: String, origin: Option<String>| {
+            let mut response = with_headers(StatusCode::NO_CONTENT.into_response(), origin);
+            let headers = response.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST, DELETE"));
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("authorization, content-type"));
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+            response
+        });
+    let answered = allowed(origins)
+        .and(routes)
+        .map(|origin: Option<String>, response: Response| with_headers(response, origin));
+    preflight.or(answered).unify()
+}
+
+fn with_headers(mut response: Response, origin: Option<String>) -> Response {
+    if let Some(origin) = origin.and_then(|origin| HeaderValue::from_str(&origin).ok()) {
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        // Logged in users are known by their session cookie
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        headers.append(header::VARY, HeaderValue::from_static("origin"));
+    }
+    response
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<ForbiddenOrigin>().is_some() {
+        Ok(warp::reply::with_status("Origin not allowed", StatusCode::FORBIDDEN).into_response())
+    } else {
+        Err(rejection)
+    }
+}
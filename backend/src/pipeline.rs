@@ -0,0 +1,74 @@
+use protocol::{Attachment, ServerEvent};
+use tracing::debug;
+
+/// A message on its way from a user to the others in its room.
+pub struct Incoming {
+    pub uid: usize,
+    pub name: String,
+    pub room: String,
+    pub text: String,
+    pub attachments: Vec<Attachment>,
+}
+
+/// Who gets an event emitted by a middleware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Audience {
+    /// Only the user who sent the message.
+    Sender,
+    /// Everybody in the room of the message, the sender included.
+    Room,
+}
+
+/// The message must not reach anybody, the sender is told why.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+/// A step every message goes through before it is broadcast. A middleware may look at
+/// the message, change it, reject it, or emit events of its own.
+pub trait Middleware: Send + Sync {
+    fn process(&self, message: &mut Incoming, emit: &mut Vec<(Audience, ServerEvent)>) -> Result<(), Rejected>;
+}
+
+impl Middleware for Box<dyn Middleware> {
+    fn process(&self, message: &mut Incoming, emit: &mut Vec<(Audience, ServerEvent)>) -> Result<(), Rejected> {
+        (**self).process(message, emit)
+    }
+}
+
+/// The middlewares in the order they were added.
+#[derive(Default)]
+pub struct Pipeline {
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> Pipeline {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Stops at the first middleware which rejects the message. The events emitted
+    /// until then are delivered either way.
+    pub fn process(&self, message: &mut Incoming, emit: &mut Vec<(Audience, ServerEvent)>) -> Result<(), Rejected> {
+        for middleware in self.middlewares.iter() {
+            middleware.process(message, emit)
+                .inspect_err(|Rejected(reason)| debug!(%reason, "message rejected"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Rejects messages longer than `max` characters.
+pub struct MaxLength {
+    pub max: usize,
+}
+
+impl Middleware for MaxLength {
+    fn process(&self, message: &mut Incoming, _: &mut Vec<(Audience, ServerEvent)>) -> Result<(), Rejected> {
+        if message.text.chars().count() > self.max {
+            Err(Rejected(format!("Messages must not be longer than {} characters", self.max)))
+        } else {
+            Ok(())
+        }
+    }
+}
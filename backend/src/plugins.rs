@@ -0,0 +1,183 @@
+use std::fs;
+use std::path::Path;
+
+use protocol::ServerEvent;
+use serde::Serialize;
+use tracing::{info, warn};
+use wasmi::core::Trap;
+use wasmi::{Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::bots::{Bot, Say};
+
+/// Longer replies trap, like replies outside of the memory of the plugin.
+const MAX_REPLY_LEN: usize = 64 << 10;
+
+/// What a plugin may use up per event.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Roughly the number of instructions a plugin may execute.
+    pub fuel: u64,
+    pub memory_bytes: usize,
+}
+
+/// The message handed to a plugin as JSON.
+#[derive(Serialize)]
+struct PluginMessage<'a> {
+    room: &'a str,
+    uid: usize,
+    name: &'a str,
+    text: &'a str,
+}
+
+struct PluginState {
+    limits: StoreLimits,
+    /// Filled by `chat.reply` while the plugin handles a message.
+    replies: Vec<String>,
+}
+
+/// A bot compiled to WebAssembly, run in a sandbox with limited fuel and memory.
+///
+/// A plugin exports its `memory`, `alloc(len: i32) -> i32` returning where the host may
+/// write `len` bytes, and `on_message(ptr: i32, len: i32)` which gets the message as JSON
+/// with `room`, `uid`, `name` and `text`. It may call the import `chat.reply(ptr: i32, len: i32)`
+/// with UTF-8 text of up to 64 KiB any number of times to answer in the room of the message.
+pub struct WasmBot {
+    name: String,
+    store: Store<PluginState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_message: TypedFunc<(i32, i32), ()>,
+    fuel: u64,
+    /// All fuel ever added, to tell what is left.
+    fuel_added: u64,
+}
+
+impl WasmBot {
+    pub fn load(name: String, wasm: &[u8], limits: Limits) -> Result<WasmBot, String> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)
+            .map_err(|e| format!("Invalid module: {}", e))?;
+
+        let state = PluginState {
+            limits: StoreLimitsBuilder::new()
+                .memory_size(limits.memory_bytes)
+                .build(),
+            replies: vec![],
+        };
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limits);
+
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap("chat", "reply", reply)
+            .map_err(|e| format!("Could not define chat.reply: {}", e))?;
+
+        // The start function runs with the fuel of one event too
+        store.add_fuel(limits.fuel)
+            .map_err(|e| format!("Could not add fuel: {}", e))?;
+        let instance = linker.instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|e| format!("Could not instantiate: {}", e))?;
+
+        let memory = memory(&instance, &store).ok_or("Missing export memory")?;
+        let alloc = instance.get_typed_func(&store, "alloc")
+            .map_err(|e| format!("Missing export alloc: {}", e))?;
+        let on_message = instance.get_typed_func(&store, "on_message")
+            .map_err(|e| format!("Missing export on_message: {}", e))?;
+
+        Ok(WasmBot { name, store, memory, alloc, on_message, fuel: limits.fuel, fuel_added: limits.fuel })
+    }
+
+    /// Returns the replies of the plugin, everything it said before running out of fuel
+    /// or failing otherwise is lost.
+    fn handle(&mut self, message: &PluginMessage) -> Result<Vec<String>, String> {
+        // Every event gets the same amount of fuel, whatever the previous ones left
+        let consumed = self.store.fuel_consumed().unwrap_or(0); // Metering is enabled
+        let remaining = self.fuel_added - consumed;
+        self.store.add_fuel(self.fuel - remaining)
+            .map_err(|e| format!("Could not add fuel: {}", e))?;
+        self.fuel_added += self.fuel - remaining;
+
+        let json = serde_json::to_vec(message)
+            .map_err(|e| format!("Could not encode message: {}", e))?;
+        let len = i32::try_from(json.len()).map_err(|_| "Message too long")?;
+        let ptr = self.alloc.call(&mut self.store, len)
+            .map_err(|e| format!("alloc failed: {}", e))?;
+        self.memory.write(&mut self.store, ptr as usize, &json)
+            .map_err(|e| format!("alloc returned invalid memory: {}", e))?;
+
+        self.store.data_mut().replies.clear();
+        self.on_message.call(&mut self.store, (ptr, len))
+            .map_err(|e| format!("on_message failed: {}", e))?;
+        Ok(std::mem::take(&mut self.store.data_mut().replies))
+    }
+}
+
+impl Bot for WasmBot {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn on_event(&mut self, room: &str, event: &ServerEvent, say: &Say) {
+        let message = match event {
+            ServerEvent::Message { uid, name, text, .. } => PluginMessage { room, uid: *uid, name, text },
+            _ => return,
+        };
+        match self.handle(&message) {
+            Ok(replies) => replies.into_iter().for_each(|reply| say.now(room, reply)),
+            Err(e) => warn!(plugin = %self.name, "plugin failed: {}", e),
+        }
+    }
+
+    /// Up to all of its fuel per event
+    fn blocking(&self) -> bool {
+        true
+    }
+}
+
+fn memory(instance: &Instance, store: &Store<PluginState>) -> Option<Memory> {
+    instance.get_export(store, "memory").and_then(Extern::into_memory)
+}
+
+/// chat.reply(ptr, len)
+fn reply(mut caller: Caller<'_, PluginState>, ptr: i32, len: i32) -> Result<(), Trap> {
+    let memory = caller.get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("Missing export memory"))?;
+    let (start, len) = match (usize::try_from(ptr), usize::try_from(len)) {
+        (Ok(start), Ok(len)) if len <= MAX_REPLY_LEN => (start, len),
+        _ => return Err(Trap::new(format!("Invalid reply length {}, at most {} bytes", len, MAX_REPLY_LEN))),
+    };
+    // Looked at in place, nothing is allocated for replies which do not fit
+    let text = memory.data(&caller).get(start..start + len)
+        .ok_or_else(|| Trap::new(format!("Invalid reply memory {}..{}", start, start + len)))?;
+    let text = std::str::from_utf8(text)
+        .map_err(|_| Trap::new("Reply is not UTF-8"))?
+        .to_owned();
+    caller.data_mut().replies.push(text);
+    Ok(())
+}
+
+/// Every *.wasm file in the directory is a bot named after the file.
+pub fn load_dir(dir: &Path, limits: Limits) -> Vec<Box<dyn Bot>> {
+    let entries = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Could not read plugins from {:?}: {}", dir, e));
+    let mut paths: Vec<_> = entries
+        .map(|entry| entry.unwrap_or_else(|e| panic!("Could not read plugins from {:?}: {}", dir, e)).path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "wasm"))
+        .collect();
+    paths.sort();
+
+    paths.into_iter()
+        .map(|path| -> Box<dyn Bot> {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned(); // Has an extension
+            let wasm = fs::read(&path)
+                .unwrap_or_else(|e| panic!("Could not read plugin {:?}: {}", path, e));
+            let bot = WasmBot::load(name, &wasm, limits)
+                .unwrap_or_else(|e| panic!("Could not load plugin {:?}: {}", path, e));
+            info!(?path, "plugin loaded");
+            Box::new(bot)
+        })
+        .collect()
+}
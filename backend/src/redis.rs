@@ -0,0 +1,260 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, info_span, warn, Instrument};
+use url::Url;
+
+use crate::broker::{ride_bus, BoxFuture, Broker, Envelope, Node, Relays};
+
+/// Before connecting again to a Redis server which could not be reached or went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest line of a reply, errors included, and longest bulk string. Envelopes are far
+/// shorter, anything longer is not from a server we can trust.
+const MAX_LINE: u64 = 64 << 10;
+const MAX_BULK: i64 = 16 << 20;
+/// Most values in an array, the server pushes arrays of 3.
+const MAX_ARRAY: i64 = 1024;
+
+/// A broker where the backends publish to and subscribe to one channel of a Redis
+/// server, or anything else speaking its protocol. Backends which stop publishing are
+/// gone after a few seconds, with their users.
+pub struct RedisBroker {
+    /// `redis://[[user]:password@]host[:port]`
+    pub url: Url,
+    pub channel: String,
+}
+
+impl RedisBroker {
+    /// The server in REDIS_URL and its channel in REDIS_CHANNEL, `rust-chat` without one.
+    /// CLUSTER_NODE tells which of the CLUSTER_SIZE backends sharing the channel this
+    /// is, counting from 1.
+    pub fn from_env() -> Option<(Node, RedisBroker)> {
+        let url = std::env::var("REDIS_URL").ok()?;
+        let url = Url::parse(&url).ok().filter(|url| url.scheme() == "redis" && url.host_str().is_some())
+            .unwrap_or_else(|| panic!("Env variable REDIS_URL contains no redis:// url: {}", url));
+        let channel = std::env::var("REDIS_CHANNEL").unwrap_or_else(|_| "rust-chat".to_owned());
+        let size = std::env::var("CLUSTER_SIZE")
+            .expect("Missing env variable CLUSTER_SIZE containing the number of backends sharing REDIS_URL");
+        let size = size.parse::<usize>().ok().filter(|&size| size >= 1)
+            .unwrap_or_else(|| panic!("Env variable CLUSTER_SIZE contains no positive number: {}", size));
+        let node = std::env::var("CLUSTER_NODE")
+            .expect("Missing env variable CLUSTER_NODE containing the number of this backend");
+        let node = node.parse::<usize>().ok().filter(|&node| node >= 1 && node <= size)
+            .unwrap_or_else(|| panic!("Env variable CLUSTER_NODE contains no number up to CLUSTER_SIZE: {}", node));
+        Some((Node { index: node - 1, count: size }, RedisBroker { url, channel }))
+    }
+}
+
+impl Broker for RedisBroker {
+    /// Fails when the server cannot be reached at first, connects again whenever it is
+    /// lost later.
+    fn start(self: Box<Self>, relays: Relays, mut shutdown: watch::Receiver<bool>) -> BoxFuture<Result<(), String>> {
+        Box::pin(async move {
+            let mut bus = self.connect().await
+                .map_err(|e| format!("Could not connect to Redis at {}: {}", self.url, e))?;
+            info!(channel = %self.channel, "subscribed to {}", self.url);
+            let span = info_span!("redis", node = relays.node() + 1, channel = %self.channel);
+            tokio::task::spawn(async move {
+                loop {
+                    let (to_bus, from_bus) = bus;
+                    if ride_bus(&relays, to_bus, from_bus, &mut shutdown).await {
+                        return;
+                    }
+                    warn!("lost Redis");
+                    relays.set_connected(false);
+                    bus = loop {
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                        match self.connect().await {
+                            Ok(bus) => break bus,
+                            Err(e) => warn!("could not connect to Redis: {}", e),
+                        }
+                    };
+                    info!("subscribed again");
+                    relays.set_connected(true);
+                }
+            }.instrument(span));
+            Ok(())
+        })
+    }
+}
+
+impl RedisBroker {
+    /// Subscribes to the channel, and publishes what goes to the sender on another
+    /// connection. The receiver ends once either connection is lost.
+    async fn connect(&self) -> io::Result<(mpsc::UnboundedSender<Envelope>, mpsc::UnboundedReceiver<Envelope>)> {
+        let (mut subscriber, mut subscriber_commands) = self.open().await?;
+        let (mut publisher, mut publisher_commands) = self.open().await?;
+        subscriber_commands.send(&[b"SUBSCRIBE", self.channel.as_bytes()]).await?;
+        match read_array(&mut subscriber).await?.first() {
+            Some(Value::Bulk(kind)) if kind == b"subscribe" => {}
+            _ => return Err(invalid("unexpected answer to SUBSCRIBE")),
+        }
+
+        let (to_bus, mut to_bus_rx) = mpsc::unbounded_channel::<Envelope>();
+        let (from_bus_tx, from_bus) = mpsc::unbounded_channel();
+        let subscribed = tokio::task::spawn(async move {
+            // Dropping it would close our side of the connection
+            let _subscriber_commands = subscriber_commands;
+            loop {
+                let message = match read_array(&mut subscriber).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("lost subscription: {}", e);
+                        return;
+                    }
+                };
+                let payload = match message.as_slice() {
+                    [Value::Bulk(kind), _, Value::Bulk(payload)] if kind == b"message" => payload,
+                    _ => continue,
+                };
+                match serde_json::from_slice(payload) {
+                    Ok(envelope) => {
+                        if from_bus_tx.send(envelope).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("unexpected message on the channel: {}", e),
+                }
+            }
+        });
+        let answered = tokio::task::spawn(async move {
+            // Every PUBLISH is answered by the number of subscribers it reached
+            loop {
+                match read_value(&mut publisher).await {
+                    Ok(Value::Error(e)) => warn!("could not publish: {}", e),
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("lost publisher: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        let channel = self.channel.clone();
+        tokio::task::spawn(async move {
+            let publishing = async {
+                while let Some(envelope) = to_bus_rx.recv().await {
+                    let payload = serde_json::to_vec(&envelope)
+                        .unwrap(); // Events and members serialize fine
+                    if let Err(e) = publisher_commands.send(&[b"PUBLISH", channel.as_bytes(), &payload]).await {
+                        warn!("lost publisher: {}", e);
+                        return;
+                    }
+                }
+            };
+            tokio::select! {
+                _ = publishing => {}
+                _ = answered => {}
+            }
+            // Ends the receiver, the bus is lost
+            subscribed.abort();
+        });
+        Ok((to_bus, from_bus))
+    }
+
+    /// A connection to the server, authenticated if the url has a password.
+    async fn open(&self) -> io::Result<(BufReader<OwnedReadHalf>, Commands)> {
+        let host = self.url.host_str().ok_or_else(|| invalid("no host in the url"))?;
+        let stream = TcpStream::connect((host, self.url.port().unwrap_or(6379))).await?;
+        let (reader, writer) = stream.into_split();
+        let (mut reader, mut commands) = (BufReader::new(reader), Commands(writer));
+        if let Some(password) = self.url.password() {
+            let user = self.url.username();
+            if user.is_empty() {
+                commands.send(&[b"AUTH", password.as_bytes()]).await?;
+            } else {
+                commands.send(&[b"AUTH", user.as_bytes(), password.as_bytes()]).await?;
+            }
+            if let Value::Error(e) = read_value(&mut reader).await? {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+            }
+        }
+        Ok((reader, commands))
+    }
+}
+
+/// Writes commands to a connection.
+struct Commands(OwnedWriteHalf);
+
+impl Commands {
+    async fn send(&mut self, args: &[&[u8]]) -> io::Result<()> {
+        let mut command = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            command.extend_from_slice(arg);
+            command.extend_from_slice(b"\r\n");
+        }
+        self.0.write_all(&command).await
+    }
+}
+
+/// What the server answers or pushes, arrays only at the top.
+#[derive(Debug)]
+enum Value {
+    Simple,
+    Error(String),
+    Integer,
+    Bulk(Vec<u8>),
+    Nil,
+    Array(usize),
+}
+
+async fn read_array(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<Vec<Value>> {
+    match read_value(reader).await? {
+        Value::Array(len) => {
+            let mut values = Vec::with_capacity(len);
+            for _ in 0..len {
+                match read_value(reader).await? {
+                    Value::Array(_) => return Err(invalid("nested array")),
+                    value => values.push(value),
+                }
+            }
+            Ok(values)
+        }
+        value => Err(invalid(&format!("expected an array, but got {:?}", value))),
+    }
+}
+
+/// The next value, only the length of an array. Fails for lengths beyond the limits
+/// before anything is allocated for them.
+async fn read_value(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<Value> {
+    let mut line = String::new();
+    if reader.take(MAX_LINE).read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.strip_suffix("\r\n").ok_or_else(|| invalid("line too long or cut off"))?;
+    let (kind, rest) = line.split_at(line.len().min(1));
+    // -1 stands for nil, other lengths must be within the limit
+    let length = |max: i64| match rest.parse::<i64>() {
+        Ok(-1) => Ok(None),
+        Ok(len) if (0..=max).contains(&len) => Ok(Some(len as usize)),
+        _ => Err(invalid(&format!("not a length up to {}: {}", max, rest))),
+    };
+    Ok(match kind {
+        "+" => Value::Simple,
+        "-" => Value::Error(rest.to_owned()),
+        ":" => Value::Integer,
+        "$" => match length(MAX_BULK)? {
+            Some(len) => {
+                let mut bulk = vec![0; len + 2];
+                reader.read_exact(&mut bulk).await?;
+                bulk.truncate(len);
+                Value::Bulk(bulk)
+            }
+            None => Value::Nil,
+        },
+        "*" => match length(MAX_ARRAY)? {
+            Some(len) => Value::Array(len),
+            None => Value::Nil,
+        },
+        _ => return Err(invalid(&format!("unknown type: {}", line))),
+    })
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
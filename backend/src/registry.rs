@@ -0,0 +1,280 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use protocol::{ServerEvent, UserInfo, DEFAULT_ROOM};
+
+use crate::chat::Outbox;
+use crate::broker::Node;
+
+/// Enough for many more cores than a chat backend gets.
+const SHARDS: usize = 64;
+
+/// A map spread over shards with a lock each, so that different keys are served in parallel.
+pub struct Sharded<K, V> {
+    shards: Vec<Mutex<HashMap<K, V>>>,
+}
+
+impl<K: Hash + Eq, V> Sharded<K, V> {
+    /// The shard holding `key`.
+    pub fn lock<Q: Hash + ?Sized>(&self, key: &Q) -> MutexGuard<'_, HashMap<K, V>> where K: Borrow<Q> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % SHARDS].lock()
+            .unwrap() // Never held across a panic
+    }
+
+    /// Every shard, locked one after the other.
+    pub fn each(&self) -> impl Iterator<Item = MutexGuard<'_, HashMap<K, V>>> {
+        self.shards.iter().map(|shard| shard.lock()
+            .unwrap()) // Never held across a panic
+    }
+}
+
+impl<K, V> Default for Sharded<K, V> {
+    fn default() -> Self {
+        Sharded { shards: (0..SHARDS).map(|_| Mutex::default()).collect() }
+    }
+}
+
+/// Who is in a room and what it is about, for users joining it.
+pub struct Presence {
+    pub users: Vec<UserInfo>,
+    pub topic: Option<String>,
+    pub last_id: u64,
+}
+
+/// The members, live connections, topic and recent messages of every room, for sending
+/// events out. Rooms are sharded, so that rooms are served in parallel and users coming
+/// and going in one room do not hold up the messages of others.
+/// Within a room everything goes out in the order it was sent.
+///
+/// A room is there while it has members. Users of this node open new ones only while there
+/// are fewer than `max_rooms`, but the default room whenever.
+pub struct Registry {
+    rooms: Sharded<String, Room>,
+    /// Of this node and any other
+    room_count: AtomicUsize,
+    max_rooms: usize,
+    history_size: usize,
+    node: Node,
+    /// Of this node or any other, messages of other nodes move it on too
+    last_message_id: AtomicU64,
+}
+
+#[derive(Default)]
+struct Room {
+    /// Names of the users in the room, those waiting to be resumed and remote ones included.
+    /// Key is the user id
+    members: HashMap<usize, String>,
+    /// Key is the user id, users waiting to be resumed are missing
+    connections: HashMap<usize, Outbox>,
+    /// Messages with the id of their sender
+    history: VecDeque<(usize, ServerEvent)>,
+    topic: Option<String>,
+}
+
+impl Registry {
+    pub fn new(history_size: usize, max_rooms: usize, node: Node) -> Registry {
+        Registry {
+            rooms: Sharded::default(),
+            room_count: AtomicUsize::new(0),
+            max_rooms,
+            history_size,
+            node,
+            last_message_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn last_message_id(&self) -> u64 {
+        self.last_message_id.load(Ordering::SeqCst)
+    }
+
+    pub fn presence(&self, room: &str) -> Presence {
+        let last_id = self.last_message_id();
+        match self.rooms.lock(room).get(room) {
+            Some(room) => room.presence(last_id),
+            None => Presence { users: vec![], topic: None, last_id },
+        }
+    }
+
+    /// A new user joins the room. It gets `welcome` with itself among the users, the others
+    /// are told like by `broadcast`, and it gets the events of the room from now on.
+    /// Returns false, and leaves the user out, when the room is new and there are too many.
+    #[must_use]
+    pub fn enter(
+        &self,
+        room_name: &str,
+        uid: usize,
+        name: String,
+        tx: Outbox,
+        welcome: impl FnOnce(Presence) -> ServerEvent,
+        also: impl FnOnce(&ServerEvent),
+    ) -> bool {
+        let mut shard = self.rooms.lock(room_name);
+        let room = match self.open(&mut shard, room_name, room_name != DEFAULT_ROOM) {
+            Some(room) => room,
+            None => return false,
+        };
+        room.members.insert(uid, name.clone());
+        let _ = tx.send(welcome(room.presence(self.last_message_id())));
+        let joined = ServerEvent::Joined { uid, name };
+        also(&joined);
+        send(room, uid, &joined);
+        room.connections.insert(uid, tx);
+        true
+    }
+
+    /// The user is back in the room. It gets `welcome`, then the messages of others after
+    /// `last_seen`, and the events of the room from now on. Returns the number of messages
+    /// it missed.
+    pub fn resume(&self, room: &str, uid: usize, last_seen: u64, tx: Outbox, welcome: impl FnOnce(Presence) -> ServerEvent) -> usize {
+        let mut shard = self.rooms.lock(room);
+        let room = self.open(&mut shard, room, false)
+            .unwrap(); // Not capped
+        let _ = tx.send(welcome(room.presence(self.last_message_id())));
+        let missed: Vec<_> = room.history.iter()
+            .filter(|(sender, event)| *sender != uid && matches!(event, ServerEvent::Message { id, .. } if *id > last_seen))
+            .collect();
+        for (_, event) in missed.iter() {
+            let _ = tx.send(event.clone());
+        }
+        let missed = missed.len();
+        room.connections.insert(uid, tx);
+        missed
+    }
+
+    /// The user stays in the room, but does not get its events until it resumes.
+    pub fn detach(&self, room: &str, uid: usize) {
+        if let Some(room) = self.rooms.lock(room).get_mut(room) {
+            room.connections.remove(&uid);
+        }
+    }
+
+    /// Queues the event for everybody in the room but `from`, and hands it to `also`
+    /// in the same order. Returns the number of users it was queued for.
+    ///
+    /// Joins, leaves, renames and topics change the room too. Once the last user has left,
+    /// the room is forgotten, nobody is left who could resume, or read its history or topic.
+    pub fn broadcast(&self, room_name: &str, from: usize, event: ServerEvent, also: impl FnOnce(&ServerEvent)) -> u64 {
+        let mut shard = self.rooms.lock(room_name);
+        also(&event);
+        let room = match &event {
+            ServerEvent::Joined { .. } => self.open(&mut shard, room_name, false)
+                .unwrap(), // Not capped
+            _ => match shard.get_mut(room_name) {
+                Some(room) => room,
+                None => return 0,
+            },
+        };
+        match &event {
+            ServerEvent::Joined { uid, name } => {
+                room.members.insert(*uid, name.clone());
+            }
+            ServerEvent::Left { uid, .. } => {
+                room.members.remove(uid);
+                room.connections.remove(uid);
+            }
+            ServerEvent::Renamed { uid, name } => {
+                if let Some(member) = room.members.get_mut(uid) {
+                    *member = name.clone();
+                }
+            }
+            ServerEvent::Topic { topic, .. } => room.topic = Some(topic.clone()),
+            _ => {}
+        }
+        let recipients = send(room, from, &event);
+        if room.members.is_empty() && matches!(event, ServerEvent::Left { .. }) {
+            shard.remove(room_name);
+            self.room_count.fetch_sub(1, Ordering::SeqCst);
+        }
+        recipients
+    }
+
+    /// Gives the message the next id, keeps it in the history of the room and queues it
+    /// like `broadcast`. Returns the id and the number of users it was queued for.
+    /// A room without members here keeps nothing, it is only handed to `also`.
+    pub fn publish(
+        &self,
+        room: &str,
+        from: usize,
+        message: impl FnOnce(u64) -> ServerEvent,
+        also: impl FnOnce(&ServerEvent),
+    ) -> (u64, u64) {
+        let mut shard = self.rooms.lock(room);
+        // Taken while the room is locked, so that ids grow in the order messages go out
+        let last = self.last_message_id.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(self.node.next_id(last)))
+            .unwrap(); // Always gives a new value
+        let id = self.node.next_id(last);
+        let event = message(id);
+        also(&event);
+        match shard.get_mut(room) {
+            Some(room) => {
+                room.keep(self.history_size, from, &event);
+                (id, send(room, from, &event))
+            }
+            None => (id, 0),
+        }
+    }
+
+    /// Keeps the message of another node with its id, and queues it for everybody in the
+    /// room, unless the room has no members here. Messages of this node get ids above it
+    /// from now on.
+    pub fn publish_relayed(&self, room: &str, from: usize, id: u64, event: ServerEvent) -> u64 {
+        let mut shard = self.rooms.lock(room);
+        self.last_message_id.fetch_max(id, Ordering::SeqCst);
+        match shard.get_mut(room) {
+            Some(room) => {
+                room.keep(self.history_size, from, &event);
+                send(room, from, &event)
+            }
+            None => 0,
+        }
+    }
+
+    /// The room in the shard, created unless it is there already. `None` when it would be
+    /// one too many for a `capped` one.
+    fn open<'a>(&self, shard: &'a mut HashMap<String, Room>, name: &str, capped: bool) -> Option<&'a mut Room> {
+        if !shard.contains_key(name) {
+            let count = |count: usize| (!capped || count < self.max_rooms).then_some(count + 1);
+            self.room_count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, count).ok()?;
+        }
+        Some(shard.entry(name.to_owned()).or_default())
+    }
+}
+
+impl Room {
+    fn presence(&self, last_id: u64) -> Presence {
+        let users = self.members.iter()
+            .map(|(&uid, name)| UserInfo { uid, name: name.clone() })
+            .collect();
+        Presence { users, topic: self.topic.clone(), last_id }
+    }
+
+    fn keep(&mut self, history_size: usize, from: usize, event: &ServerEvent) {
+        if self.history.len() == history_size {
+            self.history.pop_front();
+        }
+        if history_size > 0 {
+            self.history.push_back((from, event.clone()));
+        }
+    }
+}
+
+fn send(room: &Room, from: usize, event: &ServerEvent) -> u64 {
+    let mut recipients = 0;
+    for (_, tx) in room.connections.iter().filter(|(&uid, _)| uid != from) {
+        match tx.send(event.clone()) {
+            Ok(()) => recipients += 1,
+            Err(_disconnected) => {
+                // The tx is disconnected, our `user_disconnected` code
+                // should be happening in another task, nothing more to
+                // do here.
+            }
+        }
+    }
+    recipients
+}
@@ -0,0 +1,523 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use protocol::ClientParams;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
+use futures_util::FutureExt;
+use tracing::{error, info, Instrument};
+use warp::hyper::server::conn::{AddrIncoming, AddrStream};
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::admin::{self, Bans};
+use crate::auth::{self, Oidc, OidcConfig};
+use crate::bots::{self, Bot};
+use crate::chat::ChatState;
+use crate::broker::{self, Broker, Node, Relays};
+use crate::cluster::ClusterConfig;
+use crate::deflate::DeflateConfig;
+use crate::files::{self, FileStore, FilesConfig};
+use crate::health::{self, Readiness};
+use crate::integrations::{self, Integration};
+use crate::metrics::{self, Metrics};
+use crate::origins::{self, Origins};
+use crate::pipeline::{MaxLength, Middleware, Pipeline};
+use crate::plugins::{self, Limits};
+use crate::webhooks::{self, Webhooks, WebhooksConfig};
+use crate::websocket::{self, Upgrade};
+use crate::{connection_span, fallback, user_connected, Chat, ConnectionIds, Handshake, Negotiated};
+
+/// A chat server to be started, in this process and alongside whatever else runs in it.
+/// Everything but the address has a default, and optional features stay off unless
+/// they are configured.
+pub struct ChatServer {
+    addr: SocketAddr,
+    static_assets: Option<PathBuf>,
+    resume_grace: Duration,
+    history_size: usize,
+    max_rooms: usize,
+    max_message_length: usize,
+    middlewares: Vec<Box<dyn Middleware>>,
+    shutdown_delay: Duration,
+    oidc: Option<OidcConfig>,
+    files: Option<FilesConfig>,
+    bots: Vec<Box<dyn Bot>>,
+    integrations: Vec<Integration>,
+    webhooks: Option<WebhooksConfig>,
+    admin_token: Option<String>,
+    broker: Option<(Node, Box<dyn Broker>)>,
+    deflate: Option<DeflateConfig>,
+    allowed_origins: Vec<String>,
+}
+
+impl ChatServer {
+    /// Port 0 picks any free port, `RunningChat::addr` tells which.
+    pub fn new(addr: impl Into<SocketAddr>) -> ChatServer {
+        ChatServer {
+            addr: addr.into(),
+            static_assets: None,
+            resume_grace: Duration::from_secs(30),
+            history_size: 100,
+            max_rooms: 10_000,
+            max_message_length: 2000,
+            middlewares: vec![],
+            shutdown_delay: Duration::ZERO,
+            oidc: None,
+            files: None,
+            bots: vec![],
+            integrations: vec![],
+            webhooks: None,
+            admin_token: None,
+            broker: None,
+            deflate: None,
+            allowed_origins: vec![],
+        }
+    }
+
+    /// Configured by env variables like the `backend` binary, panics on missing or
+    /// malformed ones.
+    pub fn from_env() -> ChatServer {
+        let mut server = ChatServer::new(([127, 0, 0, 1], port()))
+            .static_assets(ui_static_assets())
+            .resume_grace(resume_grace())
+            .history_size(history_size())
+            .max_rooms(max_rooms())
+            .max_message_length(max_message_length())
+            .shutdown_delay(shutdown_delay());
+        server.oidc = OidcConfig::from_env();
+        server.files = FilesConfig::from_env();
+        server.bots = bots::from_env();
+        if let Ok(dir) = std::env::var("PLUGINS_DIR") {
+            server = server.plugins(Path::new(&dir), plugin_limits());
+        }
+        server.integrations = integrations::from_env();
+        server.webhooks = WebhooksConfig::from_env();
+        server.admin_token = admin::token_from_env();
+        server.broker = broker::from_env();
+        server.deflate = DeflateConfig::from_env();
+        server.allowed_origins = origins::from_env();
+        server
+    }
+
+    /// The UI files with index.html, there is no UI without them.
+    pub fn static_assets(mut self, dir: impl Into<PathBuf>) -> ChatServer {
+        self.static_assets = Some(dir.into());
+        self
+    }
+
+    /// How long a user who lost its connection may resume.
+    pub fn resume_grace(mut self, grace: Duration) -> ChatServer {
+        self.resume_grace = grace;
+        self
+    }
+
+    /// The messages kept per room for users who resume.
+    pub fn history_size(mut self, size: usize) -> ChatServer {
+        self.history_size = size;
+        self
+    }
+
+    /// The rooms there may be, the default room among them. Users asking for a new room
+    /// beyond it join the default room, which can always be opened.
+    pub fn max_rooms(mut self, max: usize) -> ChatServer {
+        self.max_rooms = max;
+        self
+    }
+
+    pub fn max_message_length(mut self, max: usize) -> ChatServer {
+        self.max_message_length = max;
+        self
+    }
+
+    /// Every message goes through the middlewares in the order they were added,
+    /// after its length is checked.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> ChatServer {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// How long the server stays up but not ready after a shutdown is asked for.
+    pub fn shutdown_delay(mut self, delay: Duration) -> ChatServer {
+        self.shutdown_delay = delay;
+        self
+    }
+
+    /// Login through an OpenID Connect provider.
+    pub fn oidc(mut self, config: OidcConfig) -> ChatServer {
+        self.oidc = Some(config);
+        self
+    }
+
+    /// Storage for attachments.
+    pub fn files(mut self, config: FilesConfig) -> ChatServer {
+        self.files = Some(config);
+        self
+    }
+
+    /// A bot in every room.
+    pub fn bot(mut self, bot: Box<dyn Bot>) -> ChatServer {
+        self.bots.push(bot);
+        self
+    }
+
+    /// Every *.wasm file in the directory as a bot, panics on those which do not load.
+    pub fn plugins(mut self, dir: &Path, limits: Limits) -> ChatServer {
+        self.bots.extend(plugins::load_dir(dir, limits));
+        self
+    }
+
+    pub fn integration(mut self, integration: Integration) -> ChatServer {
+        self.integrations.push(integration);
+        self
+    }
+
+    pub fn webhooks(mut self, config: WebhooksConfig) -> ChatServer {
+        self.webhooks = Some(config);
+        self
+    }
+
+    /// Enables the admin API.
+    pub fn admin_token(mut self, token: String) -> ChatServer {
+        self.admin_token = Some(token);
+        self
+    }
+
+    /// Shares the chat with other backends through the broker, as `node` of them, so that
+    /// users connected to different ones chat with each other.
+    pub fn broker(mut self, node: Node, broker: impl Broker) -> ChatServer {
+        self.broker = Some((node, Box::new(broker)));
+        self
+    }
+
+    /// Links the backends of the cluster directly with each other.
+    pub fn cluster(self, config: ClusterConfig) -> ChatServer {
+        let node = config.node();
+        self.broker(node, config)
+    }
+
+    /// Lets pages of the origin use the chat from a browser, like `https://chat.example.com`.
+    /// Pages of other origins than those and the chat itself get `403`.
+    pub fn allowed_origin(mut self, origin: impl Into<String>) -> ChatServer {
+        self.allowed_origins.push(origin.into());
+        self
+    }
+
+    /// Compresses websocket messages for clients which offer permessage-deflate.
+    pub fn deflate(mut self, config: DeflateConfig) -> ChatServer {
+        self.deflate = Some(config);
+        self
+    }
+
+    /// Binds the address and serves until the returned `RunningChat` is shut down.
+    pub async fn start(self) -> Result<RunningChat, String> {
+        // Login through an OpenID Connect provider, if one is configured
+        let oidc = match self.oidc {
+            Some(config) => Some(Arc::new(Oidc::discover(config).await
+                .map_err(|e| format!("Could not discover OpenID Connect provider: {}", e))?)),
+            None => None,
+        };
+
+        let metrics = Arc::new(Metrics::new());
+
+        // Attachments, if uploads are configured
+        let files = match self.files {
+            Some(config) => Some(Arc::new(FileStore::open(config)
+                .map_err(|e| format!("Could not open uploads: {}", e))?)),
+            None => None,
+        };
+
+        // Keep track of all connected users and what they said recently
+        // Every message goes through these before it is broadcast
+        let pipeline = self.middlewares.into_iter()
+            .fold(Pipeline::default().with(MaxLength { max: self.max_message_length }), Pipeline::with);
+        let node = self.broker.as_ref().map_or(Node::ALONE, |(node, _)| *node);
+        let chat = Chat::new(RwLock::new(ChatState::new(self.resume_grace, self.history_size, self.max_rooms, node, pipeline, metrics.clone())));
+        // Bots are in every room from the start
+        for bot in self.bots {
+            bots::spawn(chat.clone(), bot).await;
+        }
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        // Relays room events to and from the other backends, if the chat is shared
+        let relays = match self.broker {
+            Some((node, broker)) => {
+                let relays = Relays::new(chat.clone(), node, metrics.clone());
+                broker.start(relays.clone(), shutdown_rx.clone()).await?;
+                Some(relays)
+            }
+            None => None,
+        };
+
+        // Calls out for matching messages, if webhooks are configured
+        let webhooks = self.webhooks.map(|config| Arc::new(Webhooks::new(config)));
+        if let Some(webhooks) = &webhooks {
+            webhooks::spawn(chat.clone(), webhooks.clone()).await;
+        }
+
+        // POST /api/* -> messages of integrations like CI, before `chat` is turned into a filter
+        let integrations = integrations::routes(chat.clone(), self.integrations).await;
+
+        let bans = Arc::new(Bans::default());
+        // GET /admin/* -> moderation, before `chat` is turned into a filter
+        let admin = admin::routes(self.admin_token, chat.clone(), bans.clone(), webhooks);
+
+        // Tells connections apart, whatever their transport
+        let connection_ids = Arc::new(ConnectionIds::default());
+
+        // GET /sse, POST /poll, GET /poll/*, POST /send/* -> for clients without websockets
+        let fallback = fallback::routes(
+            chat.clone(), metrics.clone(), files.clone(), oidc.clone(), bans.clone(), connection_ids.clone());
+
+        // Turn our "state" into a new Filter...
+        let chat_state = warp::any().map(move || chat.clone());
+        let chat_metrics = metrics.clone();
+        let chat_metrics = warp::any().map(move || chat_metrics.clone());
+        let chat_files = files.clone();
+        let chat_files = warp::any().map(move || chat_files.clone());
+
+        // GET /chat -> websocket upgrade
+        let handshake_failures = metrics.handshake_failures.clone();
+        let deflate = self.deflate;
+        let origins = Arc::new(Origins::new(self.allowed_origins));
+        let allowed_origin = origins::allowed(origins.clone()).map(|_cors: Option<String>| ()).untuple_one();
+        let chat = warp::path("chat").and(
+            // Prepares the websocket handshake...
+            websocket::upgrade()
+                // Browsers do not ask whether pages of other origins may open websockets
+                .and(allowed_origin)
+                .and(chat_state)
+                .and(chat_metrics)
+                .and(chat_files)
+                .and(admin::admitted(auth::identity(oidc.clone()), bans.clone()))
+                .and(warp::query::<ClientParams>())
+                .and(warp::header::optional::<String>("sec-websocket-protocol"))
+                .map(move |upgrade: Upgrade, chat, metrics: Arc<Metrics>, files, identity, remote: Option<SocketAddr>, params, offered: Option<String>| {
+                    // Use a counter to tell this connection from others of the same user.
+                    let connection = connection_ids.next();
+                    let span = connection_span(connection, remote, "websocket");
+                    let protocol = Negotiated::new(offered.as_deref());
+                    let selected = protocol.selected();
+                    let handshake = Handshake { connection, remote, identity, params, protocol };
+                    // This will call our function if the handshake succeeds.
+                    let reply = upgrade.on_upgrade(deflate.as_ref(), metrics.clone(), move |socket, ratio|
+                        user_connected(socket, ratio, handshake, chat, metrics, files).instrument(span));
+                    match selected {
+                        Some(selected) => warp::reply::with_header(reply, "sec-websocket-protocol", selected).into_response(),
+                        None => reply.into_response(),
+                    }
+                })
+                .or_else(move |rejection: Rejection| {
+                    handshake_failures.inc();
+                    async move { Err(rejection) }
+                }));
+
+        // We are ready while the UI files are there, uploads can be stored, the broker is
+        // connected, and we are not shutting down
+        let mut readiness = Readiness::default();
+        if let Some(files) = files.clone() {
+            readiness.add_check("uploads", move || files.check_writable());
+        }
+        if let Some(relays) = relays {
+            readiness.add_check("broker", move || {
+                if relays.connected() {
+                    Ok(())
+                } else {
+                    Err("not connected".to_owned())
+                }
+            });
+        }
+        // GET /* -> UI
+        let static_assets = match self.static_assets {
+            Some(static_assets) => {
+                let index_html = static_assets.join("index.html");
+                readiness.add_check("static_assets", move || {
+                    if index_html.is_file() {
+                        Ok(())
+                    } else {
+                        Err(format!("{:?} is missing", index_html))
+                    }
+                });
+                warp::get().and(warp::fs::dir(static_assets)).map(Reply::into_response).boxed()
+            }
+            None => warp::any().and_then(|| async { Err::<Response, _>(warp::reject::not_found()) }).boxed(),
+        };
+        let readiness = Arc::new(readiness);
+
+        // GET /healthz, GET /readyz -> probes
+        let health = health::routes(readiness.clone());
+
+        // POST /files, GET /files/* -> attachments
+        let files = files::routes(files, oidc.clone(), bans);
+
+        // GET /auth/* -> login flow
+        let auth = auth::routes(oidc);
+
+        // GET /metrics -> Prometheus metrics
+        let metrics = metrics::route(metrics);
+
+        // The REST endpoints answer pages of the other allowed origins too
+        let rest = origins::cors(origins, admin.or(fallback).unify().or(integrations).unify().or(files).unify());
+
+        let routes = chat
+            .or(auth)
+            .or(rest)
+            .or(metrics)
+            .or(health)
+            .or(static_assets)
+            .recover(auth::handle_rejection)
+            .recover(admin::handle_rejection)
+            .recover(origins::handle_rejection)
+            .with(warp::trace::request());
+
+        // Served by hyper itself, so that websockets get their connection as it is
+        let routes = warp::service(routes);
+        let mut incoming = AddrIncoming::bind(&self.addr)
+            .map_err(|e| format!("Could not bind {}: {}", self.addr, e))?;
+        incoming.set_nodelay(true);
+        let addr = incoming.local_addr();
+        let service = make_service_fn(move |connection: &AddrStream| {
+            let (mut routes, remote) = (routes.clone(), connection.remote_addr());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut request| {
+                    websocket::arrived(&mut request);
+                    request.extensions_mut().insert(RemoteAddr(remote));
+                    routes.call(request)
+                }))
+            }
+        });
+        let server = warp::hyper::Server::builder(incoming)
+            .serve(service)
+            .with_graceful_shutdown(shutdown(shutdown_rx, readiness, self.shutdown_delay))
+            .map(|result| {
+                if let Err(e) = result {
+                    error!("server error: {}", e);
+                }
+            });
+        info!("listening on {}", addr);
+        let server = tokio::task::spawn(server);
+        Ok(RunningChat { addr, shutdown: Shutdown { tx: Arc::new(shutdown_tx) }, server })
+    }
+}
+
+/// A chat server serving in the background.
+pub struct RunningChat {
+    addr: SocketAddr,
+    shutdown: Shutdown,
+    server: JoinHandle<()>,
+}
+
+impl RunningChat {
+    /// Where the server was bound.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Completes once the server has shut down.
+    pub async fn stopped(self) {
+        // Only fails when the server panicked, which shows in the logs already
+        let _ = self.server.await;
+    }
+}
+
+/// Stops a chat server, after telling the load balancer that it is not ready
+/// anymore and giving it the shutdown delay to notice.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn shutdown(&self) {
+        // The server has stopped already when nobody listens
+        let _ = self.tx.send(true);
+    }
+}
+
+/// Where a request came from, put into its extensions when it arrives.
+#[derive(Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+/// Where the request came from, like `warp::addr::remote`.
+pub fn remote() -> impl Filter<Extract=(Option<SocketAddr>,), Error=Infallible> + Clone {
+    warp::ext::optional::<RemoteAddr>().map(|remote: Option<RemoteAddr>| remote.map(|RemoteAddr(addr)| addr))
+}
+
+/// Completes once we are asked to stop and the shutdown delay is over.
+async fn shutdown(mut rx: watch::Receiver<bool>, readiness: Arc<Readiness>, delay: Duration) {
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            // Nobody can ask us to stop anymore, serve for good
+            std::future::pending::<()>().await;
+        }
+    }
+    info!("shutting down in {:?}", delay);
+    readiness.begin_shutdown();
+    tokio::time::sleep(delay).await;
+}
+
+fn port() -> u16 {
+    let port_str = std::env::var("PORT")
+        .expect("Missing env variable PORT containing port to bind server");
+    port_str.parse::<u16>()
+        .unwrap_or_else(|_| panic!("Env variable PORT contains non numeric value: {}", port_str))
+}
+
+fn resume_grace() -> Duration {
+    let grace = std::env::var("RESUME_GRACE_SECS").unwrap_or_else(|_| "30".to_owned());
+    Duration::from_secs(grace.parse()
+        .unwrap_or_else(|_| panic!("Env variable RESUME_GRACE_SECS contains non numeric value: {}", grace)))
+}
+
+fn max_rooms() -> usize {
+    let max = std::env::var("MAX_ROOMS").unwrap_or_else(|_| "10000".to_owned());
+    max.parse()
+        .unwrap_or_else(|_| panic!("Env variable MAX_ROOMS contains non numeric value: {}", max))
+}
+
+fn history_size() -> usize {
+    let size = std::env::var("HISTORY_SIZE").unwrap_or_else(|_| "100".to_owned());
+    size.parse()
+        .unwrap_or_else(|_| panic!("Env variable HISTORY_SIZE contains non numeric value: {}", size))
+}
+
+fn max_message_length() -> usize {
+    let max = std::env::var("MAX_MESSAGE_LENGTH").unwrap_or_else(|_| "2000".to_owned());
+    max.parse()
+        .unwrap_or_else(|_| panic!("Env variable MAX_MESSAGE_LENGTH contains non numeric value: {}", max))
+}
+
+fn plugin_limits() -> Limits {
+    let fuel = std::env::var("PLUGIN_FUEL").unwrap_or_else(|_| "10000000".to_owned());
+    let memory = std::env::var("PLUGIN_MEMORY_MB").unwrap_or_else(|_| "16".to_owned());
+    Limits {
+        fuel: fuel.parse()
+            .unwrap_or_else(|_| panic!("Env variable PLUGIN_FUEL contains non numeric value: {}", fuel)),
+        memory_bytes: memory.parse::<usize>()
+            .unwrap_or_else(|_| panic!("Env variable PLUGIN_MEMORY_MB contains non numeric value: {}", memory)) << 20,
+    }
+}
+
+fn shutdown_delay() -> Duration {
+    let delay = std::env::var("SHUTDOWN_DELAY_SECS").unwrap_or_else(|_| "0".to_owned());
+    Duration::from_secs(delay.parse()
+        .unwrap_or_else(|_| panic!("Env variable SHUTDOWN_DELAY_SECS contains non numeric value: {}", delay)))
+}
+
+fn ui_static_assets() -> String {
+    let static_assets = std::env::var("STATIC_ASSETS")
+        .expect("Missing env variable STATIC_ASSETS containing path to UI files");
+    let index_html = Path::new(&static_assets).join("index.html");
+    if !index_html.exists() || !index_html.is_file() {
+        panic!("Env variable STATIC_ASSETS does not point to an existing directory with index.html: {}", static_assets);
+    }
+    static_assets
+}
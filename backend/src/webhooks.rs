@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use protocol::ServerEvent;
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::Chat;
+
+/// How many deliveries the log keeps, the oldest go first.
+const LOG_SIZE: usize = 100;
+/// Receivers are expected to answer right away and do their work later.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which messages a webhook is called for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// The word appears in the text, in any case.
+    Keyword(String),
+    /// Every message in the room.
+    Room(String),
+    /// The text contains `@name`.
+    Mention(String),
+}
+
+pub struct Webhook {
+    pub trigger: Trigger,
+    pub url: String,
+    /// Signs the payload, so that the receiver knows it comes from us
+    pub secret: String,
+}
+
+pub struct WebhooksConfig {
+    pub webhooks: Vec<Webhook>,
+    /// Including the first one
+    pub attempts: u32,
+    /// Before the first retry, doubled for every retry after it
+    pub backoff: Duration,
+}
+
+impl WebhooksConfig {
+    /// The webhooks in WEBHOOKS as `<trigger> <url> <secret>` separated by commas, where
+    /// the trigger is one of `keyword:<word>`, `room:<room>` and `mention:<name>`.
+    pub fn from_env() -> Option<WebhooksConfig> {
+        let webhooks = std::env::var("WEBHOOKS").ok()?
+            .split(',')
+            .map(str::trim)
+            .filter(|webhook| !webhook.is_empty())
+            .map(|webhook| Webhook::parse(webhook)
+                .unwrap_or_else(|e| panic!("Env variable WEBHOOKS contains {}: {}", e, webhook)))
+            .collect();
+        let attempts = std::env::var("WEBHOOK_ATTEMPTS").unwrap_or_else(|_| "5".to_owned());
+        let attempts = attempts.parse().ok().filter(|&attempts| attempts > 0)
+            .unwrap_or_else(|| panic!("Env variable WEBHOOK_ATTEMPTS contains no positive number: {}", attempts));
+        let backoff = std::env::var("WEBHOOK_BACKOFF_MS").unwrap_or_else(|_| "1000".to_owned());
+        let backoff = backoff.parse().map(Duration::from_millis)
+            .unwrap_or_else(|_| panic!("Env variable WEBHOOK_BACKOFF_MS contains non numeric value: {}", backoff));
+        Some(WebhooksConfig { webhooks, attempts, backoff })
+    }
+}
+
+impl Webhook {
+    fn parse(webhook: &str) -> Result<Webhook, &'static str> {
+        let mut fields = webhook.split_whitespace();
+        let (trigger, url, secret) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(trigger), Some(url), Some(secret), None) => (trigger, url, secret),
+            _ => return Err("a webhook without trigger, url and secret"),
+        };
+        let trigger = match trigger.split_once(':') {
+            Some(("keyword", word)) if !word.is_empty() => Trigger::Keyword(word.to_lowercase()),
+            Some(("room", room)) if !room.is_empty() => Trigger::Room(room.to_owned()),
+            Some(("mention", name)) if !name.is_empty() => Trigger::Mention(name.to_owned()),
+            _ => return Err("an unknown trigger"),
+        };
+        if url::Url::parse(url).is_err() {
+            return Err("an invalid url");
+        }
+        Ok(Webhook { trigger, url: url.to_owned(), secret: secret.to_owned() })
+    }
+}
+
+impl Trigger {
+    fn matches(&self, room: &str, text: &str) -> bool {
+        match self {
+            Trigger::Keyword(word) => text.split(|c: char| !c.is_alphanumeric())
+                .any(|candidate| candidate.to_lowercase() == *word),
+            Trigger::Room(expected) => room == expected,
+            Trigger::Mention(name) => text.split_whitespace()
+                .filter_map(|word| word.strip_prefix('@'))
+                .any(|mentioned| mentioned.trim_end_matches(|c: char| c.is_ascii_punctuation()) == name),
+        }
+    }
+}
+
+impl std::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trigger::Keyword(word) => write!(f, "keyword:{}", word),
+            Trigger::Room(room) => write!(f, "room:{}", room),
+            Trigger::Mention(name) => write!(f, "mention:{}", name),
+        }
+    }
+}
+
+/// What a webhook receives as JSON, signed in `X-Chat-Signature`.
+#[derive(Serialize)]
+struct Payload<'a> {
+    delivery: usize,
+    trigger: String,
+    room: &'a str,
+    /// Seconds since the epoch, receivers may refuse old payloads
+    timestamp: u64,
+    message: &'a ServerEvent,
+}
+
+/// A call of a webhook for a message as listed by the admin API, updated as it goes.
+#[derive(Serialize, Clone)]
+pub struct Delivery {
+    pub id: usize,
+    pub url: String,
+    pub trigger: String,
+    pub message_id: u64,
+    pub attempts: u32,
+    /// Of the last response
+    pub status: Option<u16>,
+    /// Of the last attempt which got no response
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+/// Calls the webhooks for matching messages and keeps a log of the recent deliveries.
+pub struct Webhooks {
+    config: WebhooksConfig,
+    http: reqwest::Client,
+    next_delivery: AtomicUsize,
+    log: std::sync::Mutex<VecDeque<Delivery>>,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhooksConfig) -> Webhooks {
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap(); // Only fails without a TLS backend, which is compiled in
+        Webhooks { config, http, next_delivery: AtomicUsize::new(1), log: std::sync::Mutex::default() }
+    }
+
+    /// The recent deliveries, the latest last.
+    pub fn log(&self) -> Vec<Delivery> {
+        self.log.lock().unwrap().iter().cloned().collect() // Never held across a panic
+    }
+
+    fn update(&self, delivery: &Delivery) {
+        let mut log = self.log.lock().unwrap(); // Never held across a panic
+        match log.iter_mut().find(|logged| logged.id == delivery.id) {
+            Some(logged) => *logged = delivery.clone(),
+            None => {
+                if log.len() == LOG_SIZE {
+                    log.pop_front();
+                }
+                log.push_back(delivery.clone());
+            }
+        }
+    }
+
+    /// Tries until the receiver takes the payload, gives up on client errors
+    /// other than 408 and 429 as those will not go away.
+    async fn deliver(&self, webhook: &Webhook, room: &str, message: &ServerEvent, message_id: u64) {
+        let id = self.next_delivery.fetch_add(1, Ordering::Relaxed);
+        let trigger = webhook.trigger.to_string();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let payload = Payload { delivery: id, trigger: trigger.clone(), room, timestamp, message };
+        let body = serde_json::to_vec(&payload).unwrap(); // Plain structs always serialize
+        let signature = sign(&webhook.secret, &body);
+
+        let mut delivery = Delivery {
+            id,
+            url: webhook.url.clone(),
+            trigger,
+            message_id,
+            attempts: 0,
+            status: None,
+            error: None,
+            delivered: false,
+        };
+        let mut backoff = self.config.backoff;
+        loop {
+            delivery.attempts += 1;
+            let response = self.http.post(&webhook.url)
+                .header("content-type", "application/json")
+                .header("x-chat-delivery", id.to_string())
+                .header("x-chat-signature", &signature)
+                .body(body.clone())
+                .send().await;
+            let retry = match response {
+                Ok(response) => {
+                    let status = response.status();
+                    delivery.status = Some(status.as_u16());
+                    delivery.error = None;
+                    delivery.delivered = status.is_success();
+                    status.is_server_error() || status.as_u16() == 408 || status.as_u16() == 429
+                }
+                Err(e) => {
+                    delivery.status = None;
+                    delivery.error = Some(e.to_string());
+                    true
+                }
+            };
+            self.update(&delivery);
+
+            if delivery.delivered {
+                info!(attempts = delivery.attempts, "webhook delivered");
+                return;
+            }
+            if !retry || delivery.attempts >= self.config.attempts {
+                warn!(attempts = delivery.attempts, status = ?delivery.status, error = ?delivery.error, "webhook failed");
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+/// `sha256=` and the hex encoded HMAC-SHA256 of the body with the secret as key.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .unwrap(); // HMAC takes keys of any length
+    mac.update(body);
+    let hex: String = mac.finalize().into_bytes().iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+/// Watches the messages of all rooms, every webhook whose trigger matches is called
+/// in a task of its own so that a slow receiver holds up nobody else.
+pub async fn spawn(chat: Chat, webhooks: Arc<Webhooks>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<(String, ServerEvent)>();
+    chat.write().await.watch_messages(tx);
+
+    tokio::task::spawn(async move {
+        while let Some((room, message)) = rx.recv().await {
+            let (message_id, matching): (u64, Vec<usize>) = match &message {
+                ServerEvent::Message { id, text, .. } => (*id, webhooks.config.webhooks.iter()
+                    .enumerate()
+                    .filter(|(_, webhook)| webhook.trigger.matches(&room, text))
+                    .map(|(index, _)| index)
+                    .collect()),
+                _ => continue,
+            };
+            let message = Arc::new(message);
+            for index in matching {
+                let webhook = &webhooks.config.webhooks[index];
+                let span = info_span!("webhook", url = %webhook.url, trigger = %webhook.trigger, message_id);
+                let (webhooks, room, message) = (webhooks.clone(), room.clone(), message.clone());
+                tokio::task::spawn(async move {
+                    let webhook = &webhooks.config.webhooks[index];
+                    webhooks.deliver(webhook, &room, &message, message_id).await;
+                }.instrument(span));
+            }
+        }
+    });
+}
@@ -0,0 +1,112 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+use tracing::debug;
+use warp::http::{HeaderMap, StatusCode};
+use warp::hyper::upgrade::{OnUpgrade, Upgraded};
+use warp::hyper::{header, Body, Request};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::deflate::{DeflateConfig, Deflating, Ratio};
+use crate::metrics::Metrics;
+
+/// A websocket on /chat, compressed if the client and we agreed on it.
+pub type Socket = WebSocketStream<Deflating<Upgraded>>;
+
+/// The upgrade of a request, taken out of its extensions before they reach the filters,
+/// which only get clones of them.
+#[derive(Clone)]
+struct PendingUpgrade(Arc<Mutex<Option<OnUpgrade>>>);
+
+/// Keeps the upgrade of a request which just arrived for `upgrade`.
+pub fn arrived(request: &mut Request<Body>) {
+    if request.headers().contains_key(header::UPGRADE) {
+        let upgrade = warp::hyper::upgrade::on(&mut *request);
+        request.extensions_mut().insert(PendingUpgrade(Arc::new(Mutex::new(Some(upgrade)))));
+    }
+}
+
+/// A websocket handshake, like `warp::ws` but with the extensions the client offered.
+pub fn upgrade() -> impl Filter<Extract=(Upgrade,), Error=Rejection> + Clone {
+    warp::get()
+        .and(warp::header::<String>("connection"))
+        .and_then(|connection: String| async move {
+            if connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(warp::header::exact_ignore_case("upgrade", "websocket"))
+        .and(warp::header::exact("sec-websocket-version", "13"))
+        .and(warp::header::<String>("sec-websocket-key"))
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<PendingUpgrade>())
+        .map(|key: String, headers: HeaderMap, pending: Option<PendingUpgrade>| {
+            let extensions = headers.get_all("sec-websocket-extensions").iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            Upgrade { key, extensions, pending }
+        })
+}
+
+pub struct Upgrade {
+    key: String,
+    /// Offered in `Sec-WebSocket-Extensions`
+    extensions: String,
+    pending: Option<PendingUpgrade>,
+}
+
+impl Upgrade {
+    /// Switches protocols and calls `connected` with the websocket, compressed when
+    /// `deflate` is configured and the client offered it. Answers 426 Upgrade Required if
+    /// the connection can not be taken over.
+    pub fn on_upgrade<F, U>(self, deflate: Option<&DeflateConfig>, metrics: Arc<Metrics>, connected: F) -> Response
+    where
+        F: FnOnce(Socket, Option<Arc<Ratio>>) -> U + Send + 'static,
+        U: Future<Output=()> + Send + 'static,
+    {
+        let agreed = deflate.and_then(|deflate| deflate.negotiate(&self.extensions));
+        let upgrade = self.pending.and_then(|PendingUpgrade(upgrade)| upgrade.lock()
+            .unwrap() // Never held across a panic
+            .take());
+        match upgrade {
+            Some(upgrade) => {
+                tokio::task::spawn(async move {
+                    let upgraded = match upgrade.await {
+                        Ok(upgraded) => upgraded,
+                        Err(e) => {
+                            debug!("websocket upgrade failed: {}", e);
+                            return;
+                        }
+                    };
+                    let ratio = agreed.map(|_| Arc::new(Ratio::new(metrics)));
+                    let io = Deflating::new(upgraded, agreed.zip(ratio.clone()));
+                    connected(WebSocketStream::from_raw_socket(io, Role::Server, None).await, ratio).await;
+                });
+            }
+            None => {
+                debug!("websocket could not be upgraded, there was no upgrade");
+                return StatusCode::UPGRADE_REQUIRED.into_response();
+            }
+        }
+
+        let mut response = StatusCode::SWITCHING_PROTOCOLS.into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::CONNECTION, header::HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, header::HeaderValue::from_static("websocket"));
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, derive_accept_key(self.key.as_bytes()).parse()
+            .unwrap()); // Base64 is a valid header value
+        if let Some(agreed) = agreed {
+            headers.insert(header::SEC_WEBSOCKET_EXTENSIONS, agreed.response().parse()
+                .unwrap()); // Made of tokens and numbers
+        }
+        response
+    }
+}
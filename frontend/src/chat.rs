@@ -1,60 +1,375 @@
-use futures::{SinkExt, StreamExt};
-use futures::channel::mpsc::{self, UnboundedSender};
-use log::{error, warn};
-use reqwasm::websocket::{futures::WebSocket, Message};
+use std::collections::VecDeque;
+
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::LocalBoxFuture;
+use futures::stream::{self, FusedStream, LocalBoxStream, SplitSink};
+use gloo_net::eventsource::futures::EventSource;
+use gloo_net::http::Request;
+use gloo_net::websocket::{futures::WebSocket, Message, WebSocketError};
+use gloo_timers::future::TimeoutFuture;
+use log::{error, info, warn};
+use protocol::{ClientEvent, Encoding, Frame, Polled, ServerEvent, Session, Subprotocol, CLOSE_UNSUPPORTED_PROTOCOL};
 use wasm_bindgen_futures::spawn_local;
 
+use crate::reconnect::{Attempts, EventSink, Outbox, Transport};
+pub use crate::reconnect::ConnectionState;
+
 pub struct Chat {
-    tx: UnboundedSender<String>,
+    tx: UnboundedSender<ClientEvent>,
 }
 
 impl Chat {
-    pub fn new<F>(callback: F) -> Self
-        where F: Fn(String) + 'static
+    pub fn new<F, S>(callback: F, state_callback: S) -> Self
+        where F: Fn(ServerEvent) + 'static,
+              S: Fn(ConnectionState) + 'static
     {
         let ui_url = web_sys::window().map(|w| w.location()).unwrap();
-        let chat_url = format!("ws://{}/chat", ui_url.host().unwrap());
-        let ws = WebSocket::open(&chat_url).expect(&chat_url);
-
-        let (mut ws_tx, mut ws_rx) = ws.split();
-
-        spawn_local(async move {
-            while let Some(msg) = ws_rx.next().await {
-                match msg {
-                    Ok(Message::Text(data)) => {
-                        callback(data);
-                    }
-                    Ok(Message::Bytes(b)) => {
-                        let decoded = std::str::from_utf8(&b);
-                        match decoded {
-                            Ok(val) => callback(val.into()),
-                            Err(e) => warn!("ws: {:?}", e)
-                        }
+        // The query of the page picks the room, e.g. /?room=rust
+        let search = ui_url.search().unwrap();
+        // MessagePack is more compact, /?encoding=json helps when looking at the frames
+        let encoding = web_sys::UrlSearchParams::new_with_str(&search).ok()
+            .and_then(|params| params.get("encoding"))
+            .and_then(|name| name.parse().map_err(|e| warn!("{}", e)).ok())
+            .unwrap_or(Encoding::MessagePack);
+
+        let (in_tx, in_rx) = mpsc::unbounded::<ClientEvent>();
+        spawn_local(Connection::new(ui_url.host().unwrap(), search, encoding).run(in_rx, callback, state_callback));
+
+        Self { tx: in_tx }
+    }
+
+    /// `attachments` are the ids of files uploaded before.
+    pub fn send(&mut self, text: String, attachments: Vec<String>) {
+        let result = self.tx.unbounded_send(ClientEvent::Message { text, attachments });
+        if let Err(e) = result {
+            error!("error sending to channel: {:?}", e);
+        }
+    }
+}
+
+/// What comes over a link.
+enum Received {
+    Event(ServerEvent),
+    /// Where to send our events from now on, an EventSource gets a new one whenever
+    /// it reconnects on its own
+    Session(String),
+    /// The backend refused our subprotocol
+    Outdated,
+}
+
+/// An open connection of any transport, `events` ends once it is lost.
+struct Link {
+    events: LocalBoxStream<'static, Received>,
+    sender: Sender,
+}
+
+enum Sender {
+    WebSocket(SplitSink<WebSocket, Message>, Encoding),
+    /// POST /send/<session>, unknown until the session arrives
+    Http(Option<String>),
+}
+
+impl Sender {
+    /// Returns the event back if the link is gone.
+    async fn send(&mut self, event: ClientEvent) -> Result<(), ClientEvent> {
+        match self {
+            Sender::WebSocket(ws_tx, encoding) => {
+                let message = match encoding.encode(&event)
+                    .unwrap() // Plain structs always serialize
+                {
+                    Frame::Text(text) => Message::Text(text),
+                    Frame::Binary(data) => Message::Bytes(data),
+                };
+                ws_tx.send(message).await
+                    .map_err(|e| {
+                        error!("error sending to socket: {:?}", e);
+                        event
+                    })
+            }
+            Sender::Http(Some(url)) => {
+                let json = match Encoding::Json.encode(&event)
+                    .unwrap() // Plain structs always serialize
+                {
+                    Frame::Text(json) => json,
+                    Frame::Binary(_) => unreachable!("JSON is text"),
+                };
+                let result = Request::post(url)
+                    .header("content-type", "application/json")
+                    .body(json)
+                    .send().await;
+                match result {
+                    Ok(response) if response.ok() => Ok(()),
+                    Ok(response) => {
+                        error!("error sending to {}: {}", url, response.status_text());
+                        Err(event)
                     }
                     Err(e) => {
-                        error!("ws: {:?}", e)
+                        error!("error sending to {}: {:?}", url, e);
+                        Err(event)
                     }
                 }
             }
-        });
+            Sender::Http(None) => Err(event),
+        }
+    }
+}
+
+impl EventSink for Sender {
+    fn send_event(&mut self, event: ClientEvent) -> LocalBoxFuture<'_, Result<(), ClientEvent>> {
+        self.send(event).boxed_local()
+    }
+}
+
+/// Keeps a connection open, resuming our user every time it is lost.
+struct Connection {
+    host: String,
+    /// The query of the page
+    search: String,
+    /// How we send events over the websocket, the backend answers the same way
+    encoding: Encoding,
+    attempts: Attempts,
+    /// Set once the backend refused our subprotocol
+    outdated: bool,
+    uid: Option<usize>,
+    resume_token: Option<String>,
+    last_seen: u64,
+    /// Messages waiting for the connection to come back
+    outbox: Outbox,
+}
+
+impl Connection {
+    fn new(host: String, search: String, encoding: Encoding) -> Self {
+        Self {
+            host,
+            search,
+            encoding,
+            attempts: Attempts::default(),
+            outdated: false,
+            uid: None,
+            resume_token: None,
+            last_seen: 0,
+            outbox: Outbox::default(),
+        }
+    }
+
+    async fn run<F, S>(mut self, mut in_rx: UnboundedReceiver<ClientEvent>, callback: F, state_callback: S)
+        where F: Fn(ServerEvent),
+              S: Fn(ConnectionState)
+    {
+        state_callback(ConnectionState::Connecting);
+        loop {
+            let online = match self.open().await {
+                Ok(link) => self.serve(link, &mut in_rx, &callback, &state_callback).await,
+                Err(e) => {
+                    error!("{:?}: {}", self.attempts.transport, e);
+                    false
+                }
+            };
+            if in_rx.is_terminated() {
+                // The chat is gone, nobody is interested anymore
+                return;
+            }
+            if self.outdated {
+                state_callback(ConnectionState::Outdated);
+                return;
+            }
+
+            state_callback(self.attempts.lost(online));
+            let delay = self.attempts.backoff(js_sys::Math::random());
+            info!("reconnecting in {} ms", delay);
+            TimeoutFuture::new(delay).await;
+        }
+    }
+
+    /// The query of the page, and what we need to resume our user.
+    fn query(&self) -> String {
+        match &self.resume_token {
+            Some(token) => {
+                let separator = if self.search.is_empty() { '?' } else { '&' };
+                format!("{}{}resume={}&last_seen={}", self.search, separator, token, self.last_seen)
+            }
+            None => self.search.clone(),
+        }
+    }
+
+    async fn open(&self) -> Result<Link, String> {
+        match self.attempts.transport {
+            Transport::WebSocket => self.open_websocket(),
+            Transport::EventSource => self.open_event_source(),
+            Transport::LongPoll => self.open_long_poll().await,
+        }
+    }
+
+    fn open_websocket(&self) -> Result<Link, String> {
+        let url = format!("ws://{}/chat{}", self.host, self.query());
+        // The subprotocol names the version of the events we understand
+        let ws = WebSocket::open_with_protocols(&url, &[Subprotocol::current(self.encoding).name()])
+            .map_err(|e| format!("{}: {:?}", url, e))?;
+        let (ws_tx, ws_rx) = ws.split();
 
-        let (in_tx, mut in_rx) = mpsc::unbounded::<String>();
-        spawn_local(async move {
-            while let Some(text) = in_rx.next().await {
-                let result = ws_tx.send(Message::Text(text)).await;
-                if let Err(e) = result {
-                    error!("error sending to socket: {:?}", e);
+        let events = stream::unfold(Some(ws_rx), |ws_rx| async move {
+            let mut ws_rx = ws_rx?;
+            loop {
+                let frame = match ws_rx.next().await? {
+                    Ok(Message::Text(text)) => Frame::Text(text),
+                    Ok(Message::Bytes(data)) => Frame::Binary(data),
+                    Err(WebSocketError::ConnectionClose(e)) if e.code == CLOSE_UNSUPPORTED_PROTOCOL => {
+                        error!("ws: {}", e.reason);
+                        return Some((Received::Outdated, None));
+                    }
+                    Err(e) => {
+                        error!("ws: {:?}", e);
+                        return None;
+                    }
+                };
+                match frame.decode() {
+                    Ok(event) => return Some((Received::Event(event), Some(ws_rx))),
+                    Err(e) => warn!("ws: {}", e),
                 }
             }
         });
+        Ok(Link { events: events.boxed_local(), sender: Sender::WebSocket(ws_tx, self.encoding) })
+    }
 
-        Self { tx: in_tx }
+    fn open_event_source(&self) -> Result<Link, String> {
+        let url = format!("/sse{}", self.query());
+        let mut es = EventSource::new(&url).map_err(|e| format!("{}: {:?}", url, e))?;
+        let mut subscribe = |event_type: &str| es.subscribe(event_type).map_err(|e| format!("{}: {:?}", url, e));
+        let received = stream::select(subscribe("session")?, stream::select(subscribe("message")?, subscribe("close")?));
+
+        // The stream keeps the EventSource open
+        let events = stream::unfold(Some((es, received)), |state| async move {
+            let (es, mut received) = state?;
+            loop {
+                let (event_type, event) = match received.next().await? {
+                    Ok(event) => event,
+                    Err(e) => {
+                        error!("sse: {:?}", e);
+                        return None;
+                    }
+                };
+                let data = event.data().as_string().unwrap_or_default();
+                let received_event = match event_type.as_str() {
+                    "session" => Received::Session(format!("/send/{}", data)),
+                    "close" => {
+                        error!("sse: {}", data);
+                        return None;
+                    }
+                    _ => match Frame::Text(data).decode() {
+                        Ok(event) => Received::Event(event),
+                        Err(e) => {
+                            warn!("sse: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                return Some((received_event, Some((es, received))));
+            }
+        });
+        Ok(Link { events: events.boxed_local(), sender: Sender::Http(None) })
     }
 
-    pub fn send(&mut self, text: String) {
-        let result = self.tx.unbounded_send(text);
-        if let Err(e) = result {
-            error!("error sending to channel: {:?}", e);
+    async fn open_long_poll(&self) -> Result<Link, String> {
+        let url = format!("/poll{}", self.query());
+        let response = Request::post(&url).send().await
+            .map_err(|e| format!("{}: {:?}", url, e))?;
+        if !response.ok() {
+            return Err(format!("{}: {}", url, response.status_text()));
+        }
+        let Session { session } = response.json().await
+            .map_err(|e| format!("{}: {:?}", url, e))?;
+
+        let poll_url = format!("/poll/{}", session);
+        let events = stream::unfold(Some((poll_url, VecDeque::new(), false)), |state| async move {
+            let (poll_url, mut events, mut closed) = state?;
+            loop {
+                if let Some(event) = events.pop_front() {
+                    return Some((Received::Event(event), Some((poll_url, events, closed))));
+                }
+                if closed {
+                    return None;
+                }
+                let response = Request::get(&poll_url).send().await
+                    .map_err(|e| error!("poll: {:?}", e))
+                    .ok()?;
+                if !response.ok() {
+                    error!("poll: {}", response.status_text());
+                    return None;
+                }
+                let polled: Polled = response.json().await
+                    .map_err(|e| error!("poll: {:?}", e))
+                    .ok()?;
+                if let Some(reason) = polled.closed {
+                    error!("poll: {}", reason);
+                    closed = true;
+                }
+                events.extend(polled.events);
+            }
+        });
+        Ok(Link { events: events.boxed_local(), sender: Sender::Http(Some(format!("/send/{}", session))) })
+    }
+
+    /// Relays events until the link is lost, returns whether it was ever online.
+    async fn serve<F, S>(&mut self, link: Link, in_rx: &mut UnboundedReceiver<ClientEvent>, callback: &F, state_callback: &S) -> bool
+        where F: Fn(ServerEvent),
+              S: Fn(ConnectionState)
+    {
+        let Link { events, mut sender } = link;
+        let mut events = events.fuse();
+        let mut online = false;
+
+        loop {
+            select! {
+                received = events.next() => {
+                    let event = match received {
+                        Some(Received::Event(event)) => event,
+                        Some(Received::Session(url)) => {
+                            sender = Sender::Http(Some(url));
+                            continue;
+                        }
+                        Some(Received::Outdated) => {
+                            self.outdated = true;
+                            break;
+                        }
+                        None => break,
+                    };
+
+                    match &event {
+                        ServerEvent::Welcome { uid, resume_token, last_id, .. } => {
+                            if self.uid != Some(*uid) {
+                                // A new user, there is nothing it could have missed
+                                self.uid = Some(*uid);
+                                self.last_seen = *last_id;
+                            }
+                            self.resume_token = Some(resume_token.clone());
+                            online = true;
+                            state_callback(ConnectionState::Online);
+
+                            self.outbox.replay(&mut sender).await;
+                        }
+                        ServerEvent::Message { id, .. } => {
+                            self.last_seen = self.last_seen.max(*id);
+                        }
+                        _ => {}
+                    }
+                    callback(event);
+                }
+                event = in_rx.next() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+                    if !online {
+                        self.outbox.push(event);
+                    } else if let Err(event) = sender.send(event).await {
+                        self.outbox.push(event);
+                        break;
+                    }
+                }
+            }
         }
+
+        online
     }
-}
\ No newline at end of file
+}
@@ -1,19 +1,43 @@
-use web_sys::HtmlInputElement;
+use protocol::{Attachment, ServerEvent, UserInfo};
+use gloo_net::http::Request;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{File, HtmlInputElement};
 use yew::prelude::*;
 
-use chat::Chat;
+use chat::{Chat, ConnectionState};
 
 mod chat;
+mod reconnect;
+
+/// A line of the message list with the files attached to it.
+struct Line {
+    text: String,
+    attachments: Vec<Attachment>,
+}
+
+impl From<String> for Line {
+    fn from(text: String) -> Self {
+        Line { text, attachments: vec![] }
+    }
+}
 
 struct FullStackApp {
     chat: Chat,
-    messages: Vec<String>,
+    messages: Vec<Line>,
+    room: String,
+    topic: Option<String>,
+    users: Vec<UserInfo>,
+    connection: ConnectionState,
     input: NodeRef,
+    file_input: NodeRef,
 }
 
 pub enum Msg {
-    Received(String),
+    Received(ServerEvent),
+    Connection(ConnectionState),
     Send,
+    /// The text to send with the file, once the file is uploaded
+    Uploaded(String, Result<Attachment, String>),
 }
 
 impl Component for FullStackApp {
@@ -22,52 +46,179 @@ impl Component for FullStackApp {
 
     fn create(ctx: &Context<Self>) -> Self {
         let link = ctx.link().clone();
-        let chat = Chat::new(move |s| link.send_message(Msg::Received(s)));
-        Self { chat, messages: vec![], input: NodeRef::default() }
+        let state_link = ctx.link().clone();
+        let chat = Chat::new(
+            move |s| link.send_message(Msg::Received(s)),
+            move |state| state_link.send_message(Msg::Connection(state)));
+        Self {
+            chat,
+            messages: vec![],
+            room: String::new(),
+            topic: None,
+            users: vec![],
+            connection: ConnectionState::Connecting,
+            input: NodeRef::default(),
+            file_input: NodeRef::default(),
+        }
     }
 
-    fn update(&mut self, _: &Context<Self>, msg: Self::Message) -> bool {
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
-            Msg::Received(message) => {
-                self.messages.push(message);
+            Msg::Received(event) => {
+                match event {
+                    ServerEvent::Welcome { room, topic, users, .. } => {
+                        self.room = room;
+                        self.topic = topic;
+                        self.users = users;
+                    }
+                    ServerEvent::Message { name, text, attachments, .. } => {
+                        self.messages.push(Line { text: format!("<{}>: {}", name, text), attachments });
+                    }
+                    ServerEvent::Joined { uid, name } => self.users.push(UserInfo { uid, name }),
+                    ServerEvent::Left { uid, .. } => self.users.retain(|user| user.uid != uid),
+                    ServerEvent::Renamed { uid, name } => {
+                        if let Some(user) = self.users.iter_mut().find(|user| user.uid == uid) {
+                            self.messages.push(format!("* {} is now known as {}", user.name, name).into());
+                            user.name = name;
+                        }
+                    }
+                    ServerEvent::Emote { name, text, .. } => self.messages.push(format!("* {} {}", name, text).into()),
+                    ServerEvent::Topic { name, topic } => {
+                        self.messages.push(format!("* {} set the topic to: {}", name, topic).into());
+                        self.topic = Some(topic);
+                    }
+                    ServerEvent::Notice { text } => self.messages.push(format!("* {}", text).into()),
+                }
+                true
+            }
+            Msg::Connection(state) => {
+                self.connection = state;
                 true
             }
             Msg::Send => {
                 let input = self.input.cast::<HtmlInputElement>();
                 if let Some(input) = input {
                     let message = input.value();
-                    self.messages.push("You: ".to_owned() + &message);
-                    self.chat.send(message);
                     input.set_value("");
+
+                    let file = self.file_input.cast::<HtmlInputElement>()
+                        .and_then(|file_input| {
+                            let file = file_input.files().and_then(|files| files.get(0));
+                            file_input.set_value("");
+                            file
+                        });
+                    if let Some(file) = file {
+                        // Sent once the file is there
+                        let link = ctx.link().clone();
+                        spawn_local(async move {
+                            let uploaded = upload(file).await;
+                            link.send_message(Msg::Uploaded(message, uploaded));
+                        });
+                        return false;
+                    }
+
+                    // The backend answers commands, "//" is the escape for a leading slash
+                    if !message.starts_with('/') {
+                        self.messages.push(("You: ".to_owned() + &message).into());
+                    } else if let Some(text) = message.strip_prefix("//") {
+                        self.messages.push(("You: /".to_owned() + text).into());
+                    }
+                    self.chat.send(message, vec![]);
                 }
                 true
             }
+            Msg::Uploaded(message, Ok(attachment)) => {
+                self.chat.send(message.clone(), vec![attachment.id.clone()]);
+                self.messages.push(Line { text: "You: ".to_owned() + &message, attachments: vec![attachment] });
+                true
+            }
+            Msg::Uploaded(_, Err(e)) => {
+                self.messages.push(format!("* Could not upload: {}", e).into());
+                true
+            }
         }
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
         let send = ctx.link().callback(|_| Msg::Send);
+        let (badge_class, badge_text) = match self.connection {
+            ConnectionState::Connecting => ("badge reconnecting", "connecting"),
+            ConnectionState::Online => ("badge online", "online"),
+            ConnectionState::Reconnecting => ("badge reconnecting", "reconnecting"),
+            ConnectionState::Offline => ("badge offline", "offline"),
+            ConnectionState::Outdated => ("badge offline", "outdated, please reload the page"),
+        };
         html! {
             <div>
                 <h1>{"Rust chat"}</h1>
+                <h2>{"#"}{&self.room}</h2>
+                if let Some(topic) = &self.topic {
+                    <h3>{topic}</h3>
+                }
+                <span class={badge_class}>{badge_text}</span>
+                <ul>
+                    {
+                        self.users.iter()
+                            .map(|user| {
+                                html! {
+                                    <li>{&user.name}</li>
+                                }
+                            })
+                            .collect::<Html>()
+                    }
+                </ul>
                 <div>
                     {
                         self.messages.iter()
                             .map(|message| {
                                 html! {
-                                    <p>{message}</p>
+                                    <p>
+                                        {&message.text}
+                                        { for message.attachments.iter().map(view_attachment) }
+                                    </p>
                                 }
                             })
                             .collect::<Html>()
                     }
                 </div>
                 <input type="text" ref={self.input.clone()}/>
+                <input type="file" ref={self.file_input.clone()}/>
                 <button type="button" onclick={send}>{"Send"}</button>
             </div>
         }
     }
 }
 
+/// Images show as thumbnails, other files as their name, both link to the file.
+fn view_attachment(attachment: &Attachment) -> Html {
+    let url = attachment.url();
+    if attachment.content_type.starts_with("image/") {
+        html! {
+            <a href={url} target="_blank">
+                <img src={attachment.thumbnail_url()} alt={attachment.name.clone()}/>
+            </a>
+        }
+    } else {
+        html! {
+            <a href={url}>{format!(" [{}]", attachment.name)}</a>
+        }
+    }
+}
+
+async fn upload(file: File) -> Result<Attachment, String> {
+    let name = String::from(js_sys::encode_uri_component(&file.name()));
+    let response = Request::post(&format!("/files?name={}", name))
+        .header("content-type", &file.type_())
+        .body(file)
+        .send().await
+        .map_err(|e| e.to_string())?;
+    if !response.ok() {
+        return Err(response.text().await.unwrap_or_else(|_| response.status_text()));
+    }
+    response.json::<Attachment>().await
+        .map_err(|e| e.to_string())
+}
+
 pub fn main() {
     console_log::init()
         .expect("error initializing log");
@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+
+use futures::future::LocalBoxFuture;
+use log::info;
+use protocol::ClientEvent;
+
+/// The first retry waits up to this long, every next one twice as long.
+const INITIAL_BACKOFF_MS: f64 = 500.0;
+const MAX_BACKOFF_MS: f64 = 30_000.0;
+/// After this many failed attempts in a row the chat is shown as offline,
+/// but it still keeps trying.
+const ATTEMPTS_BEFORE_OFFLINE: u32 = 5;
+/// A transport which never got online this many times in a row is given up for the next one.
+const ATTEMPTS_BEFORE_FALLBACK: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The first attempt is still under way
+    Connecting,
+    Online,
+    Reconnecting,
+    Offline,
+    /// The backend does not speak our protocol anymore, only loading the page again helps.
+    Outdated,
+}
+
+/// How we talk to the backend, in the order they are tried. Some proxies break websockets,
+/// the others get through plain HTTP requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    WebSocket,
+    /// Server-Sent Events from GET /sse, our events go to POST /send/<session>
+    EventSource,
+    /// GET /poll/<session> again and again
+    LongPoll,
+}
+
+impl Transport {
+    fn fallback(self) -> Option<Transport> {
+        match self {
+            Transport::WebSocket => Some(Transport::EventSource),
+            Transport::EventSource => Some(Transport::LongPoll),
+            Transport::LongPoll => None,
+        }
+    }
+}
+
+/// Counts the attempts to connect, to tell how long to wait before the next one and
+/// which transport to take.
+pub struct Attempts {
+    pub transport: Transport,
+    failed: u32,
+    /// Attempts of the current transport which never got online
+    never_online: u32,
+}
+
+impl Default for Attempts {
+    fn default() -> Self {
+        Self { transport: Transport::WebSocket, failed: 0, never_online: 0 }
+    }
+}
+
+impl Attempts {
+    /// Records that a connection was lost, or never opened, and returns the state
+    /// to show until the next attempt.
+    pub fn lost(&mut self, online: bool) -> ConnectionState {
+        if online {
+            self.failed = 0;
+            self.never_online = 0;
+        } else {
+            self.never_online += 1;
+        }
+        if self.never_online >= ATTEMPTS_BEFORE_FALLBACK {
+            if let Some(fallback) = self.transport.fallback() {
+                info!("falling back from {:?} to {:?}", self.transport, fallback);
+                self.transport = fallback;
+                self.never_online = 0;
+            }
+        }
+
+        self.failed += 1;
+        if self.failed < ATTEMPTS_BEFORE_OFFLINE {
+            ConnectionState::Reconnecting
+        } else {
+            ConnectionState::Offline
+        }
+    }
+
+    /// Exponential backoff with jitter, so that clients of a restarted backend
+    /// do not all come back at the same moment. `random` is from 0 to 1.
+    pub fn backoff(&self, random: f64) -> u32 {
+        let max = (INITIAL_BACKOFF_MS * 2f64.powi(self.failed as i32 - 1)).min(MAX_BACKOFF_MS);
+        (max * (0.5 + random / 2.0)) as u32
+    }
+}
+
+/// Where our events go while a link is open.
+pub trait EventSink {
+    /// Gives the event back if the link is gone.
+    fn send_event(&mut self, event: ClientEvent) -> LocalBoxFuture<'_, Result<(), ClientEvent>>;
+}
+
+/// Messages waiting for the connection to come back, in the order they were written.
+#[derive(Default)]
+pub struct Outbox {
+    events: VecDeque<ClientEvent>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, event: ClientEvent) {
+        self.events.push_back(event);
+    }
+
+    /// Sends the waiting messages in order, until `sink` gives one back because the
+    /// link is gone. That one is sent first next time.
+    pub async fn replay(&mut self, sink: &mut impl EventSink) {
+        while let Some(event) = self.events.pop_front() {
+            if let Err(event) = sink.send_event(event).await {
+                self.events.push_front(event);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::FutureExt;
+
+    use super::*;
+
+    /// Takes up to `capacity` events, then acts like the link is gone.
+    struct Sink {
+        sent: Vec<ClientEvent>,
+        capacity: usize,
+    }
+
+    impl EventSink for Sink {
+        fn send_event(&mut self, event: ClientEvent) -> LocalBoxFuture<'_, Result<(), ClientEvent>> {
+            let result = if self.sent.len() < self.capacity {
+                self.sent.push(event);
+                Ok(())
+            } else {
+                Err(event)
+            };
+            async move { result }.boxed_local()
+        }
+    }
+
+    fn message(text: &str) -> ClientEvent {
+        ClientEvent::Message { text: text.to_owned(), attachments: vec![] }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut attempts = Attempts::default();
+        let mut delays = vec![];
+        for _ in 0..8 {
+            attempts.lost(true);
+            delays.push((attempts.backoff(0.0), attempts.backoff(1.0)));
+        }
+        // Every connection got online, so the backoff starts over each time
+        assert!(delays.iter().all(|&delay| delay == (250, 500)), "{:?}", delays);
+
+        let mut attempts = Attempts::default();
+        let delays = (0..8)
+            .map(|_| {
+                attempts.lost(false);
+                (attempts.backoff(0.0), attempts.backoff(1.0))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [
+            (250, 500), (500, 1000), (1000, 2000), (2000, 4000),
+            (4000, 8000), (8000, 16000), (15000, 30000), (15000, 30000),
+        ]);
+    }
+
+    #[test]
+    fn failed_attempts_end_offline_and_fall_back() {
+        let mut attempts = Attempts::default();
+        let mut states = vec![];
+        let mut transports = vec![];
+        for _ in 0..6 {
+            states.push(attempts.lost(false));
+            transports.push(attempts.transport);
+        }
+        use ConnectionState::*;
+        assert_eq!(states, [Reconnecting, Reconnecting, Reconnecting, Reconnecting, Offline, Offline]);
+        use Transport::*;
+        assert_eq!(transports, [WebSocket, EventSource, EventSource, LongPoll, LongPoll, LongPoll]);
+
+        // Once online, the backoff starts over with the same transport
+        assert_eq!(attempts.lost(true), Reconnecting);
+        assert_eq!(attempts.transport, LongPoll);
+    }
+
+    #[test]
+    fn outbox_replays_in_order_and_keeps_what_was_not_sent() {
+        let mut outbox = Outbox::default();
+        for text in ["one", "two", "three"] {
+            outbox.push(message(text));
+        }
+
+        let mut sink = Sink { sent: vec![], capacity: 1 };
+        block_on(outbox.replay(&mut sink));
+        assert_eq!(sink.sent, [message("one")]);
+
+        outbox.push(message("four"));
+        sink.capacity = 10;
+        block_on(outbox.replay(&mut sink));
+        assert_eq!(sink.sent, [message("one"), message("two"), message("three"), message("four")]);
+
+        // Nothing is sent twice
+        block_on(outbox.replay(&mut sink));
+        assert_eq!(sink.sent.len(), 4);
+    }
+}
@@ -0,0 +1,368 @@
+//! Opens many websocket connections to a chat backend, sends messages at a steady rate
+//! and reports how long they took to arrive and how many never did.
+//!
+//! With churn, it sends for the same duration twice, first without and then with
+//! connections coming and going, and compares the two. Closed connections only leave
+//! once the resume grace of the backend is over, so it should have none for that.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use protocol::{ClientEvent, Encoding, Frame, ServerEvent, Subprotocol};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+/// Every message of ours starts with this, followed by its number and when it was sent.
+const PREFIX: &str = "loadgen";
+
+struct Config {
+    url: Url,
+    users: usize,
+    /// Users are spread evenly over this many rooms
+    rooms: usize,
+    /// Messages per second of all users together
+    rate: f64,
+    duration: Duration,
+    /// How long to wait for messages still under way after the last one was sent
+    drain: Duration,
+    /// Characters per message
+    message_size: usize,
+    /// Connections per second opened and closed again while messages are sent
+    churn: f64,
+    encoding: Encoding,
+}
+
+impl Config {
+    fn from_env() -> Config {
+        let url = std::env::var("CHAT_URL")
+            .expect("Missing env variable CHAT_URL containing the websocket url of /chat, e.g. ws://127.0.0.1:8080/chat");
+        let url = Url::parse(&url)
+            .unwrap_or_else(|e| panic!("Env variable CHAT_URL contains an invalid url {}: {}", url, e));
+        let encoding = std::env::var("ENCODING").unwrap_or_else(|_| "json".to_owned());
+        let encoding = encoding.parse()
+            .unwrap_or_else(|_| panic!("Env variable ENCODING contains unknown encoding: {}", encoding));
+        let config = Config {
+            url,
+            users: number("USERS", "100"),
+            rooms: number("ROOMS", "1"),
+            rate: number("RATE", "50"),
+            duration: Duration::from_secs(number("DURATION_SECS", "10")),
+            drain: Duration::from_secs(number("DRAIN_SECS", "2")),
+            message_size: number("MESSAGE_SIZE", "64"),
+            churn: number("CHURN", "0"),
+            encoding,
+        };
+        if config.users == 0 || config.rooms == 0 || config.rate <= 0.0 {
+            panic!("Env variables USERS, ROOMS and RATE must be positive");
+        }
+        if config.churn < 0.0 {
+            panic!("Env variable CHURN must not be negative");
+        }
+        config
+    }
+}
+
+fn number<T: std::str::FromStr>(name: &str, default: &str) -> T {
+    let value = std::env::var(name).unwrap_or_else(|_| default.to_owned());
+    value.parse()
+        .unwrap_or_else(|_| panic!("Env variable {} contains non numeric value: {}", name, value))
+}
+
+/// A connected user, it sends the texts given to `tx`.
+struct User {
+    room: usize,
+    tx: mpsc::UnboundedSender<String>,
+    /// Completes once the connection is closed
+    received: JoinHandle<Received>,
+}
+
+/// What a user got from others.
+#[derive(Default)]
+struct Received {
+    /// Numbers and latencies of the messages
+    latencies: Vec<(u64, Duration)>,
+    /// Ids of the users who left, and when after `start`
+    left: Vec<(usize, Duration)>,
+}
+
+/// What was sent in one go, messages numbered from `first` on.
+struct Phase {
+    /// After `start`
+    began: Duration,
+    first: u64,
+    sent: u64,
+    /// Deliveries of the messages, everybody in the room but the sender
+    expected: u64,
+    send_time: Duration,
+    churned: Option<Churned>,
+}
+
+struct Churned {
+    /// Connections opened and closed again
+    opened: u64,
+    failed: u64,
+    /// Users who left while messages were sent, those of closed connections
+    left: usize,
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Config::from_env();
+    let start = Instant::now();
+
+    println!("connecting {} users to {} in {} room(s)", config.users, config.url, config.rooms);
+    let connecting: Vec<_> = (0..config.users)
+        .map(|index| {
+            let room = index % config.rooms;
+            let url = config.url.clone();
+            tokio::task::spawn(async move { connect(url, room, config.encoding, start).await })
+        })
+        .collect();
+    let mut users = vec![];
+    let mut failures = vec![];
+    for connecting in connecting {
+        match connecting.await {
+            Ok(Ok(user)) => users.push(user),
+            Ok(Err(error)) => failures.push(error),
+            Err(panicked) => failures.push(panicked.to_string()),
+        }
+    }
+    let connect_time = start.elapsed();
+    if users.is_empty() {
+        panic!("No user could connect: {}", failures[0]);
+    }
+    let mut room_sizes = vec![0u64; config.rooms];
+    for user in users.iter() {
+        room_sizes[user.room] += 1;
+    }
+
+    let mut phases = vec![];
+    if config.churn > 0.0 {
+        phases.push(send(&config, &users, &room_sizes, 0, false, start).await);
+        // Messages still under way would be counted against the churn
+        tokio::time::sleep(config.drain).await;
+    }
+    let first = phases.last().map_or(0, |phase: &Phase| phase.first + phase.sent);
+    phases.push(send(&config, &users, &room_sizes, first, config.churn > 0.0, start).await);
+
+    // Users leave once everything sent had time to arrive
+    tokio::time::sleep(config.drain).await;
+    let received: Vec<_> = users.into_iter().map(|user| user.received).collect();
+    let (mut latencies, mut left) = (vec![], vec![]);
+    for received in received {
+        let received = received.await.unwrap_or_default();
+        latencies.extend(received.latencies);
+        left.extend(received.left);
+    }
+    for phase in phases.iter_mut() {
+        let sending = phase.began..phase.began + phase.send_time;
+        if let Some(churned) = &mut phase.churned {
+            churned.left = left.iter()
+                .filter(|(_, at)| sending.contains(at))
+                .map(|(uid, _)| uid)
+                .collect::<HashSet<_>>()
+                .len();
+        }
+    }
+
+    println!();
+    println!("users        {} connected in {:.2?}, {} failed", room_sizes.iter().sum::<u64>(), connect_time, failures.len());
+    if let Some(first) = failures.first() {
+        println!("             first failure: {}", first);
+    }
+    let mut p99s = vec![];
+    for phase in phases.iter() {
+        let mut latencies: Vec<_> = latencies.iter()
+            .filter(|(number, _)| (phase.first..phase.first + phase.sent).contains(number))
+            .map(|(_, latency)| *latency)
+            .collect();
+        latencies.sort();
+        if phases.len() > 1 {
+            println!("{}", if phase.churned.is_some() { "with churn" } else { "without churn" });
+        }
+        report(phase, &latencies);
+        if !latencies.is_empty() {
+            p99s.push(percentile(&latencies, 0.99));
+        }
+    }
+    if let [without, with] = p99s[..] {
+        println!("p99          {:.2?} without churn, {:.2?} with churn, {:.2} times as long",
+                 without, with, with.as_secs_f64() / without.as_secs_f64());
+    }
+}
+
+/// Sends messages at the configured rate for the configured duration, numbered from `first`
+/// on. With `churn`, connections come and go meanwhile.
+async fn send(config: &Config, users: &[User], room_sizes: &[u64], first: u64, churn: bool, start: Instant) -> Phase {
+    let sending = Instant::now();
+    let began = start.elapsed();
+    let churning = churn.then(|| {
+        let (url, rooms, churn_rate, encoding) = (config.url.clone(), config.rooms, config.churn, config.encoding);
+        tokio::task::spawn(self::churn(url, rooms, churn_rate, encoding, sending + config.duration, start))
+    });
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / config.rate));
+    let mut sent = 0u64;
+    let mut expected = 0u64;
+    while sending.elapsed() < config.duration {
+        ticker.tick().await;
+        let user = &users[sent as usize % users.len()];
+        let mut text = format!("{} {} {} ", PREFIX, first + sent, start.elapsed().as_micros());
+        let padding = config.message_size.saturating_sub(text.len());
+        text.push_str(&".".repeat(padding));
+        if user.tx.send(text).is_ok() {
+            sent += 1;
+            // Everybody in the room but the sender
+            expected += room_sizes[user.room] - 1;
+        }
+    }
+    let send_time = sending.elapsed();
+    let churned = match churning {
+        Some(churning) => {
+            let (opened, failed) = churning.await.unwrap_or_default();
+            Some(Churned { opened, failed, left: 0 })
+        }
+        None => None,
+    };
+    Phase { began, first, sent, expected, send_time, churned }
+}
+
+/// Prints what was sent and delivered, `latencies` are those of the phase, sorted.
+fn report(phase: &Phase, latencies: &[Duration]) {
+    if let Some(Churned { opened, failed, left }) = phase.churned {
+        println!("churn        {} connections opened and closed in {:.2?}, {} failed, {} left meanwhile",
+                 opened, phase.send_time, failed, left);
+        if left == 0 && opened > 0 {
+            println!("             none left while sending, start the backend with RESUME_GRACE_SECS=0 for that");
+        }
+    }
+    println!("sent         {} messages in {:.2?}, {:.1} per second",
+             phase.sent, phase.send_time, phase.sent as f64 / phase.send_time.as_secs_f64());
+    let received = latencies.len() as u64;
+    let lost = phase.expected.saturating_sub(received);
+    let loss = if phase.expected == 0 { 0.0 } else { lost as f64 * 100.0 / phase.expected as f64 };
+    println!("delivered    {} of {} expected, {} lost ({:.2}%)", received, phase.expected, lost, loss);
+    if !latencies.is_empty() {
+        println!("latency      p50 {:.2?}  p90 {:.2?}  p99 {:.2?}  max {:.2?}",
+                 percentile(latencies, 0.5), percentile(latencies, 0.9), percentile(latencies, 0.99),
+                 latencies[latencies.len() - 1]);
+    }
+}
+
+/// Of sorted latencies, by nearest rank.
+fn percentile(sorted: &[Duration], quantile: f64) -> Duration {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Keeps joining `rate` new users per second until `until`, each leaving again right
+/// after its welcome. Returns how many came and went, and how many failed to connect.
+async fn churn(url: Url, rooms: usize, rate: f64, encoding: Encoding, until: Instant, start: Instant) -> (u64, u64) {
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+    let mut connections = vec![];
+    while Instant::now() < until {
+        ticker.tick().await;
+        let (url, room) = (url.clone(), connections.len() % rooms);
+        connections.push(tokio::task::spawn(async move {
+            let user = connect(url, room, encoding, start).await?;
+            drop(user.tx);
+            // Ends once the backend has answered the close
+            let _ = user.received.await;
+            Ok::<_, String>(())
+        }));
+    }
+    let (mut opened, mut failed) = (0, 0);
+    for connection in connections {
+        match connection.await {
+            Ok(Ok(())) => opened += 1,
+            _ => failed += 1,
+        }
+    }
+    (opened, failed)
+}
+
+/// Joins as a new user, which closes its connection once its `tx` is dropped.
+async fn connect(url: Url, room: usize, encoding: Encoding, start: Instant) -> Result<User, String> {
+    let mut url = url;
+    url.query_pairs_mut().append_pair("room", &format!("{}-{}", PREFIX, room));
+    let mut request = url.as_str().into_client_request().map_err(|e| e.to_string())?;
+    let protocol = Subprotocol::current(encoding).name();
+    request.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_str(&protocol).map_err(|e| e.to_string())?);
+    let (socket, _) = tokio_tungstenite::connect_async(request).await
+        .map_err(|e| format!("Could not connect: {}", e))?;
+    let (mut ws_tx, mut ws_rx) = socket.split();
+
+    // Nothing can be sent before the welcome
+    match ws_rx.next().await {
+        Some(Ok(message)) => match decode(message, encoding) {
+            Some(ServerEvent::Welcome { .. }) => {}
+            event => return Err(format!("Expected welcome, but got {:?}", event)),
+        },
+        Some(Err(e)) => return Err(format!("Could not receive welcome: {}", e)),
+        None => return Err("Closed before welcome".to_owned()),
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    tokio::task::spawn(async move {
+        while let Some(text) = rx.recv().await {
+            let event = ClientEvent::Message { text, attachments: vec![] };
+            let message = match encoding.encode(&event) {
+                Ok(Frame::Text(text)) => Message::Text(text),
+                Ok(Frame::Binary(data)) => Message::Binary(data),
+                Err(_) => continue,
+            };
+            if ws_tx.send(message).await.is_err() {
+                return;
+            }
+        }
+        // The backend answers the close, which ends the stream of the receiver
+        let _ = ws_tx.send(Message::Close(None)).await;
+    });
+
+    let received = tokio::task::spawn(async move {
+        let mut received = Received::default();
+        while let Some(Ok(message)) = ws_rx.next().await {
+            match decode(message, encoding) {
+                Some(ServerEvent::Message { text, .. }) => {
+                    if let Some((number, sent)) = sent_at(&text) {
+                        received.latencies.push((number, start.elapsed().saturating_sub(sent)));
+                    }
+                }
+                Some(ServerEvent::Left { uid, .. }) => received.left.push((uid, start.elapsed())),
+                _ => {}
+            }
+        }
+        received
+    });
+
+    Ok(User { room, tx, received })
+}
+
+/// The number of a message of ours, and when it was sent after `start`.
+fn sent_at(text: &str) -> Option<(u64, Duration)> {
+    let mut words = text.split(' ');
+    if words.next() != Some(PREFIX) {
+        return None;
+    }
+    let number = words.next()?.parse().ok()?;
+    let sent = words.next()?.parse().ok().map(Duration::from_micros)?;
+    Some((number, sent))
+}
+
+fn decode(message: Message, encoding: Encoding) -> Option<ServerEvent> {
+    let frame = match message {
+        Message::Text(text) => Frame::Text(text),
+        Message::Binary(data) => Frame::Binary(data),
+        _ => return None,
+    };
+    // The backend answers in the encoding we asked for
+    if frame.encoding() != encoding {
+        return None;
+    }
+    frame.decode().ok()
+}
@@ -0,0 +1,247 @@
+//! Events exchanged between the chat backend and its clients over the websocket,
+//! every event is sent in a frame of its own, see `Encoding`.
+
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// The room of clients which do not ask for one.
+pub const DEFAULT_ROOM: &str = "general";
+
+/// The version of the events below, raised with every change older clients cannot cope with.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The backend closes connections which offer no subprotocol it supports with this code.
+pub const CLOSE_UNSUPPORTED_PROTOCOL: u16 = 4001;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserInfo {
+    pub uid: usize,
+    pub name: String,
+}
+
+/// An uploaded file, the backend serves it on `/files/<id>`, and a thumbnail of images
+/// on `/files/<id>/thumbnail`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Attachment {
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+}
+
+impl Attachment {
+    pub fn url(&self) -> String {
+        format!("/files/{}", self.id)
+    }
+
+    pub fn thumbnail_url(&self) -> String {
+        format!("/files/{}/thumbnail", self.id)
+    }
+}
+
+/// Events sent by the backend.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// The first event on every connection.
+    ///
+    /// `resume_token` lets a client which lost the connection come back as the same user,
+    /// see `ClientParams`, and `last_id` is the id of the last message sent before it joined.
+    /// `users` are the users in `room`, all other events are about this room only.
+    Welcome {
+        uid: usize,
+        name: String,
+        room: String,
+        topic: Option<String>,
+        resume_token: String,
+        last_id: u64,
+        users: Vec<UserInfo>,
+    },
+    Message {
+        id: u64,
+        uid: usize,
+        name: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<Attachment>,
+    },
+    Joined {
+        uid: usize,
+        name: String,
+    },
+    Left {
+        uid: usize,
+        name: String,
+    },
+    /// A user changed its name, sent to this user too.
+    Renamed {
+        uid: usize,
+        name: String,
+    },
+    /// An action like "/me waves", sent to its user too.
+    Emote {
+        uid: usize,
+        name: String,
+        text: String,
+    },
+    /// `name` set the topic of the room, sent to this user too.
+    Topic {
+        name: String,
+        topic: String,
+    },
+    /// A note from the chat itself for this client only, e.g. why its message was rejected.
+    Notice {
+        text: String,
+    },
+}
+
+/// Events sent by clients.
+///
+/// The text of a message may be a command like "/nick Alice", see "/help",
+/// a leading "//" sends the text with a single slash. `attachments` are the ids of
+/// files uploaded to POST /files before, a message with attachments is never a command.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    Message {
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<String>,
+    },
+}
+
+/// Query parameters of the websocket url.
+///
+/// A client which reconnects within the grace period passes the `resume_token` of its
+/// last `Welcome` and the id of the last message it has seen, it gets the same user back
+/// and the messages it missed. A new user joins `room`, or `DEFAULT_ROOM` without one,
+/// a resumed user stays in its room. `encoding` is how the backend sends events to
+/// this connection, JSON without one.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientParams {
+    pub room: Option<String>,
+    pub resume: Option<String>,
+    pub last_seen: Option<u64>,
+    pub encoding: Option<Encoding>,
+}
+
+/// The answer to `POST /poll`, which opens a long polling connection. The session names
+/// the connection in `GET /poll/<session>` and `POST /send/<session>`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub session: String,
+}
+
+/// The answer to `GET /poll/<session>`, the events since the last poll. `closed` tells why
+/// the backend closed the connection, it answers no more polls then.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Polled {
+    pub events: Vec<ServerEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed: Option<String>,
+}
+
+/// How events are put into websocket frames. Either side sends in the encoding the client
+/// asked for, but understands both, the kind of frame tells which one it got.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON in text frames.
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    /// MessagePack in binary frames, with the same field names as the JSON.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, event: &T) -> Result<Frame, String> {
+        match self {
+            Encoding::Json => serde_json::to_string(event)
+                .map(Frame::Text)
+                .map_err(|e| e.to_string()),
+            // Named, so that internally tagged enums and skipped fields work
+            Encoding::MessagePack => rmp_serde::to_vec_named(event)
+                .map(Frame::Binary)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Encoding, String> {
+        match name {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MessagePack),
+            _ => Err(format!("Unknown encoding {}", name)),
+        }
+    }
+}
+
+/// The payload of a websocket frame carrying an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    pub fn encoding(&self) -> Encoding {
+        match self {
+            Frame::Text(_) => Encoding::Json,
+            Frame::Binary(_) => Encoding::MessagePack,
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, String> {
+        match self {
+            Frame::Text(text) => serde_json::from_str(text).map_err(|e| e.to_string()),
+            Frame::Binary(data) => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// A websocket subprotocol like "rust-chat.v1.msgpack", naming the version of the protocol
+/// and the encoding. Clients offer it in `Sec-WebSocket-Protocol`, an encoding given there
+/// wins over the one in `ClientParams`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subprotocol {
+    pub version: u32,
+    pub encoding: Encoding,
+}
+
+impl Subprotocol {
+    /// The subprotocol of this version of the protocol.
+    pub fn current(encoding: Encoding) -> Subprotocol {
+        Subprotocol { version: PROTOCOL_VERSION, encoding }
+    }
+
+    pub fn name(&self) -> String {
+        format!("rust-chat.v{}.{}", self.version, self.encoding.as_str())
+    }
+}
+
+impl FromStr for Subprotocol {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Subprotocol, String> {
+        let parsed = name.strip_prefix("rust-chat.v")
+            .and_then(|rest| rest.split_once('.'))
+            .and_then(|(version, encoding)| Some(Subprotocol {
+                version: version.parse().ok()?,
+                encoding: encoding.parse().ok()?,
+            }));
+        parsed.ok_or_else(|| format!("Unknown subprotocol {}", name))
+    }
+}
@@ -0,0 +1,62 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use serde_json::Value;
+
+mod chat;
+mod process;
+
+const TOKEN: &str = "secret";
+
+#[test]
+fn admin_can_list_kick_and_ban() {
+    let backend = ChatBackend::start(&[("ADMIN_TOKEN", TOKEN)]);
+    let admin = |method: &str, path: &str| {
+        ureq::request(method, backend.url().join(path).unwrap().as_str())
+            .set("authorization", &format!("Bearer {}", TOKEN))
+    };
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    let connections: Value = serde_json::from_str(&admin("GET", "admin/connections").call().unwrap()
+        .into_string().unwrap()).unwrap();
+    let names: Vec<_> = connections.as_array().unwrap().iter()
+        .map(|connection| connection["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, [alice.name(), bob.name()]);
+
+    let bob_connection = connections[1]["connection"].as_u64().unwrap();
+    let kicked = admin("POST", &format!("admin/connections/{}/kick", bob_connection))
+        .send_string(r#"{"reason": "Be nice"}"#).unwrap();
+    assert_eq!(kicked.status(), 204);
+    bob.receives_close("Be nice");
+    alice.receives_left(bob.name());
+
+    let endless = format!(r#"{{"ip": "127.0.0.1", "secs": {}}}"#, u64::MAX);
+    match admin("POST", "admin/bans").send_string(&endless) {
+        Err(ureq::Error::Status(status, _)) => assert_eq!(status, 400),
+        result => panic!("Expected 400, but got {:?}", result),
+    }
+
+    let banned = admin("POST", "admin/bans")
+        .send_string(r#"{"ip": "127.0.0.1", "secs": 60, "reason": "Go away"}"#).unwrap();
+    assert_eq!(banned.status(), 201);
+    alice.receives_close("Go away");
+    match ChatClient::try_connect(&backend.chat_url(), None).map_err(|e| e.downcast::<tungstenite::Error>()) {
+        Err(Ok(tungstenite::Error::Http(response))) => assert_eq!(response.status(), 403),
+        Err(error) => panic!("Unexpected error {:?}", error),
+        Ok(_) => panic!("Connected while banned"),
+    }
+}
+
+#[test]
+fn admin_api_requires_token() {
+    let backend = ChatBackend::start(&[("ADMIN_TOKEN", TOKEN)]);
+
+    let url = backend.url().join("admin/connections").unwrap();
+    match ureq::get(url.as_str()).set("authorization", "Bearer wrong").call() {
+        Err(ureq::Error::Status(status, _)) => assert_eq!(status, 401),
+        result => panic!("Expected 401, but got {:?}", result),
+    }
+}
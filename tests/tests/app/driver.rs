@@ -49,7 +49,7 @@ impl ApplicationDriver {
     }
 
     fn start_application() -> Result<ServerProcess> {
-        ServerProcess::new("application", |port| {
+        ServerProcess::with_readiness("application", "/readyz", |port| {
             let mut cmd = Command::new("../target/debug/backend");
             cmd.env("PORT", port.to_string());
             cmd.env("STATIC_ASSETS", "../frontend/dist");
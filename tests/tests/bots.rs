@@ -0,0 +1,50 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+#[test]
+fn echo_bot_echoes_and_reminds() {
+    let backend = ChatBackend::start(&[("BOTS", "echo")]);
+
+    let mut alice = ChatClient::connect(&backend.room_url("rust"));
+    let alice_name = alice.name().to_owned();
+
+    alice.send("!echo Hello bot");
+    alice.receives_message("EchoBot", "Hello bot");
+
+    alice.send("!remind 1 stretch");
+    alice.receives_message("EchoBot", &format!("{}: stretch", alice_name));
+}
+
+#[test]
+fn echo_bot_limits_reminders() {
+    let backend = ChatBackend::start(&[("BOTS", "echo")]);
+
+    let mut alice = ChatClient::connect(&backend.room_url("rust"));
+    let usage = format!("{}: try !remind <seconds> <text>, with up to 86400 seconds and 5 reminders at a time", alice.name());
+
+    alice.send("!remind 86401 sleep");
+    alice.receives_message("EchoBot", &usage);
+
+    for _ in 0..5 {
+        alice.send("!remind 3600 stretch");
+    }
+    alice.send("!remind 1 one too many");
+    alice.receives_message("EchoBot", &usage);
+
+    // Others still have reminders to spare
+    let mut bob = ChatClient::connect(&backend.room_url("go"));
+    bob.send("!remind 1 stretch");
+    bob.receives_message("EchoBot", &format!("{}: stretch", bob.name()));
+}
+
+#[test]
+fn bot_is_listed_in_every_room() {
+    let backend = ChatBackend::start(&[("BOTS", "echo")]);
+
+    let mut alice = ChatClient::connect(&backend.room_url("rust"));
+    alice.send("/who");
+    alice.receives_notice(&format!("In this room: EchoBot, {}", alice.name()));
+}
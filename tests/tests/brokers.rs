@@ -0,0 +1,92 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::{InProcessBroker, Node, RedisBroker};
+use chat::client::ChatClient;
+use chat::embedded::EmbeddedChat;
+use mock::redis::MockRedis;
+
+mod chat;
+mod mock;
+mod process;
+
+#[test]
+fn backends_chat_through_an_in_process_broker() {
+    let broker = InProcessBroker::default();
+    let backends = start_backends(2, |node| {
+        let broker = broker.clone();
+        EmbeddedChat::start(move |server| server.broker(node, broker))
+    });
+
+    let mut alice = ChatClient::connect(&backends[0].chat_url());
+    let mut bob = ChatClient::connect(&backends[1].chat_url());
+    alice.receives_joined(bob.name());
+    assert_ne!(alice.name(), bob.name());
+
+    alice.send("Hi Bob!");
+    bob.receives_message(alice.name(), "Hi Bob!");
+    bob.send("/nick Bob");
+    bob.receives_renamed("Bob");
+    alice.receives_renamed("Bob");
+    bob.send("Hi Alice!");
+    alice.receives_message("Bob", "Hi Alice!");
+}
+
+#[test]
+fn backends_chat_through_a_redis_channel() {
+    let redis = MockRedis::start();
+    let backends = start_backends(2, |node| {
+        let broker = RedisBroker { url: redis.url(), channel: "rooms".to_owned() };
+        EmbeddedChat::start(move |server| server.broker(node, broker))
+    });
+
+    let mut alice = ChatClient::connect(&backends[0].chat_url());
+    let mut bob = ChatClient::connect(&backends[1].chat_url());
+    alice.receives_joined(bob.name());
+
+    alice.send("Hi Bob!");
+    bob.receives_message(alice.name(), "Hi Bob!");
+    bob.send("Hi Alice!");
+    alice.receives_message(bob.name(), "Hi Alice!");
+    assert!(redis.published("rooms") > 0);
+}
+
+#[test]
+fn redis_replies_beyond_the_limits_are_refused() {
+    let redis = MockRedis::start();
+    let broker = RedisBroker { url: redis.url(), channel: "rooms".to_owned() };
+    let _backend = EmbeddedChat::start(move |server| server.broker(Node { index: 0, count: 1 }, broker));
+    let message = "*3\r\n$7\r\nmessage\r\n$5\r\nrooms\r\n";
+    let hostile = [
+        format!("{}$4000000000\r\n", message),
+        format!("{}$-2\r\n", message),
+        "*4000000000\r\n".to_owned(),
+        "*-2\r\n".to_owned(),
+        format!("+{}\r\n", "long".repeat(100_000)),
+    ];
+
+    // Every one of them loses the subscription, which the backend takes out again
+    for (subscriptions, raw) in hostile.iter().enumerate() {
+        wait_until(|| redis.subscribed("rooms") == subscriptions + 1);
+        redis.push("rooms", raw.as_bytes());
+    }
+    wait_until(|| redis.subscribed("rooms") == hostile.len() + 1);
+}
+
+fn wait_until(done: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < Duration::from_secs(10), "Timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Backends sharing one broker, each given its node.
+fn start_backends(count: usize, start: impl Fn(Node) -> EmbeddedChat) -> Vec<EmbeddedChat> {
+    let backends: Vec<_> = (0..count).map(|index| start(Node { index, count })).collect();
+    // The backends greet each other on the bus before their users arrive
+    for backend in backends.iter() {
+        backend.wait_for_peers(count - 1);
+    }
+    backends
+}
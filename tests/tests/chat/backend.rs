@@ -0,0 +1,77 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::process::server::ServerProcess;
+
+static NEXT_ASSETS_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The backend process with a stub UI, for tests which talk to it without a browser.
+pub struct ChatBackend {
+    application: ServerProcess,
+    static_assets: PathBuf,
+}
+
+impl ChatBackend {
+    pub fn start(envs: &[(&str, &str)]) -> ChatBackend {
+        Self::_start(envs)
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    pub fn url(&self) -> &Url {
+        self.application.get_url()
+    }
+
+    pub fn chat_url(&self) -> Url {
+        let mut url = self.url().join("chat")
+            .unwrap(); // "chat" is a valid relative url
+        url.set_scheme("ws")
+            .unwrap(); // http can be replaced with ws
+        url
+    }
+
+    pub fn room_url(&self, room: &str) -> Url {
+        let mut url = self.chat_url();
+        url.query_pairs_mut().append_pair("room", room);
+        url
+    }
+
+    /// Stops the backend and returns its log.
+    pub fn stop_for_log(&mut self) -> String {
+        String::from_utf8_lossy(&self.application.take_stdout()).into_owned()
+    }
+
+    fn _start(envs: &[(&str, &str)]) -> Result<ChatBackend> {
+        // The backend refuses to start without UI files, the tests do not need real ones
+        let static_assets = env::temp_dir().join(format!("rust-chat-ui-{}-{}",
+            process::id(), NEXT_ASSETS_ID.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&static_assets)
+            .with_context(|| format!("Could not create {:?}", static_assets))?;
+        fs::write(static_assets.join("index.html"), "<!DOCTYPE html><html></html>")
+            .with_context(|| format!("Could not create index.html in {:?}", static_assets))?;
+
+        let application = ServerProcess::with_readiness("application", "/readyz", |port| {
+            let mut cmd = Command::new("../target/debug/backend");
+            cmd.env("PORT", port.to_string());
+            cmd.env("STATIC_ASSETS", &static_assets);
+            cmd.envs(envs.iter().copied());
+            cmd
+        }).context("Could not start application process")?;
+
+        Ok(ChatBackend { application, static_assets })
+    }
+}
+
+impl Drop for ChatBackend {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_dir_all(&self.static_assets) {
+            println!("Could not remove {:?}: {}", self.static_assets, error);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use protocol::{Attachment, ClientEvent, Encoding, Frame, ServerEvent, Subprotocol};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::HeaderValue;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+use url::Url;
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a client needs to come back as the same user.
+pub struct Resume {
+    token: String,
+    last_seen: u64,
+}
+
+impl Resume {
+    /// Resumes as if the user had not seen any message yet.
+    pub fn with_nothing_seen(self) -> Resume {
+        Resume { last_seen: 0, ..self }
+    }
+}
+
+/// A chat user talking to the backend over a plain websocket.
+pub struct ChatClient {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    /// Events go both ways in this encoding only
+    encoding: Encoding,
+    /// Selected by the backend
+    protocol: Option<String>,
+    uid: usize,
+    name: String,
+    resume_token: String,
+    last_seen: u64,
+}
+
+impl ChatClient {
+    pub fn connect(url: &Url) -> ChatClient {
+        Self::try_connect(url, None)
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    pub fn connect_with_cookie(url: &Url, cookie: &str) -> ChatClient {
+        Self::try_connect(url, Some(cookie))
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    pub fn connect_with_encoding(url: &Url, encoding: Encoding) -> ChatClient {
+        let mut url = url.clone();
+        url.query_pairs_mut().append_pair("encoding", encoding.as_str());
+        Self::_connect(&url, None, encoding)
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    /// Offers the subprotocols in `Sec-WebSocket-Protocol`, the one selected by the backend
+    /// picks the encoding.
+    pub fn connect_with_protocols(url: &Url, protocols: &str) -> ChatClient {
+        Self::_connect_with_protocols(url, protocols)
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    pub fn resume(url: &Url, resume: &Resume) -> ChatClient {
+        let mut url = url.clone();
+        url.query_pairs_mut()
+            .append_pair("resume", &resume.token)
+            .append_pair("last_seen", &resume.last_seen.to_string());
+        Self::connect(&url)
+    }
+
+    pub fn try_connect(url: &Url, cookie: Option<&str>) -> Result<ChatClient> {
+        Self::_connect(url, cookie, Encoding::Json)
+    }
+
+    fn _connect(url: &Url, cookie: Option<&str>, encoding: Encoding) -> Result<ChatClient> {
+        let mut request = url.as_str().into_client_request()?;
+        if let Some(cookie) = cookie {
+            request.headers_mut().insert("cookie", HeaderValue::from_str(cookie)?);
+        }
+        Self::welcome(Self::open(request)?, encoding, None)
+    }
+
+    fn _connect_with_protocols(url: &Url, protocols: &str) -> Result<ChatClient> {
+        let mut request = url.as_str().into_client_request()?;
+        request.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_str(protocols)?);
+        let (socket, response) = tungstenite::connect(request)?;
+        Self::set_timeout(&socket)?;
+
+        let protocol = response.headers().get("sec-websocket-protocol")
+            .context("No subprotocol selected")?
+            .to_str()?
+            .to_owned();
+        let encoding = protocol.parse::<Subprotocol>()
+            .map_or(Encoding::Json, |subprotocol| subprotocol.encoding);
+        Self::welcome(socket, encoding, Some(protocol))
+    }
+
+    /// Opens a websocket for tests which expect the backend to refuse it.
+    pub fn open(request: impl IntoClientRequest) -> Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+        let (socket, _) = tungstenite::connect(request)?;
+        Self::set_timeout(&socket)?;
+        Ok(socket)
+    }
+
+    fn set_timeout(socket: &WebSocket<MaybeTlsStream<TcpStream>>) -> Result<()> {
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        }
+        Ok(())
+    }
+
+    fn welcome(mut socket: WebSocket<MaybeTlsStream<TcpStream>>, encoding: Encoding, protocol: Option<String>) -> Result<ChatClient> {
+        match Self::read_event(&mut socket, encoding)? {
+            ServerEvent::Welcome { uid, name, resume_token, last_id, .. } =>
+                Ok(ChatClient { socket, encoding, protocol, uid, name, resume_token, last_seen: last_id }),
+            event => bail!("Expected welcome, but got {:?}", event),
+        }
+    }
+
+    pub fn uid(&self) -> usize {
+        self.uid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Loses the connection without closing it, like a network failure would.
+    pub fn drop_connection(self) -> Resume {
+        Resume { token: self.resume_token, last_seen: self.last_seen }
+    }
+
+    pub fn send(&mut self, text: &str) {
+        self.send_with_attachments(text, &[]);
+    }
+
+    pub fn send_with_attachments(&mut self, text: &str, attachments: &[&str]) {
+        let attachments = attachments.iter().map(|&id| id.to_owned()).collect();
+        let event = ClientEvent::Message { text: text.to_owned(), attachments };
+        let message = match self.encoding.encode(&event).unwrap() {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(data) => Message::Binary(data),
+        };
+        self.socket.write_message(message)
+            .context("Could not send a message")
+            .unwrap();
+    }
+
+    pub fn receives_message(&mut self, expected_name: &str, expected_text: &str) {
+        match self.receive() {
+            ServerEvent::Message { name, text, .. } => {
+                assert_eq!((name.as_str(), text.as_str()), (expected_name, expected_text));
+            }
+            event => panic!("Expected message from {}, but got {:?}", expected_name, event),
+        }
+    }
+
+    /// Messages which may come in any order, like those of different bots.
+    pub fn receives_messages_in_any_order(&mut self, expected: &[(&str, &str)]) {
+        let mut received: Vec<_> = expected.iter()
+            .map(|_| match self.receive() {
+                ServerEvent::Message { name, text, .. } => (name, text),
+                event => panic!("Expected messages {:?}, but got {:?}", expected, event),
+            })
+            .collect();
+        received.sort();
+        let mut expected: Vec<_> = expected.iter().map(|&(name, text)| (name.to_owned(), text.to_owned())).collect();
+        expected.sort();
+        assert_eq!(received, expected);
+    }
+
+    /// Returns the files attached to the message.
+    pub fn receives_message_with_attachments(&mut self, expected_name: &str, expected_text: &str) -> Vec<Attachment> {
+        match self.receive() {
+            ServerEvent::Message { name, text, attachments, .. } => {
+                assert_eq!((name.as_str(), text.as_str()), (expected_name, expected_text));
+                attachments
+            }
+            event => panic!("Expected message from {}, but got {:?}", expected_name, event),
+        }
+    }
+
+    pub fn receives_joined(&mut self, expected_name: &str) {
+        match self.receive() {
+            ServerEvent::Joined { name, .. } => assert_eq!(name, expected_name),
+            event => panic!("Expected {} to join, but got {:?}", expected_name, event),
+        }
+    }
+
+    pub fn receives_left(&mut self, expected_name: &str) {
+        match self.receive() {
+            ServerEvent::Left { name, .. } => assert_eq!(name, expected_name),
+            event => panic!("Expected {} to leave, but got {:?}", expected_name, event),
+        }
+    }
+
+    pub fn receives_notice(&mut self, expected_text: &str) {
+        match self.receive() {
+            ServerEvent::Notice { text } => assert_eq!(text, expected_text),
+            event => panic!("Expected notice {:?}, but got {:?}", expected_text, event),
+        }
+    }
+
+    pub fn receives_renamed(&mut self, expected_name: &str) {
+        match self.receive() {
+            ServerEvent::Renamed { name, .. } => assert_eq!(name, expected_name),
+            event => panic!("Expected rename to {}, but got {:?}", expected_name, event),
+        }
+    }
+
+    pub fn receives_emote(&mut self, expected_name: &str, expected_text: &str) {
+        match self.receive() {
+            ServerEvent::Emote { name, text, .. } => {
+                assert_eq!((name.as_str(), text.as_str()), (expected_name, expected_text));
+            }
+            event => panic!("Expected emote of {}, but got {:?}", expected_name, event),
+        }
+    }
+
+    pub fn receives_topic(&mut self, expected_topic: &str) {
+        match self.receive() {
+            ServerEvent::Topic { topic, .. } => assert_eq!(topic, expected_topic),
+            event => panic!("Expected topic {:?}, but got {:?}", expected_topic, event),
+        }
+    }
+
+    /// A frame as it is, for tests of what the backend does with frames other than events.
+    pub fn send_frame(&mut self, message: Message) {
+        self.socket.write_message(message)
+            .context("Could not send the frame")
+            .unwrap();
+    }
+
+    pub fn receives_pong(&mut self, expected_payload: &[u8]) {
+        match self.socket.read_message().context("Could not receive the pong").unwrap() {
+            Message::Pong(payload) => assert_eq!(payload, expected_payload),
+            message => panic!("Expected pong, but got {:?}", message),
+        }
+    }
+
+    /// Leaves like a browser closing the page.
+    pub fn close(mut self) {
+        self.socket.close(None)
+            .context("Could not close")
+            .unwrap();
+        // Until the backend answers the close
+        while self.socket.read_message().is_ok() {}
+    }
+
+    /// The backend closes the connection, telling why.
+    pub fn receives_close(&mut self, expected_reason: &str) {
+        loop {
+            match self.socket.read_message().context("Could not receive the close").unwrap() {
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Close(Some(frame)) => return assert_eq!(frame.reason, expected_reason),
+                message => panic!("Expected close with {:?}, but got {:?}", expected_reason, message),
+            }
+        }
+    }
+
+    /// The next event, whatever it is.
+    pub fn receive(&mut self) -> ServerEvent {
+        let event = Self::read_event(&mut self.socket, self.encoding)
+            .unwrap();
+        if let ServerEvent::Message { id, .. } = event {
+            self.last_seen = self.last_seen.max(id);
+        }
+        event
+    }
+
+    fn read_event(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, encoding: Encoding) -> Result<ServerEvent> {
+        loop {
+            let frame = match (socket.read_message().context("Could not receive an event")?, encoding) {
+                (Message::Text(text), Encoding::Json) => Frame::Text(text),
+                (Message::Binary(data), Encoding::MessagePack) => Frame::Binary(data),
+                (Message::Ping(_) | Message::Pong(_), _) => continue,
+                (message, _) => bail!("Unexpected message {:?}", message),
+            };
+            return frame.decode()
+                .map_err(anyhow::Error::msg)
+                .with_context(|| format!("Could not parse event {:?}", frame));
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use protocol::{ClientEvent, ServerEvent};
+use url::Url;
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Ends every compressed message, and is left out of it on the wire.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// A chat user on a websocket of its own making, which offers permessage-deflate and
+/// tells which messages came compressed.
+pub struct DeflateClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// `Sec-WebSocket-Extensions` of the response
+    agreed: Option<String>,
+    compressor: Compress,
+    decompressor: Decompress,
+    name: String,
+}
+
+impl DeflateClient {
+    /// Offers `extensions` in `Sec-WebSocket-Extensions`.
+    pub fn connect(url: &Url, extensions: &str) -> DeflateClient {
+        Self::_connect(url, extensions)
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    fn _connect(url: &Url, extensions: &str) -> Result<DeflateClient> {
+        let addr = url.socket_addrs(|| None)?;
+        let writer = TcpStream::connect(&*addr)?;
+        writer.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        write!(&writer, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Extensions: {}\r\n\r\n", url.path(), addr[0], extensions)?;
+
+        let mut reader = BufReader::new(writer.try_clone()?);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        if !status.starts_with("HTTP/1.1 101") {
+            bail!("Expected to switch protocols, but got {:?}", status);
+        }
+        let mut agreed = None;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            match line.trim_end().split_once(':') {
+                Some((name, value)) if name.eq_ignore_ascii_case("sec-websocket-extensions") =>
+                    agreed = Some(value.trim().to_owned()),
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        // Takes the smallest window there is, whatever the backend agreed to
+        let compressor = Compress::new_with_window_bits(Compression::default(), false, 9);
+        let decompressor = Decompress::new_with_window_bits(false, 15);
+        let mut client = DeflateClient { reader, writer, agreed, compressor, decompressor, name: String::new() };
+        match client.receive()?.0 {
+            ServerEvent::Welcome { name, .. } => client.name = name,
+            event => bail!("Expected welcome, but got {:?}", event),
+        }
+        Ok(client)
+    }
+
+    pub fn agreed(&self) -> Option<&str> {
+        self.agreed.as_deref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends compressed if the backend agreed to it.
+    pub fn send(&mut self, text: &str) {
+        let event = ClientEvent::Message { text: text.to_owned(), attachments: vec![] };
+        let json = serde_json::to_vec(&event).unwrap();
+        let (first, payload) = match &self.agreed {
+            Some(agreed) => {
+                if agreed.contains("client_no_context_takeover") {
+                    self.compressor.reset();
+                }
+                (0x80 | 0x40 | 0x1, self.deflate(&json))
+            }
+            None => (0x80 | 0x1, json),
+        };
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        self.writer.write_all(&frame)
+            .context("Could not send a message")
+            .unwrap();
+    }
+
+    /// The next event, and whether it came compressed.
+    pub fn receive_event(&mut self) -> (ServerEvent, bool) {
+        self.receive()
+            .context("Could not receive an event")
+            .unwrap()
+    }
+
+    pub fn receives_joined(&mut self, expected_name: &str) {
+        match self.receive_event().0 {
+            ServerEvent::Joined { name, .. } => assert_eq!(name, expected_name),
+            event => panic!("Expected {} to join, but got {:?}", expected_name, event),
+        }
+    }
+
+    /// Expects the backend to close the connection with `code`, without any event before.
+    pub fn receives_close(&mut self, code: u16) {
+        let (head, payload) = self.frame()
+            .context("Could not receive a close frame")
+            .unwrap();
+        assert_eq!(head[0] & 0x0f, 0x8, "Expected a close frame, but got {:x?}", head);
+        assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), code);
+    }
+
+    fn receive(&mut self) -> Result<(ServerEvent, bool)> {
+        let (head, mut payload) = self.frame()?;
+        if head[0] & 0x0f != 0x1 || head[0] & 0x80 == 0 {
+            bail!("Expected a whole text frame, but got {:x?}", head);
+        }
+        let compressed = head[0] & 0x40 != 0;
+        if compressed {
+            payload = self.inflate(payload)?;
+        }
+        Ok((serde_json::from_slice(&payload)?, compressed))
+    }
+
+    /// The first two bytes and the payload of the next frame.
+    fn frame(&mut self) -> Result<([u8; 2], Vec<u8>)> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head)?;
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        self.reader.read_exact(&mut payload)?;
+        Ok((head, payload))
+    }
+
+    fn deflate(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::with_capacity(message.len() + 64);
+        self.compressor.compress_vec(message, &mut compressed, FlushCompress::Sync).unwrap();
+        compressed.truncate(compressed.len() - TAIL.len());
+        compressed
+    }
+
+    fn inflate(&mut self, mut compressed: Vec<u8>) -> Result<Vec<u8>> {
+        compressed.extend_from_slice(&TAIL);
+        let mut message = Vec::with_capacity(64 << 10);
+        self.decompressor.decompress_vec(&compressed, &mut message, FlushDecompress::Sync)?;
+        Ok(message)
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+use backend::{ChatServer, RunningChat};
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// How long backends sharing a chat may take to find each other.
+const PEERS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The backend running in the test process, which starts much faster than `ChatBackend`.
+/// It has no UI and is shut down when dropped.
+pub struct EmbeddedChat {
+    server: Option<RunningChat>,
+    runtime: Runtime,
+}
+
+impl EmbeddedChat {
+    /// `configure` gets a server on a free port and adds what the test needs.
+    pub fn start(configure: impl FnOnce(ChatServer) -> ChatServer) -> EmbeddedChat {
+        let runtime = tokio::runtime::Runtime::new()
+            // Top level test methods panic on error by design
+            .unwrap();
+        let server = runtime.block_on(configure(ChatServer::new(([127, 0, 0, 1], 0))).start())
+            .unwrap();
+        EmbeddedChat { server: Some(server), runtime }
+    }
+
+    pub fn url(&self) -> Url {
+        let addr = self.server.as_ref().unwrap().addr(); // Only taken on drop
+        Url::parse(&format!("http://{}/", addr))
+            .unwrap() // An address makes a valid url
+    }
+
+    /// Asks the server to stop, it is gone once the shutdown delay is over.
+    pub fn begin_shutdown(&self) {
+        self.server.as_ref().unwrap() // Only taken on drop
+            .shutdown_handle().shutdown();
+    }
+
+    /// Waits until the backend hears from `count` other backends of the chat.
+    pub fn wait_for_peers(&self, count: usize) {
+        let expected = format!("chat_peers {}", count);
+        let start = Instant::now();
+        loop {
+            let metrics = ureq::get(self.url().join("metrics").unwrap().as_str()).call().ok()
+                .and_then(|response| response.into_string().ok())
+                .unwrap_or_default();
+            if metrics.lines().any(|line| line == expected) {
+                return;
+            }
+            assert!(start.elapsed() < PEERS_TIMEOUT, "No {:?} after {:?} in\n{}", expected, PEERS_TIMEOUT, metrics);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn chat_url(&self) -> Url {
+        let addr = self.server.as_ref().unwrap().addr(); // Only taken on drop
+        Url::parse(&format!("ws://{}/chat", addr))
+            .unwrap() // An address makes a valid url
+    }
+}
+
+impl Drop for EmbeddedChat {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.shutdown_handle().shutdown();
+            self.runtime.block_on(server.stopped());
+        }
+    }
+}
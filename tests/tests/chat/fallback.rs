@@ -0,0 +1,129 @@
+use std::io::{BufRead, BufReader, Read};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use protocol::{ClientEvent, Polled, ServerEvent, Session};
+use url::Url;
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A chat user getting events from GET /sse, like an EventSource would.
+pub struct SseClient {
+    url: Url,
+    session: String,
+    stream: BufReader<Box<dyn Read + Send + Sync>>,
+    /// Sent again when reconnecting
+    last_event_id: Option<String>,
+}
+
+impl SseClient {
+    pub fn connect(url: &Url) -> SseClient {
+        Self::_connect(url, None)
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    /// Connects again, telling the id of the last event like an EventSource does.
+    pub fn reconnect(self) -> SseClient {
+        Self::_connect(&self.url, self.last_event_id.as_deref())
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    fn _connect(url: &Url, last_event_id: Option<&str>) -> Result<SseClient> {
+        let agent = ureq::AgentBuilder::new().timeout_read(RECEIVE_TIMEOUT).build();
+        let mut request = agent.get(url.join("sse")?.as_str());
+        if let Some(id) = last_event_id {
+            request = request.set("last-event-id", id);
+        }
+        let response = request.call()?;
+        let mut client = SseClient {
+            url: url.clone(),
+            session: String::new(),
+            stream: BufReader::new(response.into_reader()),
+            last_event_id: last_event_id.map(str::to_owned),
+        };
+        match client.read_event()? {
+            (Some(event), data) if event == "session" => client.session = data,
+            event => bail!("Expected session, but got {:?}", event),
+        }
+        Ok(client)
+    }
+
+    pub fn send(&self, text: &str) {
+        send(&self.url, &self.session, text);
+    }
+
+    pub fn receive(&mut self) -> ServerEvent {
+        let (_, data) = self.read_event()
+            .unwrap();
+        serde_json::from_str(&data)
+            .with_context(|| format!("Could not parse event {}", data))
+            .unwrap()
+    }
+
+    /// Returns the type and data of the next event.
+    fn read_event(&mut self) -> Result<(Option<String>, String)> {
+        let (mut event, mut data) = (None, String::new());
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).context("Could not receive an event")? == 0 {
+                bail!("The stream has ended");
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                if event.is_some() || !data.is_empty() {
+                    return Ok((event, data));
+                }
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => event = Some(value.to_owned()),
+                "data" => data.push_str(value),
+                "id" => self.last_event_id = Some(value.to_owned()),
+                // Comments keep the stream alive
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A chat user polling GET /poll/<session> for events.
+pub struct PollClient {
+    url: Url,
+    session: String,
+}
+
+impl PollClient {
+    pub fn connect(url: &Url) -> PollClient {
+        let response = ureq::post(url.join("poll").unwrap().as_str()).call()
+            .context("Could not open a long polling connection")
+            .unwrap();
+        let Session { session } = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        PollClient { url: url.clone(), session }
+    }
+
+    pub fn send(&self, text: &str) {
+        send(&self.url, &self.session, text);
+    }
+
+    /// Polls once.
+    pub fn poll(&self) -> Polled {
+        let url = self.url.join(&format!("poll/{}", self.session)).unwrap();
+        let response = ureq::get(url.as_str()).call()
+            .context("Could not poll")
+            .unwrap();
+        serde_json::from_str(&response.into_string().unwrap()).unwrap()
+    }
+}
+
+fn send(url: &Url, session: &str, text: &str) {
+    let event = ClientEvent::Message { text: text.to_owned(), attachments: vec![] };
+    ureq::post(url.join(&format!("send/{}", session)).unwrap().as_str())
+        .set("content-type", "application/json")
+        .send_string(&serde_json::to_string(&event).unwrap())
+        .context("Could not send a message")
+        .unwrap();
+}
@@ -0,0 +1,8 @@
+// Every test suite uses only a part of the helpers
+#![allow(dead_code)]
+
+pub mod backend;
+pub mod client;
+pub mod deflate;
+pub mod embedded;
+pub mod fallback;
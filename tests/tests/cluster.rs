@@ -0,0 +1,107 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use backend::ClusterConfig;
+use chat::client::ChatClient;
+use chat::embedded::EmbeddedChat;
+
+mod chat;
+mod process;
+
+#[test]
+fn messages_reach_users_on_other_nodes() {
+    let nodes = start_cluster(3);
+
+    let mut alice = ChatClient::connect(&nodes[0].chat_url());
+    let mut bob = ChatClient::connect(&nodes[1].chat_url());
+    alice.receives_joined(bob.name());
+    let mut carol = ChatClient::connect(&nodes[2].chat_url());
+    alice.receives_joined(carol.name());
+    bob.receives_joined(carol.name());
+
+    alice.send("Hi all!");
+    bob.receives_message(alice.name(), "Hi all!");
+    carol.receives_message(alice.name(), "Hi all!");
+
+    carol.send("Hi from node 3");
+    alice.receives_message(carol.name(), "Hi from node 3");
+    bob.receives_message(carol.name(), "Hi from node 3");
+}
+
+#[test]
+fn users_of_all_nodes_are_in_the_room() {
+    let nodes = start_cluster(2);
+
+    let mut alice = ChatClient::connect(&nodes[0].chat_url());
+    let mut bob = ChatClient::connect(&nodes[1].chat_url());
+    alice.receives_joined(bob.name());
+
+    // Every node hands out ids of its own, so the names do not collide
+    assert_ne!(alice.name(), bob.name());
+    bob.send("/who");
+    let mut names = [alice.name(), bob.name()];
+    names.sort();
+    bob.receives_notice(&format!("In this room: {}", names.join(", ")));
+
+    bob.send("/nick Bob");
+    bob.receives_renamed("Bob");
+    alice.receives_renamed("Bob");
+    alice.send("/topic Clustering");
+    bob.receives_topic("Clustering");
+}
+
+#[test]
+fn a_node_going_away_takes_its_users_along() {
+    let mut nodes = start_cluster(2);
+
+    let mut alice = ChatClient::connect(&nodes[0].chat_url());
+    let bob = ChatClient::connect(&nodes[1].chat_url());
+    alice.receives_joined(bob.name());
+
+    drop(nodes.pop());
+    alice.receives_left(bob.name());
+}
+
+#[test]
+fn links_without_the_secret_are_dropped() {
+    let addresses = free_addresses(2);
+    let config = ClusterConfig { nodes: addresses.clone(), node: 0, secret: SECRET.to_owned() };
+    let node = EmbeddedChat::start(|server| server.cluster(config));
+    let mut alice = ChatClient::connect(&node.chat_url());
+
+    let hello = r#"{"node":1,"type":"hello","members":[{"uid":2,"name":"Mallory","room":"general"}]}"#;
+    for first_line in ["not the secret", hello] {
+        let mut link = TcpStream::connect(&addresses[0]).unwrap();
+        link.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        link.write_all(format!("{}\n{}\n", first_line, hello).as_bytes()).unwrap();
+        assert_eq!(link.read(&mut [0; 16]).unwrap(), 0, "Expected the link to be dropped");
+    }
+
+    alice.send("/who");
+    alice.receives_notice(&format!("In this room: {}", alice.name()));
+}
+
+const SECRET: &str = "shared by the nodes";
+
+fn free_addresses(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string())
+        .collect()
+}
+
+/// Nodes on free ports of this host, linked with each other.
+fn start_cluster(count: usize) -> Vec<EmbeddedChat> {
+    let addresses = free_addresses(count);
+    let nodes: Vec<_> = (0..count)
+        .map(|node| {
+            let config = ClusterConfig { nodes: addresses.clone(), node, secret: SECRET.to_owned() };
+            EmbeddedChat::start(|server| server.cluster(config))
+        })
+        .collect();
+    // Those started first could not reach the others yet, and dial again after a second
+    for node in nodes.iter() {
+        node.wait_for_peers(count - 1);
+    }
+    nodes
+}
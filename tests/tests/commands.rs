@@ -0,0 +1,75 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+#[test]
+fn commands_change_the_room_or_answer_the_issuer() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    bob.send("/nick Bob");
+    bob.receives_renamed("Bob");
+    alice.receives_renamed("Bob");
+
+    let alice_name = alice.name().to_owned();
+    alice.send("/me waves");
+    alice.receives_emote(&alice_name, "waves");
+    bob.receives_emote(&alice_name, "waves");
+
+    bob.send("/topic Rust");
+    bob.receives_topic("Rust");
+    alice.receives_topic("Rust");
+
+    bob.send("/who");
+    bob.receives_notice(&format!("In this room: Bob, {}", alice_name));
+
+    // Answers and errors go to the issuer only, so Alice gets just the message
+    bob.send("/dance");
+    bob.receives_notice("Unknown command /dance, try /help");
+    bob.send("//dance is not a command");
+    alice.receives_message("Bob", "/dance is not a command");
+}
+
+#[test]
+fn taken_name_is_refused() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    alice.send("/nick Alice");
+    bob.receives_renamed("Alice");
+    bob.send("/nick Alice");
+    bob.receives_notice("The name Alice is taken");
+}
+
+#[test]
+fn names_of_new_users_are_reserved() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    alice.send("/nick User#1000");
+    alice.receives_notice("Names starting with User# are given to new users");
+}
+
+#[test]
+fn topics_pass_the_pipeline() {
+    let backend = ChatBackend::start(&[("MAX_MESSAGE_LENGTH", "10")]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    bob.send("/topic Everything about Rust");
+    bob.receives_notice("Messages must not be longer than 10 characters");
+
+    bob.send("/topic Rust");
+    // Alice gets only the topic which passed
+    alice.receives_topic("Rust");
+}
@@ -0,0 +1,101 @@
+use backend::DeflateConfig;
+use chat::client::ChatClient;
+use chat::deflate::DeflateClient;
+use chat::embedded::EmbeddedChat;
+use protocol::ServerEvent;
+
+mod chat;
+mod process;
+
+#[test]
+fn messages_are_compressed_for_clients_offering_deflate() {
+    let backend = EmbeddedChat::start(|server| server.deflate(DeflateConfig::default()));
+
+    let mut alice = DeflateClient::connect(&backend.chat_url(), "permessage-deflate; client_max_window_bits");
+    assert_eq!(alice.agreed(), Some("permessage-deflate"));
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    let long = "Compress me! ".repeat(100);
+    alice.send(&long);
+    bob.receives_message(alice.name(), &long);
+    bob.send(&long);
+    match alice.receive_event() {
+        (ServerEvent::Message { name, text, .. }, compressed) => {
+            assert_eq!((name.as_str(), text.as_str()), (bob.name(), long.as_str()));
+            assert!(compressed, "Expected the message compressed");
+        }
+        event => panic!("Expected message from {}, but got {:?}", bob.name(), event),
+    }
+
+    let metrics = ureq::get(backend.url().join("metrics").unwrap().as_str()).call().unwrap().into_string().unwrap();
+    let counter = |name: &str| metrics.lines()
+        .find_map(|line| line.strip_prefix(name)?.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| panic!("Missing {} in\n{}", name, metrics));
+    assert!(counter("chat_deflate_sent_bytes_total") > 2 * counter("chat_deflate_sent_compressed_bytes_total"));
+    assert!(counter("chat_deflate_received_bytes_total") > 2 * counter("chat_deflate_received_compressed_bytes_total"));
+}
+
+#[test]
+fn window_and_context_takeover_are_negotiated() {
+    let backend = EmbeddedChat::start(|server| server.deflate(DeflateConfig::default().with_window_bits(10).with_context_takeover(false)));
+
+    // No compressor keeps to a window of 256 bytes, the second offer is taken
+    let mut alice = DeflateClient::connect(&backend.chat_url(),
+        "permessage-deflate; server_max_window_bits=8, permessage-deflate; client_max_window_bits");
+    assert_eq!(alice.agreed(), Some("permessage-deflate; server_no_context_takeover; client_no_context_takeover; \
+        server_max_window_bits=10; client_max_window_bits=10"));
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    for text in ["Hi Bob!", "Hi Bob, again!"] {
+        alice.send(text);
+        bob.receives_message(alice.name(), text);
+        bob.send(text);
+        match alice.receive_event() {
+            (ServerEvent::Message { text: received, .. }, true) => assert_eq!(received, text),
+            event => panic!("Expected compressed message {:?}, but got {:?}", text, event),
+        }
+    }
+}
+
+#[test]
+fn level_and_message_size_are_configured() {
+    let backend = EmbeddedChat::start(|server| server.deflate(DeflateConfig::default().with_level(0).with_max_message_size(1000)));
+
+    let mut alice = DeflateClient::connect(&backend.chat_url(), "permessage-deflate");
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    let long = "Compress me! ".repeat(50);
+    bob.send(&long);
+    match alice.receive_event() {
+        (ServerEvent::Message { text, .. }, true) => assert_eq!(text, long),
+        event => panic!("Expected compressed message, but got {:?}", event),
+    }
+    let metrics = ureq::get(backend.url().join("metrics").unwrap().as_str()).call().unwrap().into_string().unwrap();
+    let counter = |name: &str| metrics.lines()
+        .find_map(|line| line.strip_prefix(name)?.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| panic!("Missing {} in\n{}", name, metrics));
+    // Level 0 stores the message as it is
+    assert!(counter("chat_deflate_sent_compressed_bytes_total") >= counter("chat_deflate_sent_bytes_total"));
+
+    // Compressed it is much smaller than the limit, but not once inflated
+    alice.send(&"Compress me! ".repeat(100));
+    alice.receives_close(1009);
+}
+
+#[test]
+fn offers_are_ignored_unless_compression_is_configured() {
+    let backend = EmbeddedChat::start(|server| server);
+
+    let mut alice = DeflateClient::connect(&backend.chat_url(), "permessage-deflate");
+    assert_eq!(alice.agreed(), None);
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+    bob.send("Hi Alice!");
+    match alice.receive_event() {
+        (ServerEvent::Message { text, .. }, false) => assert_eq!(text, "Hi Alice!"),
+        event => panic!("Expected plain message, but got {:?}", event),
+    }
+}
@@ -0,0 +1,45 @@
+use std::net::TcpStream;
+
+use backend::{Audience, ChatServer, Incoming, Middleware, Rejected};
+use chat::client::ChatClient;
+use protocol::ServerEvent;
+use url::Url;
+
+mod chat;
+mod process;
+
+/// Shouts every message.
+struct Shout;
+
+impl Middleware for Shout {
+    fn process(&self, message: &mut Incoming, _: &mut Vec<(Audience, ServerEvent)>) -> Result<(), Rejected> {
+        message.text = message.text.to_uppercase();
+        Ok(())
+    }
+}
+
+#[test]
+fn chat_server_runs_in_process_until_shut_down() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(ChatServer::new(([127, 0, 0, 1], 0))
+        .max_message_length(10)
+        .middleware(Shout)
+        .start())
+        .unwrap();
+    let addr = server.addr();
+    assert_ne!(addr.port(), 0);
+    let chat_url = Url::parse(&format!("ws://{}/chat", addr)).unwrap();
+
+    let mut alice = ChatClient::connect(&chat_url);
+    let mut bob = ChatClient::connect(&chat_url);
+    alice.receives_joined(bob.name());
+    bob.send("hello");
+    alice.receives_message(bob.name(), "HELLO");
+    bob.send("hello again");
+    bob.receives_notice("Messages must not be longer than 10 characters");
+
+    drop((alice, bob));
+    server.shutdown_handle().shutdown();
+    runtime.block_on(server.stopped());
+    assert!(TcpStream::connect(addr).is_err());
+}
@@ -0,0 +1,37 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use protocol::Encoding;
+
+mod chat;
+mod process;
+
+#[test]
+fn clients_with_different_encodings_talk_to_each_other() {
+    let backend = ChatBackend::start(&[]);
+
+    // Each one gets only frames of its own encoding, anything else fails the test
+    let mut alice = ChatClient::connect_with_encoding(&backend.chat_url(), Encoding::MessagePack);
+    let mut bob = ChatClient::connect_with_encoding(&backend.chat_url(), Encoding::Json);
+    alice.receives_joined(bob.name());
+
+    alice.send("Hi Bob!");
+    bob.receives_message(alice.name(), "Hi Bob!");
+
+    bob.send("Hi Alice!");
+    alice.receives_message(bob.name(), "Hi Alice!");
+}
+
+#[test]
+fn binary_messages_go_through_the_pipeline() {
+    let backend = ChatBackend::start(&[("MAX_MESSAGE_LENGTH", "5")]);
+
+    let mut alice = ChatClient::connect_with_encoding(&backend.chat_url(), Encoding::MessagePack);
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    alice.send("Way too long");
+    alice.receives_notice("Messages must not be longer than 5 characters");
+    alice.send("/nick Al");
+    alice.receives_renamed("Al");
+    bob.receives_renamed("Al");
+}
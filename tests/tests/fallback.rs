@@ -0,0 +1,92 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use chat::fallback::{PollClient, SseClient};
+use protocol::ServerEvent;
+
+mod chat;
+mod process;
+
+#[test]
+fn event_stream_users_chat_with_websocket_users() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = SseClient::connect(backend.url());
+    let bob_name = match bob.receive() {
+        ServerEvent::Welcome { name, users, .. } => {
+            assert!(users.iter().any(|user| user.name == alice.name()));
+            name
+        }
+        event => panic!("Expected welcome, but got {:?}", event),
+    };
+    alice.receives_joined(&bob_name);
+
+    bob.send("Hi Alice!");
+    alice.receives_message(&bob_name, "Hi Alice!");
+
+    alice.send("Hi Bob!");
+    assert!(matches!(bob.receive(), ServerEvent::Message { text, .. } if text == "Hi Bob!"));
+}
+
+#[test]
+fn event_streams_resume_with_the_last_event_id() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = SseClient::connect(backend.url());
+    let bob_uid = match bob.receive() {
+        ServerEvent::Welcome { uid, .. } => uid,
+        event => panic!("Expected welcome, but got {:?}", event),
+    };
+    alice.send("Before");
+    assert!(matches!(bob.receive(), ServerEvent::Message { text, .. } if text == "Before"));
+
+    // Lost without the backend noticing yet, what follows goes nowhere
+    alice.send("While away");
+    let mut bob = bob.reconnect();
+    match bob.receive() {
+        ServerEvent::Welcome { uid, .. } => assert_eq!(uid, bob_uid),
+        event => panic!("Expected welcome, but got {:?}", event),
+    }
+    assert!(matches!(bob.receive(), ServerEvent::Message { text, .. } if text == "While away"));
+}
+
+#[test]
+fn long_polling_users_chat_with_websocket_users() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let carol = PollClient::connect(backend.url());
+    let polled = carol.poll();
+    let carol_name = match &polled.events[..] {
+        [ServerEvent::Welcome { name, .. }] => name.clone(),
+        events => panic!("Expected welcome, but got {:?}", events),
+    };
+    alice.receives_joined(&carol_name);
+
+    carol.send("Hi Alice!");
+    alice.receives_message(&carol_name, "Hi Alice!");
+
+    alice.send("Hi Carol!");
+    alice.send("How are you?");
+    // Both are there once one poll has answered, unless the second was late
+    let mut texts = vec![];
+    while texts.len() < 2 {
+        texts.extend(carol.poll().events.into_iter().map(|event| match event {
+            ServerEvent::Message { text, .. } => text,
+            event => panic!("Expected message, but got {:?}", event),
+        }));
+    }
+    assert_eq!(texts, ["Hi Carol!", "How are you?"]);
+}
+
+#[test]
+fn unknown_sessions_are_not_found() {
+    let backend = ChatBackend::start(&[]);
+
+    let url = backend.url().join("poll/unknown").unwrap();
+    match ureq::get(url.as_str()).call() {
+        Err(ureq::Error::Status(status, _)) => assert_eq!(status, 404),
+        result => panic!("Expected 404, but got {:?}", result.map(|response| response.status())),
+    }
+}
@@ -0,0 +1,101 @@
+use std::env;
+use std::io::{Cursor, Read};
+
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use image::{ImageOutputFormat, RgbImage};
+use protocol::Attachment;
+
+mod chat;
+mod process;
+
+const FAKE_PNG: &[u8] = b"\x89PNG\r\n\x1a\nnot really an image";
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut png = Cursor::new(vec![]);
+    RgbImage::new(width, height).write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    png.into_inner()
+}
+
+fn download(backend: &ChatBackend, path: &str) -> (Option<String>, Vec<u8>) {
+    let response = ureq::get(backend.url().join(path).unwrap().as_str()).call().unwrap();
+    let content_type = response.header("content-type").map(str::to_owned);
+    let mut data = vec![];
+    response.into_reader().read_to_end(&mut data).unwrap();
+    (content_type, data)
+}
+
+fn upload(backend: &ChatBackend, name: &str, content_type: &str, data: &[u8]) -> Result<Attachment, u16> {
+    let url = backend.url().join(&format!("files?name={}", name)).unwrap();
+    match ureq::post(url.as_str()).set("content-type", content_type).send_bytes(data) {
+        Ok(response) => Ok(serde_json::from_str(&response.into_string().unwrap()).unwrap()),
+        Err(ureq::Error::Status(status, _)) => Err(status),
+        Err(error) => panic!("Upload failed: {}", error),
+    }
+}
+
+#[test]
+fn uploaded_files_are_attached_and_downloaded() {
+    let uploads = env::temp_dir().join(format!("rust-chat-uploads-{}", std::process::id()));
+    let backend = ChatBackend::start(&[("UPLOADS_DIR", uploads.to_str().unwrap())]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    let cat = png(640, 360);
+    let attachment = upload(&backend, "cat.png", "image/png", &cat).unwrap();
+    assert_eq!((attachment.name.as_str(), attachment.size), ("cat.png", cat.len() as u64));
+    bob.send_with_attachments("My cat", &[&attachment.id]);
+    let attachments = alice.receives_message_with_attachments(bob.name(), "My cat");
+    assert_eq!(attachments, std::slice::from_ref(&attachment));
+
+    assert_eq!(download(&backend, &attachment.url()), (Some("image/png".to_owned()), cat));
+    let (content_type, thumbnail) = download(&backend, &attachment.thumbnail_url());
+    assert_eq!(content_type.as_deref(), Some("image/png"));
+    let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (160, 90));
+
+    let icon = upload(&backend, "icon.png", "image/png", &png(16, 16)).unwrap();
+    let thumbnail = image::load_from_memory(&download(&backend, &icon.thumbnail_url()).1).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (16, 16));
+
+    bob.send_with_attachments("Not mine", &["unknown"]);
+    bob.receives_notice("Unknown attachment unknown");
+
+    std::fs::remove_dir_all(&uploads).unwrap();
+}
+
+#[test]
+fn uploads_are_limited_in_size_and_type() {
+    let uploads = env::temp_dir().join(format!("rust-chat-uploads-limited-{}", std::process::id()));
+    let backend = ChatBackend::start(&[("UPLOADS_DIR", uploads.to_str().unwrap()), ("UPLOAD_MAX_BYTES", "100")]);
+
+    assert_eq!(upload(&backend, "page.html", "text/html", b"<html></html>").unwrap_err(), 415);
+    assert_eq!(upload(&backend, "cat.png", "image/png", b"<html></html>").unwrap_err(), 400);
+    assert_eq!(upload(&backend, "cat.png", "image/png", FAKE_PNG).unwrap_err(), 400);
+    assert_eq!(upload(&backend, "big.txt", "text/plain", &[b'a'; 101]).unwrap_err(), 413);
+
+    std::fs::remove_dir_all(&uploads).unwrap();
+}
+
+#[test]
+fn banned_users_cannot_download() {
+    let uploads = env::temp_dir().join(format!("rust-chat-uploads-banned-{}", std::process::id()));
+    let backend = ChatBackend::start(&[("UPLOADS_DIR", uploads.to_str().unwrap()), ("ADMIN_TOKEN", "secret")]);
+
+    let attachment = upload(&backend, "notes.txt", "text/plain", b"Secret notes").unwrap();
+    ureq::post(backend.url().join("admin/bans").unwrap().as_str())
+        .set("authorization", "Bearer secret")
+        .send_string(r#"{"ip": "127.0.0.1", "secs": 60}"#)
+        .unwrap();
+
+    for path in [attachment.url(), attachment.thumbnail_url()] {
+        match ureq::get(backend.url().join(&path).unwrap().as_str()).call() {
+            Err(ureq::Error::Status(status, _)) => assert_eq!(status, 403),
+            result => panic!("Expected 403 for {}, but got {:?}", path, result),
+        }
+    }
+
+    std::fs::remove_dir_all(&uploads).unwrap();
+}
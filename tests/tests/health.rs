@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+use backend::{FilesConfig, Node, RedisBroker};
+use chat::embedded::EmbeddedChat;
+use mock::redis::MockRedis;
+
+mod chat;
+mod mock;
+mod process;
+
+#[test]
+fn backend_is_ready_until_it_shuts_down() {
+    let backend = EmbeddedChat::start(|server| server.shutdown_delay(Duration::from_secs(3)));
+    let get = |path: &str| get(&backend, path);
+    assert_eq!(get("healthz"), (200, "ok".to_owned()));
+    assert_eq!(get("readyz"), (200, "ready".to_owned()));
+
+    backend.begin_shutdown();
+    let start = Instant::now();
+    while get("readyz").0 == 200 {
+        assert!(start.elapsed() < Duration::from_secs(2), "Still ready after the shutdown began");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(get("readyz"), (503, "shutting down".to_owned()));
+    // Still alive, only no new users should come
+    assert_eq!(get("healthz"), (200, "ok".to_owned()));
+}
+
+#[test]
+fn backend_is_not_ready_while_uploads_cannot_be_stored() {
+    let uploads = env::temp_dir().join(format!("rust-chat-uploads-readiness-{}", std::process::id()));
+    let config = FilesConfig { dir: uploads.clone(), max_bytes: 1000, types: vec!["text/plain".to_owned()] };
+    let backend = EmbeddedChat::start(|server| server.files(config));
+    assert_eq!(get(&backend, "readyz"), (200, "ready".to_owned()));
+
+    fs::remove_dir_all(&uploads).unwrap();
+    let (status, problems) = get(&backend, "readyz");
+    assert_eq!(status, 503);
+    assert!(problems.starts_with("uploads: could not write to"), "{}", problems);
+}
+
+#[test]
+fn backend_is_not_ready_while_the_broker_is_lost() {
+    let redis = MockRedis::start();
+    let broker = RedisBroker { url: redis.url(), channel: "rooms".to_owned() };
+    let backend = EmbeddedChat::start(move |server| server.broker(Node { index: 0, count: 1 }, broker));
+    assert_eq!(get(&backend, "readyz"), (200, "ready".to_owned()));
+
+    // Nonsense loses the subscription, the backend subscribes again a second later
+    redis.push("rooms", b"nonsense\r\n");
+    let start = Instant::now();
+    while get(&backend, "readyz").0 == 200 {
+        assert!(start.elapsed() < Duration::from_secs(2), "Still ready after Redis was lost");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(get(&backend, "readyz"), (503, "broker: not connected".to_owned()));
+    while get(&backend, "readyz").0 != 200 {
+        assert!(start.elapsed() < Duration::from_secs(10), "Not ready again once Redis was back");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn get(backend: &EmbeddedChat, path: &str) -> (u16, String) {
+    match ureq::get(backend.url().join(path).unwrap().as_str()).call() {
+        Ok(response) => (response.status(), response.into_string().unwrap()),
+        Err(ureq::Error::Status(status, response)) => (status, response.into_string().unwrap()),
+        Err(error) => panic!("GET /{} failed: {}", path, error),
+    }
+}
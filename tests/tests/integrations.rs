@@ -0,0 +1,58 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+fn post(backend: &ChatBackend, room: &str, token: &str, text: &str) -> Result<u64, (u16, String)> {
+    let url = backend.url().join(&format!("api/rooms/{}/messages", room)).unwrap();
+    let response = ureq::post(url.as_str())
+        .set("authorization", &format!("Bearer {}", token))
+        .set("content-type", "application/json")
+        .send_string(&serde_json::json!({ "text": text }).to_string());
+    match response {
+        Ok(response) => {
+            assert_eq!(response.status(), 201);
+            let created: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            Ok(created["id"].as_u64().unwrap())
+        }
+        Err(ureq::Error::Status(status, response)) => Err((status, response.into_string().unwrap())),
+        Err(error) => panic!("Post failed: {}", error),
+    }
+}
+
+#[test]
+fn integrations_post_into_rooms() {
+    let backend = ChatBackend::start(&[("INTEGRATIONS", "CI=s3cret, Monitoring=other"), ("MAX_MESSAGE_LENGTH", "20")]);
+
+    let mut alice = ChatClient::connect(&backend.room_url("builds"));
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    let mut carol = ChatClient::connect(&backend.chat_url());
+    bob.receives_joined(carol.name());
+
+    post(&backend, "builds", "s3cret", "Build #42 passed").unwrap();
+    alice.receives_message("CI", "Build #42 passed");
+
+    carol.send("Anybody here?");
+    // The build message would have arrived first, if it was sent to Bob's room
+    bob.receives_message(carol.name(), "Anybody here?");
+}
+
+#[test]
+fn integrations_need_a_valid_token() {
+    let backend = ChatBackend::start(&[("INTEGRATIONS", "CI=s3cret"), ("MAX_MESSAGE_LENGTH", "20")]);
+
+    assert_eq!(post(&backend, "builds", "wrong", "Build #42 passed").unwrap_err(),
+               (401, "Invalid integration token".to_owned()));
+    assert_eq!(post(&backend, "builds", "s3cret", "Build #42 failed with 3 errors").unwrap_err(),
+               (400, "Messages must not be longer than 20 characters".to_owned()));
+}
+
+#[test]
+fn integrations_are_missing_unless_configured() {
+    let backend = ChatBackend::start(&[]);
+
+    let (status, _) = post(&backend, "builds", "s3cret", "Build #42 passed").unwrap_err();
+    // Like any unknown POST, there is no route for it
+    assert!([404, 405].contains(&status), "{}", status);
+}
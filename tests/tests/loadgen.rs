@@ -0,0 +1,76 @@
+use std::process::Command;
+
+use chat::backend::ChatBackend;
+
+mod chat;
+mod process;
+
+#[test]
+fn load_generator_reports_deliveries_and_latency() {
+    let backend = ChatBackend::start(&[]);
+
+    let output = Command::new("../target/debug/loadgen")
+        .env("CHAT_URL", backend.chat_url().as_str())
+        .env("USERS", "10")
+        .env("ROOMS", "2")
+        .env("RATE", "20")
+        .env("DURATION_SECS", "1")
+        .env("DRAIN_SECS", "1")
+        .output()
+        .expect("Could not run loadgen, build it with `cargo build -p loadgen` first");
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", report, String::from_utf8_lossy(&output.stderr));
+
+    assert!(report.contains("users        10 connected"), "{}", report);
+    // Every message reaches the 4 others in the room of its sender
+    assert!(report.contains("expected, 0 lost (0.00%)"), "{}", report);
+    assert!(report.contains("latency      p50 "), "{}", report);
+}
+
+/// How much longer the slowest messages may take while users come and go. Loadgen and
+/// backend share the cores, and every user joining or leaving is announced to the whole room,
+/// so the churn costs some latency even without any contention.
+const MAX_CHURN_P99_RATIO: f64 = 5.0;
+
+#[test]
+fn load_generator_compares_latency_with_and_without_churn() {
+    // Closed connections leave right away instead of waiting to be resumed
+    let backend = ChatBackend::start(&[("RESUME_GRACE_SECS", "0")]);
+
+    let output = Command::new("../target/debug/loadgen")
+        .env("CHAT_URL", backend.chat_url().as_str())
+        .env("USERS", "50")
+        .env("ROOMS", "2")
+        .env("RATE", "100")
+        .env("CHURN", "50")
+        .env("DURATION_SECS", "2")
+        .env("DRAIN_SECS", "1")
+        .output()
+        .expect("Could not run loadgen, build it with `cargo build -p loadgen` first");
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", report, String::from_utf8_lossy(&output.stderr));
+
+    let (without, with) = report.split_once("with churn\n").unwrap_or_else(|| panic!("{}", report));
+    assert!(without.contains("without churn\n"), "{}", report);
+    assert!(with.contains("connections opened and closed"), "{}", report);
+    assert!(with.contains(", 0 failed, "), "{}", report);
+    let left: u64 = with.lines()
+        .find_map(|line| line.strip_suffix(" left meanwhile"))
+        .and_then(|line| line.rsplit(", ").next())
+        .and_then(|left| left.parse().ok())
+        .unwrap_or_else(|| panic!("No users left in {}", report));
+    assert!(left > 0, "{}", report);
+    // Users coming and going do not cost any messages of those staying
+    for phase in [without, with] {
+        assert!(phase.contains("expected, 0 lost (0.00%)"), "{}", report);
+        assert!(phase.contains("latency      p50 "), "{}", report);
+    }
+
+    let ratio: f64 = report.lines()
+        .find_map(|line| line.strip_prefix("p99 "))
+        .and_then(|line| line.split(", ").nth(2))
+        .and_then(|ratio| ratio.strip_suffix(" times as long"))
+        .and_then(|ratio| ratio.parse().ok())
+        .unwrap_or_else(|| panic!("No p99 ratio in {}", report));
+    assert!(ratio <= MAX_CHURN_P99_RATIO, "p99 with churn is {} times as long\n{}", ratio, report);
+}
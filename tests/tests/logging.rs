@@ -0,0 +1,33 @@
+use serde_json::Value;
+
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+#[test]
+fn json_logs_carry_the_connection_span() {
+    let mut backend = ChatBackend::start(&[("LOG_FORMAT", "json"), ("RUST_LOG", "debug")]);
+
+    let mut alice = ChatClient::connect(&backend.room_url("rust"));
+    let mut bob = ChatClient::connect(&backend.room_url("rust"));
+    alice.receives_joined(bob.name());
+    alice.send("Hi Bob!");
+    bob.receives_message(alice.name(), "Hi Bob!");
+
+    let log = backend.stop_for_log();
+    let lines = log.lines()
+        .map(|line| serde_json::from_str::<Value>(line)
+            .unwrap_or_else(|e| panic!("Expected only JSON lines, but got {:?}: {}", line, e)))
+        .collect::<Vec<_>>();
+    let received = lines.iter()
+        .find(|line| line["fields"]["message"] == "message received")
+        .unwrap_or_else(|| panic!("Missing the received message in\n{}", log));
+    let span = &received["span"];
+    assert_eq!(span["name"], "connection");
+    assert!(span["connection"].is_u64(), "Expected the connection in {}", span);
+    assert!(span["remote"].as_str().map_or(false, |remote| remote.starts_with("127.0.0.1:")), "Expected the remote address in {}", span);
+    assert_eq!(span["uid"], alice.uid() as u64);
+    assert_eq!(span["room"], "rust");
+}
@@ -0,0 +1,32 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+#[test]
+fn metrics_count_users_messages_and_failed_handshakes() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+    bob.send("Hi!");
+    alice.receives_message(bob.name(), "Hi!");
+
+    // Not a websocket upgrade
+    let _ = ureq::get(backend.url().join("chat").unwrap().as_str()).call();
+
+    let metrics = ureq::get(backend.url().join("metrics").unwrap().as_str()).call().unwrap()
+        .into_string().unwrap();
+    for expected in [
+        "chat_users 2",
+        "chat_connections 2",
+        "chat_messages_received_total 1",
+        "chat_messages_broadcast_total 1",
+        "chat_handshake_failures_total 1",
+        "chat_send_errors_total 0",
+    ] {
+        assert!(metrics.lines().any(|line| line == expected), "Missing {:?} in\n{}", expected, metrics);
+    }
+}
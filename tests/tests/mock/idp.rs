@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::runtime::Runtime;
+use url::Url;
+use warp::http::{StatusCode, Uri};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+pub const CLIENT_ID: &str = "rust-chat";
+
+struct Grant {
+    subject: String,
+    name: String,
+    nonce: Option<String>,
+    challenge: String,
+    redirect_uri: String,
+}
+
+struct State {
+    issuer: String,
+    users: HashMap<String, String>,
+    grants: Mutex<HashMap<String, Grant>>,
+    next_code: AtomicUsize,
+}
+
+/// A tiny OpenID Connect provider which logs in whoever `login_hint` names,
+/// but checks the client and PKCE parameters like a real one.
+pub struct MockIdentityProvider {
+    issuer: String,
+    _runtime: Runtime,
+}
+
+impl MockIdentityProvider {
+    /// `users` are pairs of subject and name
+    pub fn start(users: &[(&str, &str)]) -> MockIdentityProvider {
+        Self::_start(users)
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    fn _start(users: &[(&str, &str)]) -> Result<MockIdentityProvider> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let issuer = format!("http://{}", addr);
+
+        let state = Arc::new(State {
+            issuer: issuer.clone(),
+            users: users.iter().map(|(subject, name)| (subject.to_string(), name.to_string())).collect(),
+            grants: Mutex::default(),
+            next_code: AtomicUsize::new(1),
+        });
+        let state = warp::any().map(move || state.clone());
+
+        let discovery = warp::path!(".well-known" / "openid-configuration")
+            .and(state.clone())
+            .map(|state: Arc<State>| state.discovery());
+        let authorize = warp::path("authorize")
+            .and(warp::query::<HashMap<String, String>>())
+            .and(state.clone())
+            .map(|params, state: Arc<State>| state.authorize(params));
+        let token = warp::path("token")
+            .and(warp::post())
+            .and(warp::body::form::<HashMap<String, String>>())
+            .and(state)
+            .map(|params, state: Arc<State>| state.token(params));
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let (_, server) = runtime.block_on(async {
+            warp::serve(discovery.or(authorize).or(token)).try_bind_ephemeral(addr)
+        }).context("Could not bind identity provider")?;
+        runtime.spawn(server);
+
+        Ok(MockIdentityProvider { issuer, _runtime: runtime })
+    }
+}
+
+impl State {
+    fn discovery(&self) -> Response {
+        warp::reply::json(&json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": format!("{}/authorize", self.issuer),
+            "token_endpoint": format!("{}/token", self.issuer),
+        })).into_response()
+    }
+
+    fn authorize(&self, params: HashMap<String, String>) -> Response {
+        let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+        if param("client_id") != CLIENT_ID
+            || param("response_type") != "code"
+            || param("code_challenge_method") != "S256"
+            || param("code_challenge").is_empty() {
+            return bad_request("invalid_request");
+        }
+        let subject = param("login_hint");
+        let name = match self.users.get(&subject) {
+            Some(name) => name.clone(),
+            None => return bad_request("access_denied"),
+        };
+
+        let code = format!("code-{}", self.next_code.fetch_add(1, Ordering::Relaxed));
+        let redirect = Url::parse_with_params(&param("redirect_uri"), &[("code", &code), ("state", &param("state"))]);
+        let redirect = match redirect.map(|url| url.as_str().parse::<Uri>()) {
+            Ok(Ok(redirect)) => redirect,
+            _ => return bad_request("invalid_request"),
+        };
+
+        self.grants.lock().unwrap().insert(code, Grant {
+            subject,
+            name,
+            nonce: params.get("nonce").cloned(),
+            challenge: param("code_challenge"),
+            redirect_uri: param("redirect_uri"),
+        });
+
+        warp::redirect::see_other(redirect).into_response()
+    }
+
+    fn token(&self, params: HashMap<String, String>) -> Response {
+        let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+        let grant = match self.grants.lock().unwrap().remove(&param("code")) {
+            Some(grant) => grant,
+            None => return bad_request("invalid_grant"),
+        };
+        let challenge = base64::encode_config(Sha256::digest(param("code_verifier").as_bytes()), base64::URL_SAFE_NO_PAD);
+        if param("grant_type") != "authorization_code"
+            || param("client_id") != CLIENT_ID
+            || param("redirect_uri") != grant.redirect_uri
+            || challenge != grant.challenge {
+            return bad_request("invalid_grant");
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let claims = json!({
+            "iss": self.issuer,
+            "sub": grant.subject,
+            "aud": CLIENT_ID,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": grant.nonce,
+            "name": grant.name,
+        });
+        let id_token = [
+            json!({"alg": "RS256", "typ": "JWT"}).to_string(),
+            claims.to_string(),
+            // The backend gets the token directly from us and does not check signatures
+            "signature".to_owned(),
+        ].map(|part| base64::encode_config(part, base64::URL_SAFE_NO_PAD)).join(".");
+
+        warp::reply::json(&json!({
+            "access_token": "access-token",
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token,
+        })).into_response()
+    }
+}
+
+fn bad_request(error: &str) -> Response {
+    warp::reply::with_status(warp::reply::json(&json!({ "error": error })), StatusCode::BAD_REQUEST)
+        .into_response()
+}
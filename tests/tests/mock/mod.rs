@@ -0,0 +1,6 @@
+// Every test suite uses only a part of the mocks
+#![allow(dead_code)]
+
+pub mod idp;
+pub mod receiver;
+pub mod redis;
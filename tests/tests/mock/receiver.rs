@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::net::TcpListener;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::runtime::Runtime;
+use warp::http::{HeaderMap, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::Filter;
+
+/// A request as the receiver got it.
+pub struct Received {
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: serde_json::Value,
+    raw_body: Bytes,
+}
+
+impl Received {
+    /// Whether `X-Chat-Signature` is the HMAC-SHA256 of the body with `secret`.
+    pub fn is_signed_with(&self, secret: &str) -> bool {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(&self.raw_body);
+        let expected: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.headers.get("x-chat-signature").and_then(|value| value.to_str().ok())
+            == Some(format!("sha256={}", expected).as_str())
+    }
+}
+
+/// Stands in for the HTTP endpoints webhooks call, answers with the statuses it is
+/// told to and with 200 after them.
+pub struct MockReceiver {
+    url: String,
+    statuses: Arc<Mutex<VecDeque<u16>>>,
+    received: mpsc::Receiver<Received>,
+    _runtime: Runtime,
+}
+
+impl MockReceiver {
+    pub fn start() -> MockReceiver {
+        Self::_start()
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.url, path)
+    }
+
+    /// The next requests are answered with these statuses.
+    pub fn answer_with(&self, statuses: &[u16]) {
+        self.statuses.lock().unwrap().extend(statuses);
+    }
+
+    pub fn receives(&self) -> Received {
+        self.received.recv_timeout(Duration::from_secs(5))
+            .expect("Expected a request, but got none")
+    }
+
+    pub fn receives_nothing(&self) {
+        if let Ok(received) = self.received.recv_timeout(Duration::from_millis(500)) {
+            panic!("Expected no request, but got one to {}", received.path);
+        }
+    }
+
+    fn _start() -> Result<MockReceiver> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let statuses = Arc::new(Mutex::new(VecDeque::new()));
+        let (tx, received) = mpsc::channel();
+
+        let answers = statuses.clone();
+        let tx = Arc::new(Mutex::new(tx));
+        let receive = warp::post()
+            .and(warp::path::full())
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |path: warp::path::FullPath, headers: HeaderMap, raw_body: Bytes| {
+                let body = serde_json::from_slice(&raw_body).unwrap_or(serde_json::Value::Null);
+                let path = path.as_str().trim_start_matches('/').to_owned();
+                // Before the test learns about the request and tells how to answer the next ones
+                let status = answers.lock().unwrap().pop_front().unwrap_or(200);
+                let _ = tx.lock().unwrap().send(Received { path, headers, body, raw_body });
+                StatusCode::from_u16(status).unwrap()
+            });
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let (_, server) = runtime.block_on(async {
+            warp::serve(receive).try_bind_ephemeral(addr)
+        }).context("Could not bind receiver")?;
+        runtime.spawn(server);
+
+        Ok(MockReceiver { url: format!("http://{}", addr), statuses, received, _runtime: runtime })
+    }
+}
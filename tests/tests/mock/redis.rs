@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use url::Url;
+
+/// Stands in for a Redis server with just what brokers need: `AUTH`, `SUBSCRIBE`
+/// and `PUBLISH`. It keeps running until the test process ends.
+pub struct MockRedis {
+    addr: SocketAddr,
+    /// Messages published so far, key is the channel
+    published: Arc<Mutex<HashMap<String, usize>>>,
+    /// Subscriptions so far, key is the channel
+    subscribed: Arc<Mutex<HashMap<String, usize>>>,
+    subscribers: Subscribers,
+}
+
+/// Subscribed connections, key is the channel.
+type Subscribers = Arc<Mutex<HashMap<String, Vec<TcpStream>>>>;
+
+impl MockRedis {
+    pub fn start() -> MockRedis {
+        Self::_start()
+            // Top level test methods panic on error by design
+            .unwrap()
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("redis://{}", self.addr)).unwrap()
+    }
+
+    pub fn published(&self, channel: &str) -> usize {
+        self.published.lock().unwrap().get(channel).copied().unwrap_or(0)
+    }
+
+    pub fn subscribed(&self, channel: &str) -> usize {
+        self.subscribed.lock().unwrap().get(channel).copied().unwrap_or(0)
+    }
+
+    /// Sends the bytes as they are to the subscribers of the channel, like a server
+    /// which is broken or worse.
+    pub fn push(&self, channel: &str, raw: &[u8]) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.entry(channel.to_owned()).or_default()
+            .retain_mut(|subscriber| subscriber.write_all(raw).is_ok());
+    }
+
+    fn _start() -> Result<MockRedis> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let published = Arc::new(Mutex::new(HashMap::new()));
+        let subscribed = Arc::new(Mutex::new(HashMap::new()));
+        let subscribers = Subscribers::default();
+
+        let counts = (published.clone(), subscribed.clone());
+        let serving = subscribers.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (counts, subscribers) = (counts.clone(), serving.clone());
+                std::thread::spawn(move || {
+                    // A connection which is closed or talks nonsense just ends
+                    let _ = serve(stream, counts, subscribers);
+                });
+            }
+        });
+        Ok(MockRedis { addr, published, subscribed, subscribers })
+    }
+}
+
+/// Published and subscribed so far, key is the channel.
+type Counts = (Arc<Mutex<HashMap<String, usize>>>, Arc<Mutex<HashMap<String, usize>>>);
+
+fn serve(stream: TcpStream, (published, subscribed): Counts, subscribers: Subscribers) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let command = read_command(&mut reader)?;
+        let args: Vec<&[u8]> = command.iter().map(Vec::as_slice).collect();
+        match args.as_slice() {
+            [name, ..] if name.eq_ignore_ascii_case(b"AUTH") => writer.write_all(b"+OK\r\n")?,
+            [name, channel] if name.eq_ignore_ascii_case(b"SUBSCRIBE") => {
+                let channel = String::from_utf8(channel.to_vec())?;
+                *subscribed.lock().unwrap().entry(channel.clone()).or_default() += 1;
+                subscribers.lock().unwrap().entry(channel.clone()).or_default().push(writer.try_clone()?);
+                writer.write_all(&array(&[b"subscribe", channel.as_bytes()], Some(1)))?;
+            }
+            [name, channel, payload] if name.eq_ignore_ascii_case(b"PUBLISH") => {
+                let channel = String::from_utf8(channel.to_vec())?;
+                *published.lock().unwrap().entry(channel.clone()).or_default() += 1;
+                let message = array(&[b"message", channel.as_bytes(), payload], None);
+                let mut subscribers = subscribers.lock().unwrap();
+                let receivers = subscribers.entry(channel).or_default();
+                receivers.retain_mut(|subscriber| subscriber.write_all(&message).is_ok());
+                writer.write_all(format!(":{}\r\n", receivers.len()).as_bytes())?;
+            }
+            _ => writer.write_all(b"-ERR unknown command\r\n")?,
+        }
+    }
+}
+
+/// A command as an array of bulk strings.
+fn read_command(reader: &mut impl BufRead) -> Result<Vec<Vec<u8>>> {
+    let len: usize = read_header(reader, '*')?;
+    let mut args = Vec::with_capacity(len);
+    for _ in 0..len {
+        let mut arg = vec![0; read_header(reader, '$')? + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(arg.len() - 2);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+fn read_header(reader: &mut impl BufRead, kind: char) -> Result<usize> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        bail!("Connection closed");
+    }
+    line.trim_end().strip_prefix(kind)
+        .with_context(|| format!("Expected {}, but got {:?}", kind, line))?
+        .parse()
+        .context("Expected a length")
+}
+
+/// Bulk strings, and an integer after them.
+fn array(bulks: &[&[u8]], integer: Option<i64>) -> Vec<u8> {
+    let len = bulks.len() + integer.map_or(0, |_| 1);
+    let mut array = format!("*{}\r\n", len).into_bytes();
+    for bulk in bulks {
+        array.extend_from_slice(format!("${}\r\n", bulk.len()).as_bytes());
+        array.extend_from_slice(bulk);
+        array.extend_from_slice(b"\r\n");
+    }
+    if let Some(integer) = integer {
+        array.extend_from_slice(format!(":{}\r\n", integer).as_bytes());
+    }
+    array
+}
@@ -0,0 +1,147 @@
+use std::thread;
+use std::time::Duration;
+
+use url::Url;
+
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use mock::idp::{self, MockIdentityProvider};
+
+mod chat;
+mod mock;
+mod process;
+
+#[test]
+fn logged_in_users_chat_under_their_names() {
+    let idp = MockIdentityProvider::start(&[("alice", "Alice"), ("bob", "Bob")]);
+    let backend = start_backend(&idp);
+
+    let mut alice = ChatClient::connect_with_cookie(&backend.chat_url(), &log_in(&backend, "alice"));
+    let mut bob = ChatClient::connect_with_cookie(&backend.chat_url(), &log_in(&backend, "bob"));
+
+    alice.receives_joined("Bob");
+
+    alice.send("Hi Bob!");
+    bob.receives_message("Alice", "Hi Bob!");
+
+    bob.send("Hi Alice!");
+    alice.receives_message("Bob", "Hi Alice!");
+}
+
+#[test]
+fn logged_in_users_get_a_number_added_to_taken_names() {
+    let idp = MockIdentityProvider::start(&[("alice", "Alice"), ("other-alice", "Alice")]);
+    let backend = start_backend(&idp);
+
+    let mut alice = ChatClient::connect_with_cookie(&backend.chat_url(), &log_in(&backend, "alice"));
+    let other = ChatClient::connect_with_cookie(&backend.chat_url(), &log_in(&backend, "other-alice"));
+    assert_eq!(alice.name(), "Alice");
+    assert_eq!(other.name(), "Alice#2");
+    alice.receives_joined("Alice#2");
+}
+
+#[test]
+fn chat_requires_login() {
+    let idp = MockIdentityProvider::start(&[("alice", "Alice")]);
+    let backend = start_backend(&idp);
+
+    match ChatClient::try_connect(&backend.chat_url(), None).map_err(|e| e.downcast::<tungstenite::Error>()) {
+        Err(Ok(tungstenite::Error::Http(response))) => assert_eq!(response.status(), 401),
+        Err(error) => panic!("Unexpected error {:?}", error),
+        Ok(_) => panic!("Connected without login"),
+    }
+
+    let response = browser().get(backend.url().as_str()).call().unwrap();
+    assert_eq!(response.status(), 303);
+    assert_eq!(response.header("location"), Some("/auth/login"));
+}
+
+#[test]
+fn login_with_forged_state_fails() {
+    let idp = MockIdentityProvider::start(&[("alice", "Alice")]);
+    let backend = start_backend(&idp);
+
+    let mut callback = authorize(&backend, "alice");
+    let params = callback.query_pairs()
+        .map(|(name, value)| if name == "state" { (name.into_owned(), "forged".to_owned()) } else { (name.into_owned(), value.into_owned()) })
+        .collect::<Vec<_>>();
+    callback.query_pairs_mut().clear().extend_pairs(params);
+
+    match browser().get(callback.as_str()).call() {
+        Err(ureq::Error::Status(status, _)) => assert_eq!(status, 400),
+        Err(error) => panic!("Unexpected error {}", error),
+        Ok(response) => panic!("Logged in with forged state, status {}", response.status()),
+    }
+}
+
+#[test]
+fn sessions_expire() {
+    let idp = MockIdentityProvider::start(&[("alice", "Alice")]);
+    let backend = ChatBackend::start(&[
+        ("OIDC_ISSUER", idp.issuer()),
+        ("OIDC_CLIENT_ID", idp::CLIENT_ID),
+        ("OIDC_REDIRECT_URL", "https://chat.example.com/auth/callback"),
+        ("OIDC_SESSION_SECS", "1"),
+    ]);
+
+    let cookie = session_cookie(&backend, "alice");
+    let attributes = cookie.split(';').map(str::trim).collect::<Vec<_>>();
+    assert!(attributes.contains(&"Secure"), "Expected a secure cookie over https, but got {}", cookie);
+    assert!(attributes.contains(&"Max-Age=1"), "Expected the cookie to expire with the session, but got {}", cookie);
+    let session = attributes[0];
+    ChatClient::connect_with_cookie(&backend.chat_url(), session);
+
+    thread::sleep(Duration::from_millis(1100));
+    match ChatClient::try_connect(&backend.chat_url(), Some(session)).map_err(|e| e.downcast::<tungstenite::Error>()) {
+        Err(Ok(tungstenite::Error::Http(response))) => assert_eq!(response.status(), 401),
+        Err(error) => panic!("Unexpected error {:?}", error),
+        Ok(_) => panic!("Connected with an expired session"),
+    }
+}
+
+fn start_backend(idp: &MockIdentityProvider) -> ChatBackend {
+    // The redirect url is known only once the backend has a port, and the
+    // provider does not care whether it is right as long as it does not change
+    ChatBackend::start(&[
+        ("OIDC_ISSUER", idp.issuer()),
+        ("OIDC_CLIENT_ID", idp::CLIENT_ID),
+        ("OIDC_REDIRECT_URL", "http://localhost/auth/callback"),
+    ])
+}
+
+/// Walks through the login like a browser would and returns the session cookie.
+fn log_in(backend: &ChatBackend, subject: &str) -> String {
+    let cookie = session_cookie(backend, subject);
+    cookie.split(';').next().unwrap().to_owned()
+}
+
+/// The `Set-Cookie` of the login, with its attributes.
+fn session_cookie(backend: &ChatBackend, subject: &str) -> String {
+    let callback = authorize(backend, subject);
+
+    let response = browser().get(callback.as_str()).call().unwrap();
+    assert_eq!(response.status(), 303);
+    response.header("set-cookie")
+        .expect("No session cookie after login")
+        .to_owned()
+}
+
+/// Starts the login and lets the provider authorize `subject`, returns the backend's callback url.
+fn authorize(backend: &ChatBackend, subject: &str) -> Url {
+    let response = browser().get(backend.url().join("auth/login").unwrap().as_str()).call().unwrap();
+    assert_eq!(response.status(), 303);
+    let mut authorize = Url::parse(response.header("location").unwrap()).unwrap();
+    authorize.query_pairs_mut().append_pair("login_hint", subject);
+
+    let response = browser().get(authorize.as_str()).call().unwrap();
+    assert_eq!(response.status(), 303);
+    let callback = Url::parse(response.header("location").unwrap()).unwrap();
+    backend.url().join(&format!("{}?{}", callback.path(), callback.query().unwrap_or_default()))
+        .unwrap()
+}
+
+fn browser() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .redirects(0)
+        .build()
+}
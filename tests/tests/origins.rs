@@ -0,0 +1,94 @@
+use chat::client::ChatClient;
+use chat::embedded::EmbeddedChat;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::HeaderValue;
+use url::Url;
+
+mod chat;
+mod process;
+
+const ALLOWED: &str = "https://chat.example.com";
+const OTHER: &str = "https://evil.example.com";
+
+/// A websocket request from a page of `origin`.
+fn request_from(url: &Url, origin: &str) -> Request {
+    let mut request = url.as_str().into_client_request().unwrap();
+    request.headers_mut().insert("origin", HeaderValue::from_str(origin).unwrap());
+    request
+}
+
+#[test]
+fn websockets_from_pages_of_other_origins_are_forbidden() {
+    let backend = EmbeddedChat::start(|server| server.allowed_origin(ALLOWED));
+
+    match tungstenite::connect(request_from(&backend.chat_url(), OTHER)) {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("Expected 403, but got {:?}", other.map(|_| ())),
+    }
+    ChatClient::open(request_from(&backend.chat_url(), ALLOWED)).unwrap();
+    // The page served by the chat itself
+    let own = backend.url().as_str().trim_end_matches('/').to_owned();
+    ChatClient::open(request_from(&backend.chat_url(), &own)).unwrap();
+    // Clients other than browsers send no origin
+    ChatClient::connect(&backend.chat_url());
+}
+
+#[test]
+fn rest_endpoints_answer_allowed_origins_with_cors_headers() {
+    let backend = EmbeddedChat::start(|server| server.allowed_origin(ALLOWED));
+    let poll = backend.url().join("poll").unwrap();
+
+    let preflight = ureq::request("OPTIONS", poll.as_str())
+        .set("origin", ALLOWED)
+        .set("access-control-request-method", "POST")
+        .call().unwrap();
+    assert_eq!(preflight.status(), 204);
+    assert_eq!(preflight.header("access-control-allow-origin"), Some(ALLOWED));
+    assert_eq!(preflight.header("access-control-allow-methods"), Some("GET, POST, DELETE"));
+
+    let response = ureq::post(poll.as_str()).set("origin", ALLOWED).call().unwrap();
+    assert_eq!(response.header("access-control-allow-origin"), Some(ALLOWED));
+    assert_eq!(response.header("access-control-allow-credentials"), Some("true"));
+
+    match ureq::post(poll.as_str()).set("origin", OTHER).call() {
+        Err(ureq::Error::Status(403, _)) => {}
+        other => panic!("Expected 403, but got {:?}", other),
+    }
+}
+
+#[test]
+fn only_the_own_page_may_chat_unless_origins_are_configured() {
+    let backend = EmbeddedChat::start(|server| server);
+    let own = backend.url().as_str().trim_end_matches('/').to_owned();
+
+    ChatClient::open(request_from(&backend.chat_url(), &own)).unwrap();
+    ChatClient::connect(&backend.chat_url());
+    for origin in [OTHER, own.replacen("http://", "https://", 1).as_str()] {
+        match tungstenite::connect(request_from(&backend.chat_url(), origin)) {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403, "{}", origin),
+            other => panic!("Expected 403 for {}, but got {:?}", origin, other.map(|_| ())),
+        }
+        match ureq::post(backend.url().join("poll").unwrap().as_str()).set("origin", origin).call() {
+            Err(ureq::Error::Status(403, _)) => {}
+            other => panic!("Expected 403 for {}, but got {:?}", origin, other),
+        }
+    }
+}
+
+#[test]
+fn the_scheme_of_the_own_page_comes_from_the_proxy() {
+    let backend = EmbeddedChat::start(|server| server);
+    let own = backend.url().as_str().trim_end_matches('/').to_owned();
+    let from_proxy = |origin: &str| {
+        let mut request = request_from(&backend.chat_url(), origin);
+        request.headers_mut().insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        request
+    };
+
+    ChatClient::open(from_proxy(&own.replacen("http://", "https://", 1))).unwrap();
+    match tungstenite::connect(from_proxy(&own)) {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("Expected 403, but got {:?}", other.map(|_| ())),
+    }
+}
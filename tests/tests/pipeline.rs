@@ -0,0 +1,21 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+#[test]
+fn too_long_message_is_rejected() {
+    let backend = ChatBackend::start(&[("MAX_MESSAGE_LENGTH", "10")]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    bob.send("Hello everybody!");
+    bob.receives_notice("Messages must not be longer than 10 characters");
+
+    bob.send("Hi!");
+    // Alice gets only the message which passed
+    alice.receives_message(bob.name(), "Hi!");
+}
@@ -0,0 +1,109 @@
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+/// Answers every message with "pong".
+const PONG: &str = r#"
+(module
+  (import "chat" "reply" (func $reply (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "pong")
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "on_message") (param i32 i32) (call $reply (i32.const 0) (i32.const 4))))
+"#;
+
+/// Answers every message with "pang".
+const PANG: &str = r#"
+(module
+  (import "chat" "reply" (func $reply (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "pang")
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "on_message") (param i32 i32) (call $reply (i32.const 0) (i32.const 4))))
+"#;
+
+/// Replies with far more than its memory holds.
+const HUGE: &str = r#"
+(module
+  (import "chat" "reply" (func $reply (param i32 i32)))
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "on_message") (param i32 i32) (call $reply (i32.const 0) (i32.const 0x7fffffff))))
+"#;
+
+/// Never returns, unless it runs out of fuel.
+const SPIN: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "on_message") (param i32 i32) (loop $forever (br $forever))))
+"#;
+
+#[test]
+fn plugins_reply_and_run_out_of_fuel() {
+    let plugins = env::temp_dir().join(format!("rust-chat-plugins-{}", std::process::id()));
+    fs::create_dir_all(&plugins).unwrap();
+    fs::write(plugins.join("pong.wasm"), wat::parse_str(PONG).unwrap()).unwrap();
+    fs::write(plugins.join("spin.wasm"), wat::parse_str(SPIN).unwrap()).unwrap();
+    fs::write(plugins.join("huge.wasm"), wat::parse_str(HUGE).unwrap()).unwrap();
+
+    let backend = ChatBackend::start(&[("PLUGINS_DIR", plugins.to_str().unwrap()), ("PLUGIN_FUEL", "100000")]);
+    let mut alice = ChatClient::connect(&backend.chat_url());
+
+    alice.send("ping");
+    alice.receives_message("pong", "pong");
+    // The spinning plugin is stopped, the huge reply traps, and both get the next message as well
+    alice.send("ping again");
+    alice.receives_message("pong", "pong");
+    alice.send("/who");
+    alice.receives_notice(&format!("In this room: {}, huge, pong, spin", alice.name()));
+
+    fs::remove_dir_all(&plugins).unwrap();
+}
+
+#[test]
+fn plugins_do_not_answer_each_other() {
+    let plugins = env::temp_dir().join(format!("rust-chat-plugins-{}-chatty", std::process::id()));
+    fs::create_dir_all(&plugins).unwrap();
+    fs::write(plugins.join("pong.wasm"), wat::parse_str(PONG).unwrap()).unwrap();
+    fs::write(plugins.join("pang.wasm"), wat::parse_str(PANG).unwrap()).unwrap();
+
+    let backend = ChatBackend::start(&[("PLUGINS_DIR", plugins.to_str().unwrap())]);
+    let mut alice = ChatClient::connect(&backend.chat_url());
+
+    alice.send("ping");
+    alice.receives_messages_in_any_order(&[("pang", "pang"), ("pong", "pong")]);
+    // Answers to answers would have come before
+    alice.send("/who");
+    alice.receives_notice(&format!("In this room: {}, pang, pong", alice.name()));
+
+    fs::remove_dir_all(&plugins).unwrap();
+}
+
+#[test]
+fn busy_plugins_do_not_hold_up_the_chat() {
+    let plugins = env::temp_dir().join(format!("rust-chat-plugins-{}-busy", std::process::id()));
+    fs::create_dir_all(&plugins).unwrap();
+    fs::write(plugins.join("spin.wasm"), wat::parse_str(SPIN).unwrap()).unwrap();
+
+    // Seconds of spinning for every message
+    let backend = ChatBackend::start(&[("PLUGINS_DIR", plugins.to_str().unwrap()), ("PLUGIN_FUEL", "1000000000")]);
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    let start = Instant::now();
+    for text in ["one", "two", "three"] {
+        alice.send(text);
+        bob.receives_message(alice.name(), text);
+    }
+    assert!(start.elapsed() < Duration::from_millis(500), "Messages took {:?}", start.elapsed());
+
+    fs::remove_dir_all(&plugins).unwrap();
+}
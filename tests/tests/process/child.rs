@@ -54,6 +54,12 @@ impl BackgroundChild {
         }
     }
 
+    /// Stops the process and returns what it wrote to stdout.
+    pub fn take_stdout(&mut self) -> Vec<u8> {
+        self.stop(false);
+        Self::get_stream_data(self.name, "stdout", &mut self.stdout)
+    }
+
     fn print_status(stdout: &mut StdoutLock, process_name: &str, status: String) {
         writeln!(stdout, "{} status: {}", process_name, status)
             .unwrap(); // println! panics too
@@ -1,2 +1,5 @@
+// Every test suite uses only a part of the helpers
+#![allow(dead_code)]
+
 pub mod child;
 pub mod server;
\ No newline at end of file
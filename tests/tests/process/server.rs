@@ -1,4 +1,5 @@
-use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::process::Command;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -15,6 +16,24 @@ pub struct ServerProcess {
 
 impl ServerProcess {
     pub fn new(name: &'static str, cmd_builder: impl FnOnce(u16) -> Command) -> Result<ServerProcess> {
+        Self::start(name, None, cmd_builder)
+    }
+
+    /// Unlike `new`, waits until GET `readiness_path` answers with 200 OK.
+    pub fn with_readiness(name: &'static str, readiness_path: &'static str, cmd_builder: impl FnOnce(u16) -> Command) -> Result<ServerProcess> {
+        Self::start(name, Some(readiness_path), cmd_builder)
+    }
+
+    pub fn get_url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Stops the server and returns what it wrote to stdout.
+    pub fn take_stdout(&mut self) -> Vec<u8> {
+        self._process.take_stdout()
+    }
+
+    fn start(name: &'static str, readiness_path: Option<&str>, cmd_builder: impl FnOnce(u16) -> Command) -> Result<ServerProcess> {
         // Allow tests to run in parallel (in theory) by finding any open port
         // available for our driver. We can't bind the port for the driver, but
         // hopefully the OS gives this invocation unique ports across processes
@@ -25,21 +44,21 @@ impl ServerProcess {
         let mut cmd = cmd_builder(server_addr.port());
         let mut process = BackgroundChild::spawn(name, &mut cmd)?;
 
-        // Wait for the driver to come online and bind its port before we try to
-        // connect to it.
+        // Wait for the driver to come online and bind its port (and to be ready
+        // if it can tell us) before we try to connect to it.
         let start = Instant::now();
         let max = Duration::new(5, 0);
-        let mut bound = false;
+        let mut ready = false;
         while start.elapsed() < max {
-            if TcpStream::connect(&server_addr).is_ok() {
-                bound = true;
+            if Self::is_ready(&server_addr, readiness_path) {
+                ready = true;
                 break;
             }
             thread::sleep(Duration::from_millis(100));
         }
-        if !bound {
+        if !ready {
             process.stop(true);
-            bail!("server failed to bind port during startup")
+            bail!("server failed to get ready during startup")
         }
 
         let url = Url::parse(&format!("http://{}", server_addr))?;
@@ -47,7 +66,23 @@ impl ServerProcess {
         Ok(ServerProcess { url, _process: process })
     }
 
-    pub fn get_url(&self) -> &Url {
-        &self.url
+    fn is_ready(server_addr: &SocketAddr, readiness_path: Option<&str>) -> bool {
+        let mut stream = match TcpStream::connect(server_addr) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let readiness_path = match readiness_path {
+            Some(path) => path,
+            None => return true,
+        };
+
+        // A bare request is enough, we only need the status line
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", readiness_path, server_addr);
+        let mut response = String::new();
+        let answered = stream.set_read_timeout(Some(Duration::from_secs(1)))
+            .and_then(|_| stream.write_all(request.as_bytes()))
+            .and_then(|_| stream.read_to_string(&mut response))
+            .is_ok();
+        answered && response.split_whitespace().nth(1) == Some("200")
     }
 }
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use chat::client::ChatClient;
+use chat::embedded::EmbeddedChat;
+use tungstenite::Message;
+
+mod chat;
+mod process;
+
+#[test]
+fn messages_reach_everybody_but_the_sender() {
+    let chat = EmbeddedChat::start(|server| server);
+
+    let mut alice = ChatClient::connect(&chat.chat_url());
+    let mut bob = ChatClient::connect(&chat.chat_url());
+    let mut carol = ChatClient::connect(&chat.chat_url());
+    alice.receives_joined(bob.name());
+    alice.receives_joined(carol.name());
+    bob.receives_joined(carol.name());
+
+    alice.send("Hi all!");
+    bob.receives_message(alice.name(), "Hi all!");
+    carol.receives_message(alice.name(), "Hi all!");
+
+    bob.send("Hi Alice!");
+    // Alice's own message would have arrived first, if it was sent back to her
+    alice.receives_message(bob.name(), "Hi Alice!");
+    carol.receives_message(bob.name(), "Hi Alice!");
+}
+
+#[test]
+fn users_leave_once_their_connection_is_gone() {
+    let chat = EmbeddedChat::start(|server| server.resume_grace(Duration::ZERO));
+
+    let mut alice = ChatClient::connect(&chat.chat_url());
+    let bob = ChatClient::connect(&chat.chat_url());
+    let carol = ChatClient::connect(&chat.chat_url());
+    let (bob_name, carol_name) = (bob.name().to_owned(), carol.name().to_owned());
+    alice.receives_joined(&bob_name);
+    alice.receives_joined(&carol_name);
+
+    bob.close();
+    alice.receives_left(&bob_name);
+
+    // Without a close frame, like a network failure
+    carol.drop_connection();
+    alice.receives_left(&carol_name);
+
+    // Nobody is left to get the message, but the backend does not mind
+    alice.send("Anybody here?");
+    let dave = ChatClient::connect(&chat.chat_url());
+    alice.receives_joined(dave.name());
+}
+
+#[test]
+fn frames_other_than_events_do_not_break_the_connection() {
+    let chat = EmbeddedChat::start(|server| server);
+
+    let mut alice = ChatClient::connect(&chat.chat_url());
+    let mut bob = ChatClient::connect(&chat.chat_url());
+    alice.receives_joined(bob.name());
+
+    bob.send_frame(Message::Ping(b"are you alive?".to_vec()));
+    bob.receives_pong(b"are you alive?");
+
+    bob.send_frame(Message::Text("not an event".to_owned()));
+    bob.send_frame(Message::Binary(vec![0xc1, 0xff, 0x00]));
+    bob.send_frame(Message::Pong(b"unasked".to_vec()));
+
+    bob.send("Still here");
+    alice.receives_message(bob.name(), "Still here");
+}
@@ -0,0 +1,46 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+
+mod chat;
+mod process;
+
+#[test]
+fn resumed_user_gets_missed_messages_without_leaving() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    alice.receives_joined(bob.name());
+
+    bob.send("Hi Alice!");
+    alice.receives_message(bob.name(), "Hi Alice!");
+
+    let alice_uid = alice.uid();
+    let resume = alice.drop_connection();
+    bob.send("Are you there?");
+
+    let mut alice = ChatClient::resume(&backend.chat_url(), &resume);
+    assert_eq!(alice.uid(), alice_uid);
+    alice.receives_message(bob.name(), "Are you there?");
+
+    // Bob sees neither Alice leaving nor joining again
+    alice.send("Sorry, lost my connection");
+    bob.receives_message(alice.name(), "Sorry, lost my connection");
+}
+
+#[test]
+fn user_leaves_when_grace_period_is_over() {
+    let backend = ChatBackend::start(&[("RESUME_GRACE_SECS", "1")]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let bob = ChatClient::connect(&backend.chat_url());
+    let bob_name = bob.name().to_owned();
+    alice.receives_joined(&bob_name);
+
+    let resume = bob.drop_connection();
+    alice.receives_left(&bob_name);
+
+    let bob = ChatClient::resume(&backend.chat_url(), &resume);
+    assert_ne!(bob.name(), bob_name);
+    alice.receives_joined(bob.name());
+}
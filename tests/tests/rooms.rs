@@ -0,0 +1,77 @@
+use std::thread;
+use std::time::Duration;
+
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use protocol::ServerEvent;
+
+mod chat;
+mod process;
+
+#[test]
+fn users_only_see_their_own_room() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut alice = ChatClient::connect(&backend.room_url("rust"));
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    let mut carol = ChatClient::connect(&backend.room_url("rust"));
+    alice.receives_joined(carol.name());
+
+    bob.send("Anybody here?");
+    carol.send("Hi Alice!");
+    // Bob's message would have arrived first, if it was sent to the room
+    alice.receives_message(carol.name(), "Hi Alice!");
+
+    alice.send("Hi Carol!");
+    carol.receives_message(alice.name(), "Hi Carol!");
+}
+
+#[test]
+fn users_open_new_rooms_up_to_the_limit() {
+    let backend = ChatBackend::start(&[("MAX_ROOMS", "3"), ("RESUME_GRACE_SECS", "0"), ("INTEGRATIONS", "CI=s3cret")]);
+    let mut dave = ChatClient::connect(&backend.chat_url());
+    let _alice = ChatClient::connect(&backend.room_url("rust"));
+
+    // Rooms without members are not opened by messages
+    post(&backend, "builds");
+    post(&backend, "deploys");
+    let bob = ChatClient::connect(&backend.room_url("go"));
+
+    let mut carol = ChatClient::connect(&backend.room_url("haskell"));
+    carol.receives_notice("There are too many rooms to open haskell, you are in general");
+    dave.receives_joined(carol.name());
+
+    // Bob's room goes once he has left, which takes a moment after the close
+    bob.close();
+    for _ in 0..100 {
+        let mut erin = ChatClient::connect(&backend.room_url("haskell"));
+        erin.send("/who");
+        match erin.receive() {
+            ServerEvent::Notice { text } if text.starts_with("In this room") => return,
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    panic!("Expected a room to be free once Bob left");
+}
+
+#[test]
+fn messages_to_rooms_without_members_are_not_kept() {
+    let backend = ChatBackend::start(&[("INTEGRATIONS", "CI=s3cret")]);
+    post(&backend, "builds");
+
+    let alice = ChatClient::connect(&backend.room_url("builds"));
+    let resume = alice.drop_connection().with_nothing_seen();
+    let mut alice = ChatClient::resume(&backend.room_url("builds"), &resume);
+    // The message would come first, had the room kept it
+    alice.send("/who");
+    alice.receives_notice(&format!("In this room: {}", alice.name()));
+}
+
+fn post(backend: &ChatBackend, room: &str) {
+    let url = backend.url().join(&format!("api/rooms/{}/messages", room)).unwrap();
+    ureq::post(url.as_str())
+        .set("authorization", "Bearer s3cret")
+        .set("content-type", "application/json")
+        .send_string(r#"{"text": "Build #42 passed"}"#)
+        .unwrap();
+}
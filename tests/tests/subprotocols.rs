@@ -0,0 +1,48 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use protocol::CLOSE_UNSUPPORTED_PROTOCOL;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::HeaderValue;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message;
+
+mod chat;
+mod process;
+
+#[test]
+fn the_backend_selects_a_supported_subprotocol() {
+    let backend = ChatBackend::start(&[]);
+
+    // MessagePack in the subprotocol wins over JSON in the query
+    let mut url = backend.chat_url();
+    url.query_pairs_mut().append_pair("encoding", "json");
+    let mut alice = ChatClient::connect_with_protocols(&url, "rust-chat.v2.json, rust-chat.v1.msgpack");
+    assert_eq!(alice.protocol(), Some("rust-chat.v1.msgpack"));
+
+    // Clients from before subprotocols are still welcome
+    let mut bob = ChatClient::connect(&backend.chat_url());
+    assert_eq!(bob.protocol(), None);
+    alice.receives_joined(bob.name());
+
+    alice.send("Hi Bob!");
+    bob.receives_message(alice.name(), "Hi Bob!");
+    bob.send("Hi Alice!");
+    alice.receives_message(bob.name(), "Hi Alice!");
+}
+
+#[test]
+fn unsupported_subprotocols_are_closed_with_a_reason() {
+    let backend = ChatBackend::start(&[]);
+
+    let mut request = backend.chat_url().as_str().into_client_request().unwrap();
+    request.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_static("rust-chat.v2.json"));
+    let mut socket = ChatClient::open(request).unwrap();
+
+    match socket.read_message().unwrap() {
+        Message::Close(Some(frame)) => {
+            assert_eq!(frame.code, CloseCode::from(CLOSE_UNSUPPORTED_PROTOCOL));
+            assert_eq!(frame.reason, "Unsupported protocol, supported are rust-chat.v1.json and rust-chat.v1.msgpack");
+        }
+        message => panic!("Expected close, but got {:?}", message),
+    }
+}
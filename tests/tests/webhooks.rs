@@ -0,0 +1,87 @@
+use chat::backend::ChatBackend;
+use chat::client::ChatClient;
+use mock::receiver::MockReceiver;
+use serde_json::Value;
+
+mod chat;
+mod mock;
+mod process;
+
+const TOKEN: &str = "admin-secret";
+
+fn deliveries(backend: &ChatBackend) -> Value {
+    let url = backend.url().join("admin/webhooks/deliveries").unwrap();
+    let response = ureq::get(url.as_str()).set("authorization", &format!("Bearer {}", TOKEN)).call().unwrap();
+    serde_json::from_str(&response.into_string().unwrap()).unwrap()
+}
+
+#[test]
+fn webhooks_are_called_for_matching_messages() {
+    let receiver = MockReceiver::start();
+    let webhooks = format!("keyword:deploy {} s3cret, room:ops {} other, mention:Bob {} s3cret",
+                           receiver.url("deploys"), receiver.url("ops"), receiver.url("mentions"));
+    let backend = ChatBackend::start(&[("WEBHOOKS", &webhooks)]);
+
+    let mut alice = ChatClient::connect(&backend.chat_url());
+    let mut carol = ChatClient::connect(&backend.room_url("ops"));
+
+    alice.send("Shall we Deploy today?");
+    let received = receiver.receives();
+    assert_eq!(received.path, "deploys");
+    assert!(received.is_signed_with("s3cret"));
+    assert_eq!(received.body["trigger"], "keyword:deploy");
+    assert_eq!(received.body["room"], "general");
+    assert_eq!(received.body["message"]["text"], "Shall we Deploy today?");
+    assert_eq!(received.body["message"]["name"], alice.name());
+    assert!(received.headers.contains_key("x-chat-delivery"));
+
+    alice.send("Redeployment is not a keyword");
+    receiver.receives_nothing();
+
+    alice.send("@Bob, are you there?");
+    assert_eq!(receiver.receives().path, "mentions");
+
+    carol.send("Disk is full");
+    let received = receiver.receives();
+    assert_eq!(received.path, "ops");
+    assert!(received.is_signed_with("other"));
+    assert!(!received.is_signed_with("s3cret"));
+}
+
+#[test]
+fn failed_deliveries_are_retried_and_logged() {
+    let receiver = MockReceiver::start();
+    let webhooks = format!("keyword:deploy {} s3cret, keyword:release {} s3cret",
+                           receiver.url("deploys"), receiver.url("releases"));
+    let backend = ChatBackend::start(&[
+        ("WEBHOOKS", &webhooks),
+        ("WEBHOOK_ATTEMPTS", "3"),
+        ("WEBHOOK_BACKOFF_MS", "50"),
+        ("ADMIN_TOKEN", TOKEN),
+    ]);
+    let mut alice = ChatClient::connect(&backend.chat_url());
+
+    receiver.answer_with(&[503, 500]);
+    alice.send("deploy");
+    let first = receiver.receives();
+    let retried = [receiver.receives(), receiver.receives()];
+    for received in retried.iter() {
+        assert_eq!(received.path, "deploys");
+        assert_eq!(received.headers["x-chat-delivery"], first.headers["x-chat-delivery"]);
+        assert_eq!(received.body, first.body);
+    }
+
+    receiver.answer_with(&[400]);
+    alice.send("release");
+    assert_eq!(receiver.receives().path, "releases");
+    // Bad requests stay bad
+    receiver.receives_nothing();
+
+    let deliveries = deliveries(&backend);
+    let deliveries = deliveries.as_array().unwrap();
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!((&deliveries[0]["trigger"], &deliveries[0]["attempts"], &deliveries[0]["status"], &deliveries[0]["delivered"]),
+               (&Value::from("keyword:deploy"), &Value::from(3), &Value::from(200), &Value::from(true)));
+    assert_eq!((&deliveries[1]["trigger"], &deliveries[1]["attempts"], &deliveries[1]["status"], &deliveries[1]["delivered"]),
+               (&Value::from("keyword:release"), &Value::from(1), &Value::from(400), &Value::from(false)));
+}